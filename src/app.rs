@@ -1,56 +1,102 @@
 use chrono::Local;
 use color_eyre::Result;
-use crossterm::event::{KeyCode, KeyEvent};
+use color_eyre::eyre::eyre;
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
 use ratatui::style::Style;
 use ratatui::{
     DefaultTerminal,
     widgets::{Block, ListState},
 };
 use sqlx::sqlite::SqlitePool;
+use std::time::{Duration, Instant};
 use tui_textarea::TextArea;
 
 use crate::db;
+use crate::input::{input_tokens, parse_input, parse_tags};
 use crate::models::{
-    CompletedTodoList, InputMode, TodoItem, TodoList, WhichList, new_todo_item,
-    sort_todos_hierarchically,
+    CompletedTodoList, INBOX_LIST_ID, InputMode, ListAction, ListPrompt, NamedList, Notice,
+    NoticeLevel, Priority, SortMode, TodoItem, TodoList, WhichList, filter_by_tags, hide_collapsed,
+    new_todo_item, next_sibling, previous_sibling, retain_with_ancestors,
+    sort_todos_hierarchically_by,
 };
+use crate::search::fuzzy_match;
+use crate::writer::{Write, Writer};
 
 pub struct App {
     pub should_exit: bool,
     pub pool: SqlitePool,
     pub uncompleted_todo_list: TodoList,
     pub completed_todo_list: CompletedTodoList,
+    pub trash_todo_list: TodoList,
+    /// Whether the trash is shown in place of the completed list
+    pub show_trash: bool,
     pub creating_child_todo: bool,
     pub editing_index: Option<usize>,
     pub input_mode: InputMode,
     pub focused_list: WhichList,
     pub textarea: TextArea<'static>,
+    /// Tags the active list is narrowed to, empty when unfiltered
+    pub tag_filter: Vec<String>,
+    pub sort_mode: SortMode,
+    /// First key of a two key command such as `za`
+    pub pending_key: Option<char>,
+    /// Todo whose details are open in the full screen editor
+    pub details_todo_id: Option<i64>,
+    /// Fuzzy search narrowing `search_list`, empty when not searching
+    pub search_query: String,
+    pub search_list: WhichList,
+    /// Ids found by a full-text search, None while the search is fuzzy
+    pub full_text_hits: Option<Vec<i64>>,
+    /// Named list every todo list is scoped to
+    pub list_id: i64,
+    /// Lists that aren't archived, as shown in the list popup
+    pub lists: Vec<NamedList>,
+    pub lists_state: ListState,
+    pub list_action: ListAction,
+    pub list_prompt: Option<ListPrompt>,
+    /// Queue every change to the database goes through
+    pub writer: Writer,
+    /// Every notice shown this session, oldest first
+    pub messages: Vec<Notice>,
+    /// Notice in the status line, until a key is pressed or an info notice goes stale
+    pub status: Option<Notice>,
+    pub messages_state: ListState,
 }
 
+/// How many notices `:messages` keeps
+const MESSAGES_LIMIT: usize = 200;
+
+/// How long an info notice stays in the status line without a key press
+const INFO_TIMEOUT: chrono::TimeDelta = chrono::TimeDelta::seconds(5);
+
 // Public API - Core Application Interface
 impl App {
     /// Creates a new App instance with database connection and loads existing todos
     pub async fn with_pool(pool: SqlitePool) -> Result<Self, sqlx::Error> {
-        let todo_items: Vec<TodoItem> = crate::db::uncompleted_todos(&pool).await?;
-        let completed_items: Vec<TodoItem> = crate::db::completed_todos(&pool).await?;
+        let list_id = INBOX_LIST_ID;
+        let todo_items: Vec<TodoItem> = crate::db::uncompleted_todos(&pool, list_id).await?;
+        let completed_items: Vec<TodoItem> = crate::db::completed_todos(&pool, list_id).await?;
+        let deleted_items: Vec<TodoItem> = crate::db::deleted_todos(&pool, list_id).await?;
+        let lists: Vec<NamedList> = crate::db::all_lists(&pool)
+            .await?
+            .into_iter()
+            .filter(|list| !list.archived)
+            .collect();
 
         let no_todos = {
             TodoList {
-                items: vec![TodoItem {
-                    id: None,
-                    todo: "Make a todo item".to_string(),
-                    details: "One's life always has something to do".to_string(),
-                    completed_at: None,
-                    date: Local::now().naive_local(),
-                    parent_id: None,
-                    sort_order: 0,
-                }],
+                items: vec![new_todo_item(
+                    "Make a todo item",
+                    "One's life always has something to do",
+                    None,
+                )],
                 state: ListState::default(),
             }
         };
 
         Ok(Self {
             should_exit: false,
+            writer: Writer::start(pool.clone()),
             pool,
             input_mode: InputMode::Normal,
             editing_index: None,
@@ -67,32 +113,121 @@ impl App {
                 items: completed_items,
                 state: ListState::default(),
             },
+            trash_todo_list: TodoList {
+                items: deleted_items,
+                state: ListState::default(),
+            },
+            show_trash: false,
             focused_list: WhichList::Uncompleted,
             textarea: TextArea::default(),
+            tag_filter: Vec::new(),
+            sort_mode: SortMode::Manual,
+            pending_key: None,
+            details_todo_id: None,
+            search_query: String::new(),
+            search_list: WhichList::Uncompleted,
+            full_text_hits: None,
+            list_id,
+            lists,
+            lists_state: ListState::default(),
+            list_action: ListAction::Switch,
+            list_prompt: None,
+            messages: Vec::new(),
+            status: None,
+            messages_state: ListState::default(),
         })
     }
 
     /// Main application loop that handles rendering and input
     pub fn run(mut self, terminal: &mut DefaultTerminal) -> Result<()> {
         while !self.should_exit {
+            for failure in self.writer.take_failures() {
+                self.notify(NoticeLevel::Error, failure);
+            }
+            if self.status.as_ref().is_some_and(|notice| {
+                notice.level == NoticeLevel::Info
+                    && Local::now().naive_local() - notice.at > INFO_TIMEOUT
+            }) {
+                self.status = None;
+            }
             terminal.draw(|f| crate::ui::render_impl(&mut self, f))?;
 
-            if let crossterm::event::Event::Key(key) = crossterm::event::read()? {
+            // Wake up now and then so failed writes show without a key press
+            if crossterm::event::poll(Duration::from_millis(250))?
+                && let crossterm::event::Event::Key(key) = crossterm::event::read()?
+            {
+                self.status = None;
                 self.handle_key(key);
             }
         }
+
+        // Don't drop writes still in the queue on the way out
+        self.writer.flush()?;
+        let failures = self.writer.take_failures();
+        if !failures.is_empty() {
+            return Err(eyre!(failures.join("\n")));
+        }
         Ok(())
     }
 
+    /// Shows a notice in the status line and keeps it for `:messages`
+    pub fn notify(&mut self, level: NoticeLevel, text: String) {
+        match level {
+            NoticeLevel::Info => tracing::info!("{text}"),
+            NoticeLevel::Warning => tracing::warn!("{text}"),
+            NoticeLevel::Error => tracing::error!("{text}"),
+        }
+        let notice = Notice {
+            level,
+            text,
+            at: Local::now().naive_local(),
+        };
+        self.messages.push(notice.clone());
+        if self.messages.len() > MESSAGES_LIMIT {
+            self.messages.remove(0);
+        }
+        self.status = Some(notice);
+    }
+
+    fn show_error(&mut self, doing: &str, e: sqlx::Error) {
+        self.notify(NoticeLevel::Error, format!("Database error {doing}: {e}"));
+    }
+
     /// Handles keyboard input and routes to appropriate actions
     pub fn handle_key(&mut self, key: KeyEvent) {
+        if let Some('z') = self.pending_key.take()
+            && let KeyCode::Char(c) = key.code
+        {
+            self.handle_fold_key(c);
+            return;
+        }
+
         match self.input_mode {
             InputMode::Normal => match key.code {
+                KeyCode::Char('z') => self.pending_key = Some('z'),
+                KeyCode::Char('r') if key.modifiers.contains(KeyModifiers::CONTROL) => self.redo(),
+                KeyCode::Char('u') => self.undo(),
                 KeyCode::Char('i') => self.enter_insert_mode(),
                 KeyCode::Char('o') => self.enter_child_mode(),
                 KeyCode::Char('e') => self.enter_edit_mode(),
+                KeyCode::Char('E') => self.enter_details_mode(),
+                KeyCode::Char('/') => self.enter_search_mode(false),
+                KeyCode::Char('?') => self.enter_search_mode(true),
+                KeyCode::Char('n') => self.jump_to_match(true),
+                KeyCode::Char('N') => self.jump_to_match(false),
+                KeyCode::Esc => self.set_search(String::new()),
                 KeyCode::Char('q') => self.should_exit = true,
                 KeyCode::Char('d') => self.delete_selected_todo(),
+                KeyCode::Char('r') => self.restore_selected_todo(),
+                KeyCode::Char('T') => self.toggle_trash(),
+                KeyCode::Char('L') => self.enter_lists_mode(ListAction::Switch),
+                KeyCode::Char('m') => {
+                    if self.focused_list != WhichList::Trash
+                        && let Some(todo_id) = self.selected_todo().and_then(|todo| todo.id)
+                    {
+                        self.enter_lists_mode(ListAction::Move(todo_id));
+                    }
+                }
                 KeyCode::Char('h') | KeyCode::Left => self.select_none(),
                 KeyCode::Char('j') | KeyCode::Down => self.select_next(),
                 KeyCode::Char('k') | KeyCode::Up => self.select_previous(),
@@ -100,6 +235,14 @@ impl App {
                 KeyCode::Char('G') | KeyCode::End => self.select_last(),
                 KeyCode::Char('J') => self.move_todo_down(),
                 KeyCode::Char('K') => self.move_todo_up(),
+                KeyCode::Char('>') => self.indent_todo(),
+                KeyCode::Char('<') => self.outdent_todo(),
+                KeyCode::Char('f') => self.enter_filter_mode(),
+                KeyCode::Char('F') => self.set_tag_filter(Vec::new()),
+                KeyCode::Char('+') | KeyCode::Char('=') => self.change_priority(Priority::raise),
+                KeyCode::Char('-') => self.change_priority(Priority::lower),
+                KeyCode::Char('P') => self.toggle_sort_mode(),
+                KeyCode::Char(':') => self.enter_command_mode(),
                 KeyCode::Tab => {
                     self.toggle_focused_list();
                     self.unfocused_state().select(None);
@@ -128,77 +271,252 @@ impl App {
                     self.textarea.input(input);
                 }
             },
+            InputMode::Details => match key.code {
+                KeyCode::Esc => {
+                    self.details_todo_id = None;
+                    self.input_mode.toggle();
+                    self.textarea = TextArea::default();
+                }
+                KeyCode::Char('s') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                    self.save_details();
+                }
+                _ => {
+                    let input = tui_textarea::Input::from(key);
+                    self.textarea.input(input);
+                }
+            },
+            InputMode::Search => match key.code {
+                KeyCode::Esc => {
+                    self.input_mode.toggle();
+                    self.textarea = TextArea::default();
+                    self.set_search(String::new());
+                }
+                KeyCode::Enter => {
+                    if self.full_text_hits.is_some() {
+                        self.set_full_text_search(self.textarea.lines().join(" "));
+                        self.focused_state().select(None);
+                        self.jump_to_match(true);
+                    }
+                    self.input_mode.toggle();
+                    self.textarea = TextArea::default();
+                }
+                _ => {
+                    let input = tui_textarea::Input::from(key);
+                    self.textarea.input(input);
+                    // Half typed FTS queries are often invalid, so those only run on Enter
+                    if self.full_text_hits.is_none() {
+                        self.set_search(self.textarea.lines().join(" "));
+                        self.focused_state().select(None);
+                        self.jump_to_match(true);
+                    }
+                }
+            },
+            InputMode::Lists if self.list_prompt.is_some() => match key.code {
+                KeyCode::Esc => {
+                    self.list_prompt = None;
+                    self.textarea = TextArea::default();
+                }
+                KeyCode::Enter => self.save_list_prompt(),
+                _ => {
+                    let input = tui_textarea::Input::from(key);
+                    self.textarea.input(input);
+                }
+            },
+            InputMode::Lists => match key.code {
+                KeyCode::Esc | KeyCode::Char('q') => self.input_mode.toggle(),
+                KeyCode::Char('j') | KeyCode::Down => self.lists_state.select_next(),
+                KeyCode::Char('k') | KeyCode::Up => self.lists_state.select_previous(),
+                KeyCode::Enter => self.pick_list(),
+                KeyCode::Char('a') => self.start_list_prompt(ListPrompt::Create),
+                KeyCode::Char('r') => {
+                    if let Some(list) = self.selected_list() {
+                        self.start_list_prompt(ListPrompt::Rename(list.id));
+                    }
+                }
+                KeyCode::Char('x') => self.archive_selected_list(),
+                _ => {}
+            },
+            InputMode::Command => match key.code {
+                KeyCode::Esc => {
+                    self.input_mode.toggle();
+                    self.textarea = TextArea::default();
+                }
+                KeyCode::Enter => {
+                    let command = self.textarea.lines().join(" ");
+                    self.input_mode.toggle();
+                    self.textarea = TextArea::default();
+                    self.run_command(command.trim());
+                }
+                _ => {
+                    let input = tui_textarea::Input::from(key);
+                    self.textarea.input(input);
+                }
+            },
+            InputMode::Messages => match key.code {
+                KeyCode::Esc | KeyCode::Char('q') => self.input_mode.toggle(),
+                KeyCode::Char('j') | KeyCode::Down => self.messages_state.select_next(),
+                KeyCode::Char('k') | KeyCode::Up => self.messages_state.select_previous(),
+                KeyCode::Char('g') | KeyCode::Home => self.messages_state.select_first(),
+                KeyCode::Char('G') | KeyCode::End => self.messages_state.select_last(),
+                _ => {}
+            },
+            InputMode::Filter => match key.code {
+                KeyCode::Esc => {
+                    self.input_mode.toggle();
+                    self.textarea = TextArea::default();
+                }
+                KeyCode::Enter => {
+                    let terms = self
+                        .textarea
+                        .lines()
+                        .join(" ")
+                        .split_whitespace()
+                        .map(str::to_lowercase)
+                        .collect();
+                    self.input_mode.toggle();
+                    self.textarea = TextArea::default();
+                    self.set_tag_filter(terms);
+                }
+                _ => {
+                    let input = tui_textarea::Input::from(key);
+                    self.textarea.input(input);
+                }
+            },
         }
     }
 
     pub fn move_todo_up(&mut self) {
-        if let Some(index) = self.uncompleted_todo_list.state.selected() {
-            if index > 0 && index < self.uncompleted_todo_list.items.len() {
-                // Swap sort_orders between current and previous item
-                let current_order = self.uncompleted_todo_list.items[index].sort_order;
-                let prev_order = self.uncompleted_todo_list.items[index - 1].sort_order;
-
-                self.uncompleted_todo_list.items[index].sort_order = prev_order;
-                self.uncompleted_todo_list.items[index - 1].sort_order = current_order;
-
-                // Swap items in the list
-                self.uncompleted_todo_list.items.swap(index, index - 1);
-                self.uncompleted_todo_list.state.select(Some(index - 1));
-
-                // Update database
-                self.update_sort_orders_in_db();
-            }
-        }
+        self.move_among_siblings(true);
     }
 
     pub fn move_todo_down(&mut self) {
-        if let Some(index) = self.uncompleted_todo_list.state.selected() {
-            if index < self.uncompleted_todo_list.items.len() - 1 {
-                // Swap sort_orders between current and next item
-                let current_order = self.uncompleted_todo_list.items[index].sort_order;
-                let next_order = self.uncompleted_todo_list.items[index + 1].sort_order;
-
-                self.uncompleted_todo_list.items[index].sort_order = next_order;
-                self.uncompleted_todo_list.items[index + 1].sort_order = current_order;
-
-                // Swap items in the list
-                self.uncompleted_todo_list.items.swap(index, index + 1);
-                self.uncompleted_todo_list.state.select(Some(index + 1));
+        self.move_among_siblings(false);
+    }
 
-                // Update database
-                self.update_sort_orders_in_db();
-            }
+    /// Moves the selected todo past the sibling above or below it, its
+    /// subtree and the sibling's coming along
+    fn move_among_siblings(&mut self, up: bool) {
+        // Manual order isn't visible while sorted by priority
+        if self.sort_mode == SortMode::Priority {
+            return;
         }
+        let Some((index, id)) = self.selected_active_todo() else {
+            return;
+        };
+        let items = &self.uncompleted_todo_list.items;
+        let parent = items[index].parent_id;
+        let (before, after) = if up {
+            match previous_sibling(items, index).and_then(|sibling| sibling.id) {
+                Some(sibling) => (Some(sibling), None),
+                None => return,
+            }
+        } else {
+            match next_sibling(items, index).and_then(|sibling| sibling.id) {
+                Some(sibling) => (None, Some(sibling)),
+                None => return,
+            }
+        };
+        self.move_selected_todo(id, before, after, parent);
     }
 
-    fn update_sort_orders_in_db(&self) {
-        let pool = self.pool.clone();
-        let items = self.uncompleted_todo_list.items.clone();
+    /// Makes the selected todo the last child of the sibling above it
+    pub fn indent_todo(&mut self) {
+        let Some((index, id)) = self.selected_active_todo() else {
+            return;
+        };
+        let Some(sibling) = previous_sibling(&self.uncompleted_todo_list.items, index) else {
+            return;
+        };
+        let (Some(parent), collapsed) = (sibling.id, sibling.collapsed) else {
+            return;
+        };
+        // Unfold the new parent so the todo stays in sight
+        if collapsed {
+            self.writer.submit(Write::Fold {
+                id: parent,
+                collapsed: false,
+            });
+        }
+        self.move_selected_todo(id, None, None, Some(parent));
+    }
 
-        tokio::spawn(async move {
-            for item in items {
-                if let Some(id) = item.id {
-                    if let Err(e) =
-                        crate::db::update_todo_sort_order(&pool, id, item.sort_order).await
-                    {
-                        eprintln!("Database error updating sort order: {}", e);
-                    }
+    /// Moves the selected todo out of its parent, to right after it
+    pub fn outdent_todo(&mut self) {
+        let Some((index, id)) = self.selected_active_todo() else {
+            return;
+        };
+        let items = &self.uncompleted_todo_list.items;
+        let Some(parent) = items[index].parent_id else {
+            return;
+        };
+        let listed = items
+            .iter()
+            .find(|item| item.id == Some(parent))
+            .map(|item| item.parent_id);
+        // A completed or hidden parent isn't in the list, so ask the database
+        let grandparent = match listed {
+            Some(grandparent) => grandparent,
+            None => match tokio::task::block_in_place(|| {
+                tokio::runtime::Handle::current().block_on(db::get_todo(&self.pool, parent))
+            }) {
+                Ok(Some(parent)) => parent.parent_id,
+                Ok(None) => {
+                    self.notify(
+                        NoticeLevel::Warning,
+                        "Can't outdent: the parent todo no longer exists".to_string(),
+                    );
+                    return;
                 }
-            }
-        });
+                Err(e) => {
+                    self.show_error("outdenting todo", e);
+                    return;
+                }
+            },
+        };
+        self.move_selected_todo(id, None, Some(parent), grandparent);
+    }
+
+    /// Where the selected active todo is in the list, and its id
+    fn selected_active_todo(&self) -> Option<(usize, i64)> {
+        let index = self.uncompleted_todo_list.state.selected()?;
+        let id = self.uncompleted_todo_list.items.get(index)?.id?;
+        Some((index, id))
+    }
+
+    fn move_selected_todo(
+        &mut self,
+        id: i64,
+        before: Option<i64>,
+        after: Option<i64>,
+        parent: Option<i64>,
+    ) {
+        if let Err(e) = self.writer.run(Write::Reorder {
+            id,
+            before,
+            after,
+            parent,
+        }) {
+            self.show_error("reordering todo", e);
+        }
+        if let Err(e) = self.refresh_from_database() {
+            self.show_error("refreshing lists", e);
+        }
+        self.select_todo(id);
     }
 
     fn focused_state(&mut self) -> &mut ratatui::widgets::ListState {
         match self.focused_list {
             WhichList::Uncompleted => &mut self.uncompleted_todo_list.state,
             WhichList::Completed => &mut self.completed_todo_list.state,
+            WhichList::Trash => &mut self.trash_todo_list.state,
         }
     }
 
     fn unfocused_state(&mut self) -> &mut ratatui::widgets::ListState {
-        match self.focused_list {
-            WhichList::Uncompleted => &mut self.completed_todo_list.state,
-            WhichList::Completed => &mut self.uncompleted_todo_list.state,
+        match (self.focused_list, self.show_trash) {
+            (WhichList::Uncompleted, false) => &mut self.completed_todo_list.state,
+            (WhichList::Uncompleted, true) => &mut self.trash_todo_list.state,
+            (WhichList::Completed | WhichList::Trash, _) => &mut self.uncompleted_todo_list.state,
         }
     }
 
@@ -234,19 +552,26 @@ impl App {
             return;
         }
 
-        let new_text = self.textarea.lines().join("\n");
-        self.uncompleted_todo_list.items[index].todo = new_text.clone();
-
-        let pool = self.pool.clone();
-        let todo_id = self.uncompleted_todo_list.items[index].id;
+        let input = parse_input(&self.textarea.lines().join("\n"), Local::now().date_naive());
+        let todo = &mut self.uncompleted_todo_list.items[index];
+        todo.todo = input.text;
+        // Kept in step with the text, as the writer does for `todo_tags`
+        todo.tags = parse_tags(&todo.todo);
+        todo.due = input.due;
+        todo.scheduled = input.scheduled;
+        todo.recurrence = input.recurrence;
+        todo.priority = input.priority.unwrap_or_default();
 
-        tokio::spawn(async move {
-            if let Some(id) = todo_id {
-                if let Err(e) = crate::db::update_todo_text(&pool, id, &new_text).await {
-                    eprintln!("Database error updating todo text: {}", e);
-                }
-            }
-        });
+        if let Some(id) = todo.id {
+            self.writer.submit(Write::Edit {
+                id,
+                text: todo.todo.clone(),
+                due: todo.due,
+                scheduled: todo.scheduled,
+                recurrence: todo.recurrence.clone(),
+                priority: todo.priority,
+            });
+        }
 
         self.textarea = TextArea::default();
         self.editing_index = None;
@@ -264,7 +589,7 @@ impl App {
     pub fn enter_insert_mode(&mut self) {
         self.creating_child_todo = false;
         self.textarea = TextArea::default();
-        self.set_textarea_block(String::from("New todo"));
+        self.set_textarea_block(String::from("New todo (due:fri sched:+3d rec:weekly)"));
         self.textarea.set_cursor_line_style(Style::default());
         self.input_mode.toggle();
     }
@@ -286,7 +611,7 @@ impl App {
             return;
         };
 
-        self.textarea = TextArea::new(vec![todo.todo.clone()]);
+        self.textarea = TextArea::new(vec![format!("{}{}", todo.todo, input_tokens(todo))]);
         self.textarea.move_cursor(tui_textarea::CursorMove::End);
         self.textarea.set_cursor_line_style(Style::default());
         self.set_textarea_block(String::from("Edit Todo"));
@@ -295,15 +620,424 @@ impl App {
         self.input_mode.toggle();
     }
 
+    /// Opens the details of the selected todo in a full screen editor
+    pub fn enter_details_mode(&mut self) {
+        let Some(todo) = self.selected_todo().cloned() else {
+            return;
+        };
+        let Some(todo_id) = todo.id else {
+            return;
+        };
+
+        // Split on newlines alone so saving unchanged details writes them back as they were
+        self.textarea = TextArea::new(todo.details.split('\n').map(str::to_string).collect());
+        self.set_textarea_block(format!(
+            "Details of '{}' (Ctrl-s save, Esc cancel)",
+            todo.todo
+        ));
+        self.details_todo_id = Some(todo_id);
+        self.input_mode = InputMode::Details;
+    }
+
+    pub fn save_details(&mut self) {
+        let Some(todo_id) = self.details_todo_id.take() else {
+            return;
+        };
+        let details = self.textarea.lines().join("\n");
+
+        for todo in self
+            .uncompleted_todo_list
+            .items
+            .iter_mut()
+            .chain(self.completed_todo_list.items.iter_mut())
+            .filter(|todo| todo.id == Some(todo_id))
+        {
+            todo.details = details.clone();
+        }
+
+        self.writer.submit(Write::Details {
+            id: todo_id,
+            details,
+        });
+
+        self.textarea = TextArea::default();
+        self.input_mode.toggle();
+    }
+
+    /// Reverts the most recent change to the todos
+    pub fn undo(&mut self) {
+        if let Err(e) = self.writer.run(Write::Undo) {
+            self.show_error("undoing", e);
+        }
+        self.refresh_keeping_selection();
+    }
+
+    /// Reapplies the most recently undone change
+    pub fn redo(&mut self) {
+        if let Err(e) = self.writer.run(Write::Redo) {
+            self.show_error("redoing", e);
+        }
+        self.refresh_keeping_selection();
+    }
+
+    /// Reloads the lists, keeping each cursor inside its list as they may have shrunk
+    fn refresh_keeping_selection(&mut self) {
+        if let Err(e) = self.refresh_from_database() {
+            self.show_error("refreshing lists", e);
+        }
+        for (len, state) in [
+            (
+                self.uncompleted_todo_list.items.len(),
+                &mut self.uncompleted_todo_list.state,
+            ),
+            (
+                self.completed_todo_list.items.len(),
+                &mut self.completed_todo_list.state,
+            ),
+            (
+                self.trash_todo_list.items.len(),
+                &mut self.trash_todo_list.state,
+            ),
+        ] {
+            if let Some(index) = state.selected()
+                && index >= len
+            {
+                state.select(len.checked_sub(1));
+            }
+        }
+    }
+
+    /// The todo under the cursor in the focused list
+    pub fn selected_todo(&self) -> Option<&TodoItem> {
+        match self.focused_list {
+            WhichList::Uncompleted => self
+                .uncompleted_todo_list
+                .state
+                .selected()
+                .and_then(|index| self.uncompleted_todo_list.items.get(index)),
+            WhichList::Completed => self
+                .completed_todo_list
+                .state
+                .selected()
+                .and_then(|index| self.completed_todo_list.items.get(index)),
+            WhichList::Trash => self
+                .trash_todo_list
+                .state
+                .selected()
+                .and_then(|index| self.trash_todo_list.items.get(index)),
+        }
+    }
+
+    /// Opens a popup for the tags to narrow the active list to
+    pub fn enter_filter_mode(&mut self) {
+        let known_tags = tokio::task::block_in_place(|| {
+            tokio::runtime::Handle::current().block_on(db::all_tags(&self.pool, self.list_id))
+        })
+        .unwrap_or_default();
+
+        self.textarea = TextArea::new(vec![self.tag_filter.join(" ")]);
+        self.textarea.move_cursor(tui_textarea::CursorMove::End);
+        self.textarea.set_cursor_line_style(Style::default());
+        self.set_textarea_block(format!("Filter by tags ({})", known_tags.join(" ")));
+        self.input_mode = InputMode::Filter;
+    }
+
+    /// Narrows the active list to todos carrying every one of `terms`
+    pub fn set_tag_filter(&mut self, terms: Vec<String>) {
+        self.tag_filter = terms;
+        if let Err(e) = self.refresh_from_database() {
+            self.show_error("refreshing lists", e);
+        }
+        self.uncompleted_todo_list.state.select(None);
+    }
+
+    /// Applies the tag filter, search, sort mode and folds to a freshly loaded list
+    fn apply_view(&self, items: Vec<TodoItem>) -> Vec<TodoItem> {
+        let items = if self.tag_filter.is_empty() {
+            items
+        } else {
+            filter_by_tags(items, &self.tag_filter)
+        };
+        let items = self.apply_search(WhichList::Uncompleted, items);
+        let items = match self.sort_mode {
+            SortMode::Manual => items,
+            SortMode::Priority => sort_todos_hierarchically_by(items, self.sort_mode),
+        };
+        // Matches inside folded subtrees shouldn't stay hidden
+        if self.search_for(WhichList::Uncompleted).is_some() {
+            items
+        } else {
+            hide_collapsed(items)
+        }
+    }
+
+    fn apply_search(&self, list: WhichList, items: Vec<TodoItem>) -> Vec<TodoItem> {
+        match self.search_for(list) {
+            Some(query) => retain_with_ancestors(items, |todo| self.matches_search(query, todo)),
+            None => items,
+        }
+    }
+
+    fn matches_search(&self, query: &str, todo: &TodoItem) -> bool {
+        match &self.full_text_hits {
+            Some(hits) => todo.id.is_some_and(|id| hits.contains(&id)),
+            None => fuzzy_match(query, todo).is_some(),
+        }
+    }
+
+    /// The search narrowing `list`, if any
+    pub fn search_for(&self, list: WhichList) -> Option<&str> {
+        (!self.search_query.is_empty() && self.search_list == list)
+            .then_some(self.search_query.as_str())
+    }
+
+    /// The fuzzy search narrowing `list`, whose matches get highlighted
+    pub fn fuzzy_search_for(&self, list: WhichList) -> Option<&str> {
+        self.search_for(list)
+            .filter(|_| self.full_text_hits.is_none())
+    }
+
+    /// Opens the search line at the bottom for the focused list, fuzzy or full-text
+    pub fn enter_search_mode(&mut self, full_text: bool) {
+        if self.search_list != self.focused_list || full_text != self.full_text_hits.is_some() {
+            self.set_search(String::new());
+        }
+        self.search_list = self.focused_list;
+        if full_text && self.full_text_hits.is_none() {
+            self.full_text_hits = Some(Vec::new());
+        }
+        self.textarea = TextArea::new(vec![self.search_query.clone()]);
+        self.textarea.move_cursor(tui_textarea::CursorMove::End);
+        self.textarea.set_cursor_line_style(Style::default());
+        self.input_mode = InputMode::Search;
+    }
+
+    /// Narrows the searched list to todos fuzzy matching `query`, or shows all of it when empty
+    pub fn set_search(&mut self, query: String) {
+        self.search_query = query;
+        self.full_text_hits = None;
+        self.refresh_keeping_selection();
+    }
+
+    /// Narrows the searched list to the todos the full-text index finds for `query`
+    pub fn set_full_text_search(&mut self, query: String) {
+        let hits = if query.trim().is_empty() {
+            Ok(Vec::new())
+        } else {
+            tokio::task::block_in_place(|| {
+                tokio::runtime::Handle::current().block_on(db::search_todos(
+                    &self.pool,
+                    self.list_id,
+                    &query,
+                ))
+            })
+        };
+
+        match hits {
+            Ok(todos) => {
+                self.search_query = query;
+                self.full_text_hits = Some(todos.iter().filter_map(|todo| todo.id).collect());
+            }
+            Err(e) => {
+                self.show_error("searching", e);
+                self.search_query = String::new();
+                self.full_text_hits = None;
+            }
+        }
+        self.refresh_keeping_selection();
+    }
+
+    /// Moves the cursor to the next or previous todo matching the search, wrapping around
+    pub fn jump_to_match(&mut self, forward: bool) {
+        if self.focused_list != self.search_list {
+            return;
+        }
+        let Some(query) = self.search_for(self.search_list) else {
+            return;
+        };
+        let items = match self.search_list {
+            WhichList::Uncompleted => &self.uncompleted_todo_list.items,
+            WhichList::Completed => &self.completed_todo_list.items,
+            WhichList::Trash => &self.trash_todo_list.items,
+        };
+        // Ancestors are only shown for context, skip over them
+        let matches: Vec<usize> = items
+            .iter()
+            .enumerate()
+            .filter(|(_, todo)| self.matches_search(query, todo))
+            .map(|(index, _)| index)
+            .collect();
+
+        let current = self.focused_state().selected();
+        let next = if forward {
+            matches
+                .iter()
+                .find(|&&index| current.is_none_or(|current| index > current))
+                .or(matches.first())
+        } else {
+            matches
+                .iter()
+                .rev()
+                .find(|&&index| current.is_none_or(|current| index < current))
+                .or(matches.last())
+        };
+        if let Some(&index) = next {
+            self.focused_state().select(Some(index));
+        }
+    }
+
+    /// Handles the second key of `za` (toggle), `zo` (open), `zc` (close),
+    /// `zR` (open all) and `zM` (close all)
+    pub fn handle_fold_key(&mut self, key: char) {
+        match key {
+            'a' => self.fold_selected(None),
+            'o' => self.fold_selected(Some(false)),
+            'c' => self.fold_selected(Some(true)),
+            'R' => self.fold_all(false),
+            'M' => self.fold_all(true),
+            _ => {}
+        }
+    }
+
+    /// Folds or unfolds the selected subtree, toggling when `collapsed` is None
+    fn fold_selected(&mut self, collapsed: Option<bool>) {
+        let items = &self.uncompleted_todo_list.items;
+        let Some(todo) = self
+            .uncompleted_todo_list
+            .state
+            .selected()
+            .and_then(|index| items.get(index))
+        else {
+            return;
+        };
+
+        let has_children = todo.collapsed
+            || items
+                .iter()
+                .any(|item| item.parent_id.is_some() && item.parent_id == todo.id);
+        // On a todo without children, act on the subtree it sits in
+        let target = if has_children {
+            Some(todo)
+        } else {
+            todo.parent_id
+                .and_then(|parent_id| items.iter().find(|item| item.id == Some(parent_id)))
+        };
+        let Some(target) = target else {
+            return;
+        };
+        let Some(target_id) = target.id else {
+            return;
+        };
+        let collapsed = collapsed.unwrap_or(!target.collapsed);
+
+        if let Err(e) = self.writer.run(Write::Fold {
+            id: target_id,
+            collapsed,
+        }) {
+            self.show_error("updating fold", e);
+        }
+
+        if let Err(e) = self.refresh_from_database() {
+            self.show_error("refreshing lists", e);
+        }
+        self.select_todo(target_id);
+    }
+
+    fn fold_all(&mut self, collapsed: bool) {
+        let selected_id = self
+            .uncompleted_todo_list
+            .state
+            .selected()
+            .and_then(|index| self.uncompleted_todo_list.items.get(index))
+            .and_then(|item| item.id);
+
+        if let Err(e) = self.writer.run(Write::FoldAll {
+            list_id: self.list_id,
+            collapsed,
+        }) {
+            self.show_error("updating folds", e);
+        }
+
+        if let Err(e) = self.refresh_from_database() {
+            self.show_error("refreshing lists", e);
+        }
+        if let Some(id) = selected_id {
+            self.select_todo(id);
+        }
+    }
+
+    /// Moves the cursor in the active list to the todo with the given id, if it's visible
+    fn select_todo(&mut self, todo_id: i64) {
+        if let Some(index) = self
+            .uncompleted_todo_list
+            .items
+            .iter()
+            .position(|item| item.id == Some(todo_id))
+        {
+            self.uncompleted_todo_list.state.select(Some(index));
+        }
+    }
+
+    /// Switches between manual ordering and ordering by priority
+    pub fn toggle_sort_mode(&mut self) {
+        self.sort_mode = match self.sort_mode {
+            SortMode::Manual => SortMode::Priority,
+            SortMode::Priority => SortMode::Manual,
+        };
+        if let Err(e) = self.refresh_from_database() {
+            self.show_error("refreshing lists", e);
+        }
+    }
+
+    /// Raises or lowers the priority of the selected todo
+    pub fn change_priority(&mut self, change: fn(Priority) -> Priority) {
+        let Some(index) = self.uncompleted_todo_list.state.selected() else {
+            return;
+        };
+        let Some(todo) = self.uncompleted_todo_list.items.get_mut(index) else {
+            return;
+        };
+
+        todo.priority = change(todo.priority);
+
+        let todo_id = todo.id;
+        if let Some(id) = todo_id {
+            self.writer.submit(Write::Priority {
+                id,
+                priority: todo.priority,
+            });
+        }
+
+        if self.sort_mode == SortMode::Priority {
+            self.uncompleted_todo_list.items = sort_todos_hierarchically_by(
+                self.uncompleted_todo_list.items.clone(),
+                self.sort_mode,
+            );
+            // Keep the cursor on the todo that just moved
+            if let Some(id) = todo_id {
+                self.select_todo(id);
+            }
+        }
+    }
+
     /// Refreshes the todo list app fields
     pub fn refresh_from_database(&mut self) -> Result<(), sqlx::Error> {
-        tokio::task::block_in_place(|| {
+        // Read back what was queued, not what the database held before it
+        self.writer.flush()?;
+        let started = Instant::now();
+        let result = tokio::task::block_in_place(|| {
             tokio::runtime::Handle::current().block_on(async {
-                self.uncompleted_todo_list.items = db::uncompleted_todos(&self.pool).await?;
-                self.completed_todo_list.items = db::completed_todos(&self.pool).await?;
+                let uncompleted = db::uncompleted_todos(&self.pool, self.list_id).await?;
+                self.uncompleted_todo_list.items = self.apply_view(uncompleted);
+                let completed = db::completed_todos(&self.pool, self.list_id).await?;
+                self.completed_todo_list.items = self.apply_search(WhichList::Completed, completed);
+                let deleted = db::deleted_todos(&self.pool, self.list_id).await?;
+                self.trash_todo_list.items = self.apply_search(WhichList::Trash, deleted);
                 Ok(())
             })
-        })
+        });
+        tracing::debug!(list_id = self.list_id, elapsed = ?started.elapsed(), "refreshed lists");
+        result
     }
 
     /// Changes the status of the selected list item
@@ -317,48 +1051,34 @@ impl App {
                 &self.completed_todo_list.items,
                 &self.completed_todo_list.state,
             ),
+            WhichList::Trash => return,
         };
 
         let Some(index) = state.selected() else {
             return;
         };
-        let Some(todo) = list_items.get(index) else {
+        let Some(todo_id) = list_items.get(index).and_then(|todo| todo.id) else {
             return;
         };
 
-        let result = tokio::task::block_in_place(|| {
-            tokio::runtime::Handle::current().block_on(async {
-                crate::db::toggle_todo_status_in_database(&self.pool, todo.id).await
-            })
-        });
-
-        if let Err(e) = result {
-            eprintln!("Database error toggling status: {e}");
+        if let Err(e) = self.writer.run(Write::Toggle(todo_id)) {
+            self.show_error("toggling status", e);
         }
 
         if let Err(e) = self.refresh_from_database() {
-            eprintln!("Database error refreshing lists: {e}");
+            self.show_error("refreshing lists", e);
         }
     }
 
     pub fn toggle_focused_list(&mut self) {
         self.focused_list = match self.focused_list {
-            WhichList::Uncompleted => WhichList::Completed,
-            WhichList::Completed => WhichList::Uncompleted,
+            WhichList::Uncompleted => self.bottom_list(),
+            WhichList::Completed | WhichList::Trash => WhichList::Uncompleted,
         }
     }
 
     /// Adds a new todo item from user input
     pub fn add_input_todo(&mut self) {
-        let next_sort_order = self
-            .uncompleted_todo_list
-            .items
-            .iter()
-            .map(|item| item.sort_order)
-            .max()
-            .unwrap_or(0)
-            + 10;
-
         let parent_id = if self.creating_child_todo {
             self.uncompleted_todo_list
                 .state
@@ -369,51 +1089,411 @@ impl App {
             None
         };
 
-        let input_text = self.textarea.lines().join("\n");
-        let mut todo_item = new_todo_item(&input_text, "New Status", parent_id);
-        todo_item.sort_order = next_sort_order;
+        let input = parse_input(&self.textarea.lines().join("\n"), Local::now().date_naive());
+        let mut todo_item = new_todo_item(&input.text, "", parent_id);
+        todo_item.list_id = self.list_id;
+        todo_item.due = input.due;
+        todo_item.scheduled = input.scheduled;
+        todo_item.recurrence = input.recurrence;
+        todo_item.priority = input.priority.unwrap_or_default();
 
-        let pool = self.pool.clone();
-        let item_for_db = todo_item.clone();
-
-        tokio::spawn(async move {
-            if let Err(e) = crate::db::write_input_to_database(&pool, &item_for_db).await {
-                eprintln!("Database error: {}", e);
+        // Wait for the new id, so the todo can be edited or nested under straight away
+        let id = match self.writer.run(Write::Add(todo_item.clone())) {
+            Ok(id) => id,
+            Err(e) => {
+                // Keep the input open so nothing typed is lost
+                self.show_error("adding todo", e);
+                return;
             }
+        };
+        // The writer put it after every sibling, hidden and completed ones too
+        let stored = id.and_then(|id| {
+            tokio::task::block_in_place(|| {
+                tokio::runtime::Handle::current().block_on(db::get_todo(&self.pool, id))
+            })
+            .ok()
+            .flatten()
         });
+        match stored {
+            Some(stored) => todo_item = stored,
+            None => todo_item.id = id,
+        }
 
         self.uncompleted_todo_list.items.push(todo_item);
         self.uncompleted_todo_list.items =
-            sort_todos_hierarchically(self.uncompleted_todo_list.items.clone());
+            sort_todos_hierarchically_by(self.uncompleted_todo_list.items.clone(), self.sort_mode);
         self.textarea = TextArea::default();
         self.input_mode.toggle();
     }
 
     /// Deletes the currently selected todo item
     pub fn delete_selected_todo(&mut self) {
-        if let Some(index) = self.uncompleted_todo_list.state.selected() {
-            if index < self.uncompleted_todo_list.items.len() {
-                let todo_to_delete = self.uncompleted_todo_list.items[index].clone();
-                let pool = self.pool.clone();
-
-                tokio::spawn(async move {
-                    if let Err(e) =
-                        crate::db::delete_todo_from_database(&pool, &todo_to_delete).await
-                    {
-                        eprintln!("Database error deleting todo: {}", e);
-                    }
-                });
+        let Some(todo_id) = self.selected_todo().and_then(|todo| todo.id) else {
+            return;
+        };
+        let purge = self.focused_list == WhichList::Trash;
 
-                self.uncompleted_todo_list.items.remove(index);
+        let write = if purge {
+            Write::Purge(todo_id)
+        } else {
+            Write::Delete(todo_id)
+        };
+        let doing = write.describe();
+        if let Err(e) = self.writer.run(write) {
+            self.show_error(doing, e);
+        }
+        self.refresh_keeping_selection();
+    }
 
-                if self.uncompleted_todo_list.items.is_empty() {
-                    self.uncompleted_todo_list.state.select(None);
-                } else if index >= self.uncompleted_todo_list.items.len() {
-                    self.uncompleted_todo_list
-                        .state
-                        .select(Some(self.uncompleted_todo_list.items.len() - 1));
-                }
+    /// Takes the selected todo in the trash back out of it
+    pub fn restore_selected_todo(&mut self) {
+        if self.focused_list != WhichList::Trash {
+            return;
+        }
+        let Some(todo_id) = self.selected_todo().and_then(|todo| todo.id) else {
+            return;
+        };
+
+        if let Err(e) = self.writer.run(Write::Restore(todo_id)) {
+            self.show_error("restoring todo", e);
+        }
+        self.refresh_keeping_selection();
+    }
+
+    /// Shows the trash in place of the completed list, or the other way round
+    pub fn toggle_trash(&mut self) {
+        self.show_trash = !self.show_trash;
+        if self.focused_list != WhichList::Uncompleted {
+            self.unfocused_state().select(None);
+            self.focused_list = self.bottom_list();
+            self.focused_state().select(Some(0));
+        }
+        if let Err(e) = self.refresh_from_database() {
+            self.show_error("refreshing lists", e);
+        }
+    }
+
+    /// Whichever of the completed list and the trash is shown under the active list
+    pub fn bottom_list(&self) -> WhichList {
+        if self.show_trash {
+            WhichList::Trash
+        } else {
+            WhichList::Completed
+        }
+    }
+}
+
+// Named Lists - Switching between lists and moving todos across them
+impl App {
+    /// Name of the list the todos are scoped to
+    pub fn current_list_name(&self) -> &str {
+        self.list_name(self.list_id)
+    }
+
+    fn list_name(&self, list_id: i64) -> &str {
+        self.lists
+            .iter()
+            .find(|list| list.id == list_id)
+            .map_or("Inbox", |list| list.name.as_str())
+    }
+
+    /// Opens the list popup with the cursor on the current list
+    pub fn enter_lists_mode(&mut self, action: ListAction) {
+        self.reload_lists();
+        let index = self.lists.iter().position(|list| list.id == self.list_id);
+        self.lists_state.select(index.or(Some(0)));
+        self.list_action = action;
+        self.list_prompt = None;
+        self.input_mode = InputMode::Lists;
+    }
+
+    pub fn selected_list(&self) -> Option<&NamedList> {
+        self.lists_state
+            .selected()
+            .and_then(|index| self.lists.get(index))
+    }
+
+    /// Switches to, or moves the todo to, the list under the cursor
+    pub fn pick_list(&mut self) {
+        let Some(list_id) = self.selected_list().map(|list| list.id) else {
+            return;
+        };
+        match self.list_action {
+            ListAction::Switch => self.switch_list(list_id),
+            ListAction::Move(todo_id) => self.move_todo_to_list(todo_id, list_id),
+        }
+        self.input_mode = InputMode::Normal;
+    }
+
+    /// Scopes the todo lists to another named list, dropping any filter or search
+    pub fn switch_list(&mut self, list_id: i64) {
+        self.list_id = list_id;
+        self.tag_filter.clear();
+        self.search_query.clear();
+        self.full_text_hits = None;
+        self.focused_list = WhichList::Uncompleted;
+        self.uncompleted_todo_list.state.select(None);
+        self.completed_todo_list.state.select(None);
+        self.trash_todo_list.state.select(None);
+        if let Err(e) = self.refresh_from_database() {
+            self.show_error("refreshing lists", e);
+        }
+    }
+
+    fn move_todo_to_list(&mut self, todo_id: i64, list_id: i64) {
+        if list_id == self.list_id {
+            return;
+        }
+
+        match self.writer.run(Write::MoveToList {
+            id: todo_id,
+            list_id,
+        }) {
+            Ok(_) => {
+                let name = self.list_name(list_id).to_string();
+                self.notify(NoticeLevel::Info, format!("Moved to {name}"));
             }
+            Err(e) => self.show_error("moving todo", e),
+        }
+        self.refresh_keeping_selection();
+    }
+
+    /// Starts typing the name of a new list, or a new name for an existing one
+    pub fn start_list_prompt(&mut self, prompt: ListPrompt) {
+        let (name, title) = match prompt {
+            ListPrompt::Create => (String::new(), "New list"),
+            ListPrompt::Rename(list_id) => {
+                let name = self
+                    .lists
+                    .iter()
+                    .find(|list| list.id == list_id)
+                    .map(|list| list.name.clone())
+                    .unwrap_or_default();
+                (name, "Rename list")
+            }
+        };
+
+        self.textarea = TextArea::new(vec![name]);
+        self.textarea.move_cursor(tui_textarea::CursorMove::End);
+        self.textarea.set_cursor_line_style(Style::default());
+        self.set_textarea_block(String::from(title));
+        self.list_prompt = Some(prompt);
+    }
+
+    pub fn save_list_prompt(&mut self) {
+        let Some(prompt) = self.list_prompt.take() else {
+            return;
+        };
+        let name = self.textarea.lines().join(" ").trim().to_string();
+        self.textarea = TextArea::default();
+        if name.is_empty() {
+            return;
+        }
+
+        let result = match prompt {
+            ListPrompt::Create => self.writer.run(Write::CreateList(name)),
+            ListPrompt::Rename(list_id) => self
+                .writer
+                .run(Write::RenameList { id: list_id, name })
+                .map(|_| Some(list_id)),
+        };
+
+        match result {
+            Ok(list_id) => {
+                self.reload_lists();
+                let index = self.lists.iter().position(|list| Some(list.id) == list_id);
+                self.lists_state.select(index);
+            }
+            // Names are unique, so this is usually a clash with another list
+            Err(e) => self.show_error("saving list", e),
+        }
+    }
+
+    /// Archives the list under the cursor, going back to the inbox if it was current
+    pub fn archive_selected_list(&mut self) {
+        let Some(list_id) = self.selected_list().map(|list| list.id) else {
+            return;
+        };
+        if list_id == INBOX_LIST_ID {
+            return;
+        }
+
+        if let Err(e) = self.writer.run(Write::ArchiveList(list_id)) {
+            self.show_error("archiving list", e);
+            return;
+        }
+        let name = self.list_name(list_id).to_string();
+        self.notify(NoticeLevel::Info, format!("Archived {name}"));
+        if list_id == self.list_id {
+            self.switch_list(INBOX_LIST_ID);
         }
+        self.reload_lists();
+    }
+
+    /// Reloads the lists that aren't archived, keeping the cursor inside them
+    fn reload_lists(&mut self) {
+        let result = tokio::task::block_in_place(|| {
+            tokio::runtime::Handle::current().block_on(crate::db::all_lists(&self.pool))
+        });
+
+        match result {
+            Ok(lists) => self.lists = lists.into_iter().filter(|list| !list.archived).collect(),
+            Err(e) => self.show_error("loading lists", e),
+        }
+        if let Some(index) = self.lists_state.selected()
+            && index >= self.lists.len()
+        {
+            self.lists_state.select(self.lists.len().checked_sub(1));
+        }
+    }
+}
+
+// Commands - `:` commands typed in the footer and the messages popup
+impl App {
+    pub fn enter_command_mode(&mut self) {
+        self.textarea = TextArea::default();
+        self.textarea.set_cursor_line_style(Style::default());
+        self.input_mode = InputMode::Command;
+    }
+
+    /// Runs a command typed after `:`, accepting any prefix of its name like vim does
+    pub fn run_command(&mut self, command: &str) {
+        if command.is_empty() {
+            return;
+        }
+        if "messages".starts_with(command) {
+            self.open_messages();
+        } else {
+            self.notify(NoticeLevel::Warning, format!("Unknown command: {command}"));
+        }
+    }
+
+    /// Opens the popup listing past notices with the cursor on the newest
+    pub fn open_messages(&mut self) {
+        self.messages_state
+            .select(self.messages.len().checked_sub(1));
+        self.input_mode = InputMode::Messages;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn press(app: &mut App, code: KeyCode) {
+        app.handle_key(KeyEvent::new(code, KeyModifiers::NONE));
+    }
+
+    fn type_text(app: &mut App, text: &str) {
+        for c in text.chars() {
+            press(app, KeyCode::Char(c));
+        }
+    }
+
+    async fn details_of(pool: &SqlitePool, id: i64) -> String {
+        db::get_todo(pool, id).await.unwrap().unwrap().details
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn editing_a_todo_updates_its_tags() {
+        let pool = db::memory_pool().await;
+        db::add(&pool, "call mum #phone", None).await;
+        let mut app = App::with_pool(pool.clone()).await.unwrap();
+
+        press(&mut app, KeyCode::Char('j'));
+        press(&mut app, KeyCode::Char('e'));
+        app.textarea = TextArea::new(vec!["call mum #Family @home".to_string()]);
+        press(&mut app, KeyCode::Enter);
+
+        assert_eq!(
+            app.uncompleted_todo_list.items[0].tags,
+            ["#family", "@home"]
+        );
+        app.writer.flush().unwrap();
+        app.set_tag_filter(vec!["#family".to_string()]);
+        assert_eq!(app.uncompleted_todo_list.items.len(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn outdent_moves_past_a_completed_parent() {
+        let pool = db::memory_pool().await;
+        let project = db::add(&pool, "project", None).await;
+        let done = db::append_todo(
+            &pool,
+            &TodoItem {
+                completed_at: Some(Local::now().naive_local()),
+                ..new_todo_item("done step", "", Some(project))
+            },
+        )
+        .await
+        .unwrap();
+        let follow_up = db::add(&pool, "follow up", Some(done)).await;
+        let mut app = App::with_pool(pool.clone()).await.unwrap();
+
+        let index = app
+            .uncompleted_todo_list
+            .items
+            .iter()
+            .position(|item| item.id == Some(follow_up))
+            .unwrap();
+        app.uncompleted_todo_list.state.select(Some(index));
+        press(&mut app, KeyCode::Char('<'));
+        app.writer.flush().unwrap();
+
+        let moved = db::get_todo(&pool, follow_up).await.unwrap().unwrap();
+        assert_eq!(moved.parent_id, Some(project));
+        assert!(app.messages.is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn details_survive_the_editor_unchanged() {
+        let pool = db::memory_pool().await;
+        let details = "first line\n\n  indented, after a blank line\n";
+        let id = db::append_todo(&pool, &new_todo_item("todo", details, None))
+            .await
+            .unwrap();
+        let mut app = App::with_pool(pool.clone()).await.unwrap();
+
+        press(&mut app, KeyCode::Char('j'));
+        press(&mut app, KeyCode::Char('E'));
+        assert!(app.input_mode == InputMode::Details);
+        assert_eq!(
+            app.textarea.lines(),
+            ["first line", "", "  indented, after a blank line", ""]
+        );
+        app.handle_key(KeyEvent::new(KeyCode::Char('s'), KeyModifiers::CONTROL));
+        app.writer.flush().unwrap();
+
+        assert!(app.input_mode == InputMode::Normal);
+        assert_eq!(details_of(&pool, id).await, details);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn edited_details_are_saved_on_ctrl_s_only() {
+        let pool = db::memory_pool().await;
+        let id = db::append_todo(&pool, &new_todo_item("todo", "", None))
+            .await
+            .unwrap();
+        let mut app = App::with_pool(pool.clone()).await.unwrap();
+
+        press(&mut app, KeyCode::Char('j'));
+        press(&mut app, KeyCode::Char('E'));
+        type_text(&mut app, "call the bank");
+        press(&mut app, KeyCode::Esc);
+        app.writer.flush().unwrap();
+        assert_eq!(details_of(&pool, id).await, "");
+
+        press(&mut app, KeyCode::Char('E'));
+        type_text(&mut app, "call the bank");
+        press(&mut app, KeyCode::Enter);
+        type_text(&mut app, "ask about fees");
+        app.handle_key(KeyEvent::new(KeyCode::Char('s'), KeyModifiers::CONTROL));
+        app.writer.flush().unwrap();
+
+        assert_eq!(details_of(&pool, id).await, "call the bank\nask about fees");
+        assert_eq!(
+            app.selected_todo().unwrap().details,
+            "call the bank\nask about fees"
+        );
     }
 }
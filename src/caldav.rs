@@ -0,0 +1,1038 @@
+use clap::ValueEnum;
+use color_eyre::Result;
+use color_eyre::eyre::{bail, eyre};
+use reqwest::header::{CONTENT_TYPE, ETAG, IF_MATCH, IF_NONE_MATCH};
+use reqwest::{Client, Method, Response, StatusCode, Url};
+use serde::Deserialize;
+use sqlx::sqlite::SqlitePool;
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+
+use crate::db;
+use crate::ics;
+use crate::models::{CaldavResource, TodoItem};
+
+/// Which side wins when a todo changed both in dodo and on the server since
+/// the last sync
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictPolicy {
+    /// Keep the server's version
+    #[default]
+    Remote,
+    /// Keep dodo's version
+    Local,
+}
+
+/// Asks for the etag of every VTODO in a calendar collection
+const CALENDAR_QUERY: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<c:calendar-query xmlns:d="DAV:" xmlns:c="urn:ietf:params:xml:ns:caldav">
+  <d:prop><d:getetag/></d:prop>
+  <c:filter>
+    <c:comp-filter name="VCALENDAR"><c:comp-filter name="VTODO"/></c:comp-filter>
+  </c:filter>
+</c:calendar-query>"#;
+
+/// A CalDAV calendar collection holding VTODOs, one per resource
+pub struct Collection {
+    http: Client,
+    url: Url,
+    username: Option<String>,
+    password: Option<String>,
+}
+
+impl Collection {
+    pub fn new(url: &str, username: Option<String>, password: Option<String>) -> Result<Self> {
+        let mut url = Url::parse(url)?;
+        // Resource hrefs are resolved against the collection, which needs the slash
+        if !url.path().ends_with('/') {
+            url.set_path(&format!("{}/", url.path()));
+        }
+        Ok(Self {
+            http: Client::new(),
+            url,
+            username,
+            password,
+        })
+    }
+
+    fn request(&self, method: Method, href: &str) -> Result<reqwest::RequestBuilder> {
+        let request = self.http.request(method, self.url.join(href)?);
+        Ok(match &self.username {
+            Some(username) => request.basic_auth(username, self.password.as_deref()),
+            None => request,
+        })
+    }
+
+    /// Identifies the collection in the database
+    fn id(&self) -> &str {
+        self.url.as_str()
+    }
+
+    /// The path of a resource, however the server or dodo spelled its href
+    fn path(&self, href: &str) -> Result<String> {
+        Ok(self.url.join(href)?.path().to_string())
+    }
+
+    /// The etag of every VTODO in the collection, by path
+    async fn etags(&self) -> Result<HashMap<String, String>> {
+        let response = self
+            .request(Method::from_bytes(b"REPORT")?, "")?
+            .header("Depth", "1")
+            .header(CONTENT_TYPE, "application/xml; charset=utf-8")
+            .body(CALENDAR_QUERY)
+            .send()
+            .await?;
+        if response.status() != StatusCode::MULTI_STATUS {
+            bail!("listing {} failed: {}", self.url, response.status());
+        }
+
+        let body = response.text().await?;
+        let document = roxmltree::Document::parse(&body)?;
+        let mut etags = HashMap::new();
+        for item in document
+            .descendants()
+            .filter(|node| node.has_tag_name(("DAV:", "response")))
+        {
+            let text = |name: &str| {
+                item.descendants()
+                    .find(|node| node.has_tag_name(("DAV:", name)))
+                    .and_then(|node| node.text())
+                    .map(str::trim)
+            };
+            if let (Some(href), Some(etag)) = (text("href"), text("getetag")) {
+                let path = self.path(href)?;
+                if path != self.url.path() {
+                    etags.insert(path, etag.to_string());
+                }
+            }
+        }
+        Ok(etags)
+    }
+
+    async fn get(&self, href: &str) -> Result<(String, Option<String>)> {
+        let response = self
+            .request(Method::GET, href)?
+            .send()
+            .await?
+            .error_for_status()?;
+        let etag = etag_header(&response);
+        Ok((response.text().await?, etag))
+    }
+
+    /// Writes a resource if it still has `etag` or, without one, only if it
+    /// doesn't exist yet. Returns the new etag when the server gives one.
+    async fn put(&self, href: &str, body: String, etag: Option<&str>) -> Result<Option<String>> {
+        let request = self
+            .request(Method::PUT, href)?
+            .header(CONTENT_TYPE, "text/calendar; charset=utf-8")
+            .body(body);
+        let request = match etag {
+            Some(etag) => request.header(IF_MATCH, etag),
+            None => request.header(IF_NONE_MATCH, "*"),
+        };
+
+        let response = request.send().await?;
+        if response.status() == StatusCode::PRECONDITION_FAILED {
+            bail!("{href} changed on the server during the sync");
+        }
+        let response = response.error_for_status()?;
+        Ok(etag_header(&response))
+    }
+
+    async fn delete(&self, href: &str, etag: Option<&str>) -> Result<()> {
+        let request = self.request(Method::DELETE, href)?;
+        let request = match etag {
+            Some(etag) => request.header(IF_MATCH, etag),
+            None => request,
+        };
+
+        let response = request.send().await?;
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(()),
+            StatusCode::PRECONDITION_FAILED => {
+                bail!("{href} changed on the server during the sync")
+            }
+            _ => {
+                response.error_for_status()?;
+                Ok(())
+            }
+        }
+    }
+}
+
+fn etag_header(response: &Response) -> Option<String> {
+    response
+        .headers()
+        .get(ETAG)
+        .and_then(|etag| etag.to_str().ok())
+        .map(str::to_string)
+}
+
+/// What a sync did, for printing afterwards
+#[derive(Default)]
+pub struct SyncReport {
+    pub pulled: usize,
+    pub pushed: usize,
+    pub deleted_here: usize,
+    pub deleted_there: usize,
+    pub conflicts: usize,
+    /// Todos that couldn't be synced, to be tried again next time
+    pub failures: Vec<String>,
+}
+
+/// A remote version of a todo waiting to be written to the database
+struct Pulled {
+    href: String,
+    etag: Option<String>,
+    todo_id: Option<i64>,
+    component: ics::Component,
+}
+
+struct Sync<'a> {
+    pool: &'a SqlitePool,
+    collection: &'a Collection,
+    list_id: i64,
+    policy: ConflictPolicy,
+    /// The VTODO UID of every todo that is or is about to be on the server
+    uids: HashMap<i64, String>,
+    local: HashMap<i64, TodoItem>,
+    /// Todos that have a resource on the server
+    on_server: HashSet<i64>,
+    report: SyncReport,
+}
+
+/// Two-way sync between a list and a CalDAV collection. Each side's changes
+/// since the last sync are found by comparing etags for the server and the
+/// VTODO dodo last sent or received for dodo, then creates, updates and
+/// deletes are carried across. A todo changed on both sides goes the way of
+/// `policy`.
+pub async fn sync(
+    pool: &SqlitePool,
+    collection: &Collection,
+    list_id: i64,
+    policy: ConflictPolicy,
+) -> Result<SyncReport> {
+    let mut remote = collection.etags().await?;
+    let resources = db::caldav_resources(pool, collection.id()).await?;
+
+    let mut local = HashMap::new();
+    for todo in db::all_todos(pool, list_id)
+        .await?
+        .into_iter()
+        .chain(db::deleted_todos(pool, list_id).await?)
+    {
+        if let Some(id) = todo.id {
+            local.insert(id, todo);
+        }
+    }
+    let mut uids: HashMap<i64, String> = resources
+        .iter()
+        .filter_map(|resource| resource.todo_id.map(|id| (id, resource.uid.clone())))
+        .collect();
+    for (id, todo) in &local {
+        if todo.deleted_at.is_none() {
+            uids.entry(*id)
+                .or_insert_with(|| Uuid::new_v4().to_string());
+        }
+    }
+
+    let mut sync = Sync {
+        pool,
+        collection,
+        list_id,
+        policy,
+        uids,
+        local,
+        on_server: resources
+            .iter()
+            .filter_map(|resource| resource.todo_id)
+            .collect(),
+        report: SyncReport::default(),
+    };
+
+    let mut pulled = Vec::new();
+    for resource in resources {
+        let remote_etag = remote.remove(&resource.href);
+        let href = resource.href.clone();
+        match sync.reconcile(resource, remote_etag).await {
+            Ok(Some(remote_version)) => pulled.push(remote_version),
+            Ok(None) => {}
+            Err(e) => sync.report.failures.push(format!("{href}: {e}")),
+        }
+    }
+
+    // Whatever is left on the server is new there
+    for (href, etag) in remote {
+        match sync.fetch(&href, Some(etag), None).await {
+            Ok(remote_version) => pulled.push(remote_version),
+            Err(e) => sync.report.failures.push(format!("{href}: {e}")),
+        }
+    }
+    sync.apply_pulled(pulled).await?;
+
+    // And whatever was never on the server is new here
+    let new_here: Vec<TodoItem> = sync
+        .local
+        .values()
+        .filter(|todo| todo.deleted_at.is_none())
+        .filter(|todo| todo.id.is_some_and(|id| !sync.on_server.contains(&id)))
+        .cloned()
+        .collect();
+    for todo in new_here {
+        if let Err(e) = sync.push(&todo, None, None).await {
+            sync.report.failures.push(format!("{}: {e}", todo.todo));
+        }
+    }
+
+    Ok(sync.report)
+}
+
+impl Sync<'_> {
+    /// Carries a change to a todo that was on the server at the last sync
+    /// across, returning the server's version when that should be written here
+    async fn reconcile(
+        &mut self,
+        resource: CaldavResource,
+        remote_etag: Option<String>,
+    ) -> Result<Option<Pulled>> {
+        let todo = resource
+            .todo_id
+            .and_then(|id| self.local.get(&id))
+            .filter(|todo| todo.deleted_at.is_none())
+            .cloned();
+        let changed_here = todo
+            .as_ref()
+            .map(|todo| self.vtodo(todo).join("\n") != resource.synced);
+        let changed_there = remote_etag
+            .as_ref()
+            .map(|etag| Some(etag) != resource.etag.as_ref());
+
+        match (todo, changed_here, changed_there) {
+            // Gone from both sides
+            (None, _, None) => {
+                db::delete_caldav_resource(self.pool, self.collection.id(), &resource.href).await?;
+            }
+            // Deleted, purged or moved to another list here
+            (None, _, Some(false)) => {
+                self.collection
+                    .delete(&resource.href, resource.etag.as_deref())
+                    .await?;
+                db::delete_caldav_resource(self.pool, self.collection.id(), &resource.href).await?;
+                self.report.deleted_there += 1;
+            }
+            (None, _, Some(true)) => {
+                self.report.conflicts += 1;
+                match self.policy {
+                    ConflictPolicy::Remote => {
+                        return self
+                            .fetch(&resource.href, remote_etag, resource.todo_id)
+                            .await
+                            .map(Some);
+                    }
+                    ConflictPolicy::Local => {
+                        self.collection
+                            .delete(&resource.href, remote_etag.as_deref())
+                            .await?;
+                        db::delete_caldav_resource(self.pool, self.collection.id(), &resource.href)
+                            .await?;
+                        self.report.deleted_there += 1;
+                    }
+                }
+            }
+            // Deleted on the server
+            (Some(todo), Some(changed_here), None) => {
+                if changed_here {
+                    self.report.conflicts += 1;
+                }
+                if changed_here && self.policy == ConflictPolicy::Local {
+                    self.push(&todo, Some(resource.href), None).await?;
+                } else {
+                    self.trash(&todo).await?;
+                    db::delete_caldav_resource(self.pool, self.collection.id(), &resource.href)
+                        .await?;
+                    self.report.deleted_here += 1;
+                }
+            }
+            (Some(todo), Some(true), Some(false)) => {
+                self.push(&todo, Some(resource.href), resource.etag.as_deref())
+                    .await?;
+            }
+            (Some(todo), Some(changed_here), Some(true)) => {
+                if changed_here {
+                    self.report.conflicts += 1;
+                }
+                if changed_here && self.policy == ConflictPolicy::Local {
+                    self.push(&todo, Some(resource.href), remote_etag.as_deref())
+                        .await?;
+                } else {
+                    return self
+                        .fetch(&resource.href, remote_etag, resource.todo_id)
+                        .await
+                        .map(Some);
+                }
+            }
+            // Unchanged on both sides
+            (Some(_), Some(false), Some(false)) => {}
+            (Some(_), None, _) => unreachable!("a todo here is always compared with the last sync"),
+        }
+        Ok(None)
+    }
+
+    async fn fetch(
+        &self,
+        href: &str,
+        etag: Option<String>,
+        todo_id: Option<i64>,
+    ) -> Result<Pulled> {
+        let (body, fetched_etag) = self.collection.get(href).await?;
+        let (components, _) = ics::read_vtodos(&body);
+        let component = components
+            .into_iter()
+            .next()
+            .ok_or_else(|| eyre!("no VTODO in {href}"))?;
+        Ok(Pulled {
+            href: href.to_string(),
+            etag: fetched_etag.or(etag),
+            todo_id,
+            component,
+        })
+    }
+
+    /// Writes the server's versions of todos, once every one of them has an
+    /// id that the others can be nested under
+    async fn apply_pulled(&mut self, pulled: Vec<Pulled>) -> Result<()> {
+        let mut written = Vec::new();
+        for remote_version in pulled {
+            let existing = remote_version
+                .todo_id
+                .and_then(|id| self.local.get(&id))
+                .cloned();
+            let remote_todo = remote_version.component.todo.clone();
+            let id = match existing {
+                Some(local) => {
+                    let merged = TodoItem {
+                        id: local.id,
+                        date: local.date,
+                        parent_id: local.parent_id,
+                        sort_order: local.sort_order,
+                        collapsed: local.collapsed,
+                        deleted_at: None,
+                        list_id: local.list_id,
+                        ..remote_todo
+                    };
+                    db::update_todo(self.pool, &merged).await?;
+                    local.id
+                }
+                None => {
+                    let mut todo = remote_todo;
+                    todo.list_id = self.list_id;
+                    Some(db::append_todo(self.pool, &todo).await?)
+                }
+            };
+            let Some(id) = id else {
+                continue;
+            };
+
+            let uid = remote_version
+                .component
+                .uid
+                .clone()
+                .unwrap_or_else(|| Uuid::new_v4().to_string());
+            self.uids.insert(id, uid);
+            self.on_server.insert(id);
+            written.push((id, remote_version));
+            self.report.pulled += 1;
+        }
+
+        for (id, remote_version) in written {
+            let parent_id = remote_version
+                .component
+                .parent_uid
+                .as_ref()
+                .and_then(|parent_uid| {
+                    self.uids
+                        .iter()
+                        .find(|(_, uid)| *uid == parent_uid)
+                        .map(|(id, _)| *id)
+                });
+            let Some(mut todo) = db::get_todo(self.pool, id).await? else {
+                continue;
+            };
+            if todo.parent_id != parent_id && parent_id != Some(id) {
+                todo.parent_id = parent_id;
+                db::update_todo(self.pool, &todo).await?;
+            }
+
+            db::save_caldav_resource(
+                self.pool,
+                &CaldavResource {
+                    collection: self.collection.id().to_string(),
+                    href: remote_version.href,
+                    uid: self.uids[&id].clone(),
+                    etag: remote_version.etag,
+                    synced: self.vtodo(&todo).join("\n"),
+                    todo_id: Some(id),
+                },
+            )
+            .await?;
+            self.local.insert(id, todo);
+        }
+        Ok(())
+    }
+
+    /// Sends dodo's version of a todo to the server, creating it when there is no href
+    async fn push(
+        &mut self,
+        todo: &TodoItem,
+        href: Option<String>,
+        etag: Option<&str>,
+    ) -> Result<()> {
+        let Some(id) = todo.id else {
+            return Ok(());
+        };
+        let uid = self.uids[&id].clone();
+        let href = match href {
+            Some(href) => href,
+            None => self.collection.path(&format!("{uid}.ics"))?,
+        };
+
+        let vtodo = self.vtodo(todo);
+        let synced = vtodo.join("\n");
+        let etag = self
+            .collection
+            .put(&href, ics::calendar([vtodo]), etag)
+            .await?;
+        db::save_caldav_resource(
+            self.pool,
+            &CaldavResource {
+                collection: self.collection.id().to_string(),
+                href,
+                uid,
+                etag,
+                synced,
+                todo_id: Some(id),
+            },
+        )
+        .await?;
+        self.report.pushed += 1;
+        Ok(())
+    }
+
+    /// Moves a todo deleted on the server to the trash. Its children may
+    /// still be on the server, so they move up a level rather than going too.
+    async fn trash(&mut self, todo: &TodoItem) -> Result<()> {
+        let Some(id) = todo.id else {
+            return Ok(());
+        };
+        for child in self
+            .local
+            .values_mut()
+            .filter(|child| child.parent_id == Some(id) && child.deleted_at.is_none())
+        {
+            child.parent_id = todo.parent_id;
+            db::update_todo(self.pool, child).await?;
+        }
+        db::delete_todo_from_database(self.pool, id).await?;
+        Ok(())
+    }
+
+    fn vtodo(&self, todo: &TodoItem) -> Vec<String> {
+        let uid = todo
+            .id
+            .and_then(|id| self.uids.get(&id))
+            .map(String::as_str)
+            .unwrap_or_default();
+        let parent_uid = todo
+            .parent_id
+            .and_then(|id| self.uids.get(&id))
+            .map(String::as_str);
+        ics::vtodo(todo, uid, parent_uid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::add;
+    use crate::models::INBOX_LIST_ID;
+    use axum::http::{HeaderMap, Method as HttpMethod, StatusCode as HttpStatus, Uri, header};
+    use axum::response::{IntoResponse, Response as HttpResponse};
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+
+    const COLLECTION: &str = "/calendars/me/todos/";
+
+    struct Resource {
+        etag: String,
+        body: String,
+    }
+
+    #[derive(Default)]
+    struct Store {
+        resources: BTreeMap<String, Resource>,
+        last_etag: u32,
+        /// A resource someone else changes right after the next REPORT
+        race: Option<String>,
+    }
+
+    impl Store {
+        fn write(&mut self, path: &str, body: String) -> String {
+            self.last_etag += 1;
+            let etag = format!("\"{}\"", self.last_etag);
+            self.resources.insert(
+                path.to_string(),
+                Resource {
+                    etag: etag.clone(),
+                    body,
+                },
+            );
+            etag
+        }
+    }
+
+    /// A CalDAV server holding one VTODO per resource, answering just the
+    /// requests `Collection` makes
+    #[derive(Clone, Default)]
+    struct Server(Arc<Mutex<Store>>);
+
+    impl Server {
+        async fn start() -> (Self, Collection) {
+            let server = Server::default();
+            let router = axum::Router::new()
+                .fallback(handle)
+                .with_state(server.clone());
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("http://{}{COLLECTION}", listener.local_addr().unwrap());
+            tokio::spawn(async move { axum::serve(listener, router).await });
+            (server, Collection::new(&url, None, None).unwrap())
+        }
+
+        /// Adds or replaces a resource as another client would
+        fn put(&self, name: &str, body: &str) -> String {
+            let path = format!("{COLLECTION}{name}");
+            self.0.lock().unwrap().write(&path, body.to_string());
+            path
+        }
+
+        fn edit(&self, path: &str, from: &str, to: &str) {
+            let mut store = self.0.lock().unwrap();
+            let body = store.resources[path].body.replace(from, to);
+            assert_ne!(body, store.resources[path].body, "{from:?} isn't in {path}");
+            store.write(path, body);
+        }
+
+        fn remove(&self, path: &str) {
+            self.0.lock().unwrap().resources.remove(path);
+        }
+
+        fn etag(&self, path: &str) -> String {
+            self.0.lock().unwrap().resources[path].etag.clone()
+        }
+
+        fn body(&self, path: &str) -> String {
+            self.0.lock().unwrap().resources[path].body.clone()
+        }
+
+        fn todos(&self) -> Vec<(String, ics::Component)> {
+            let store = self.0.lock().unwrap();
+            store
+                .resources
+                .iter()
+                .map(|(path, resource)| {
+                    let (mut components, _) = ics::read_vtodos(&resource.body);
+                    assert_eq!(components.len(), 1, "{path}");
+                    (path.clone(), components.remove(0))
+                })
+                .collect()
+        }
+
+        fn summaries(&self) -> Vec<String> {
+            let mut summaries: Vec<String> = self
+                .todos()
+                .into_iter()
+                .map(|(_, component)| component.todo.todo)
+                .collect();
+            summaries.sort();
+            summaries
+        }
+
+        fn path_of(&self, summary: &str) -> String {
+            self.todos()
+                .into_iter()
+                .find(|(_, component)| component.todo.todo == summary)
+                .map(|(path, _)| path)
+                .unwrap_or_else(|| panic!("no {summary:?} on the server"))
+        }
+
+        fn component(&self, summary: &str) -> ics::Component {
+            let path = self.path_of(summary);
+            self.todos()
+                .into_iter()
+                .find(|(found, _)| *found == path)
+                .unwrap()
+                .1
+        }
+
+        fn race_on_next_report(&self, path: &str) {
+            self.0.lock().unwrap().race = Some(path.to_string());
+        }
+    }
+
+    async fn handle(
+        axum::extract::State(server): axum::extract::State<Server>,
+        method: HttpMethod,
+        uri: Uri,
+        headers: HeaderMap,
+        body: String,
+    ) -> HttpResponse {
+        let mut store = server.0.lock().unwrap();
+        let path = uri.path().to_string();
+        let header = |name: header::HeaderName| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        let current = store
+            .resources
+            .get(&path)
+            .map(|resource| resource.etag.clone());
+        let precondition_failed = match (header(header::IF_MATCH), header(header::IF_NONE_MATCH)) {
+            (Some(wanted), _) => current.as_ref() != Some(&wanted),
+            (None, Some(any)) if any == "*" => current.is_some(),
+            _ => false,
+        };
+
+        match method.as_str() {
+            "REPORT" if path == COLLECTION => {
+                let mut xml = format!(
+                    r#"<?xml version="1.0" encoding="utf-8"?>
+<d:multistatus xmlns:d="DAV:" xmlns:c="urn:ietf:params:xml:ns:caldav">
+<d:response><d:href>{COLLECTION}</d:href><d:propstat><d:prop/><d:status>HTTP/1.1 200 OK</d:status></d:propstat></d:response>"#
+                );
+                for (path, resource) in &store.resources {
+                    xml.push_str(&format!(
+                        "<d:response><d:href>{path}</d:href><d:propstat><d:prop><d:getetag>{}</d:getetag></d:prop><d:status>HTTP/1.1 200 OK</d:status></d:propstat></d:response>\n",
+                        resource.etag
+                    ));
+                }
+                xml.push_str("</d:multistatus>");
+                if let Some(raced) = store.race.take() {
+                    let body = store.resources[&raced]
+                        .body
+                        .replace("SUMMARY:", "SUMMARY:Changed during sync, was ");
+                    store.write(&raced, body);
+                }
+                (HttpStatus::MULTI_STATUS, xml).into_response()
+            }
+            "GET" => match store.resources.get(&path) {
+                Some(resource) => (
+                    [(header::ETAG, resource.etag.clone())],
+                    resource.body.clone(),
+                )
+                    .into_response(),
+                None => HttpStatus::NOT_FOUND.into_response(),
+            },
+            "PUT" if precondition_failed => HttpStatus::PRECONDITION_FAILED.into_response(),
+            "PUT" => {
+                let status = if current.is_some() {
+                    HttpStatus::NO_CONTENT
+                } else {
+                    HttpStatus::CREATED
+                };
+                let etag = store.write(&path, body);
+                (status, [(header::ETAG, etag)]).into_response()
+            }
+            "DELETE" if current.is_none() => HttpStatus::NOT_FOUND.into_response(),
+            "DELETE" if precondition_failed => HttpStatus::PRECONDITION_FAILED.into_response(),
+            "DELETE" => {
+                store.resources.remove(&path);
+                HttpStatus::NO_CONTENT.into_response()
+            }
+            _ => HttpStatus::METHOD_NOT_ALLOWED.into_response(),
+        }
+    }
+
+    /// A VTODO as another client would write it, alarm included
+    fn remote_vtodo(uid: &str, summary: &str, parent_uid: Option<&str>) -> String {
+        let related = parent_uid
+            .map(|parent| format!("RELATED-TO:{parent}\r\n"))
+            .unwrap_or_default();
+        format!(
+            "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nPRODID:-//test//EN\r\n\
+             BEGIN:VTODO\r\nUID:{uid}\r\nDTSTAMP:20250601T090000Z\r\nSUMMARY:{summary}\r\n\
+             STATUS:NEEDS-ACTION\r\n{related}\
+             BEGIN:VALARM\r\nACTION:DISPLAY\r\nDESCRIPTION:Reminder\r\nTRIGGER:-PT15M\r\nEND:VALARM\r\n\
+             END:VTODO\r\nEND:VCALENDAR\r\n"
+        )
+    }
+
+    async fn local(pool: &SqlitePool, text: &str) -> TodoItem {
+        db::all_todos(pool, INBOX_LIST_ID)
+            .await
+            .unwrap()
+            .into_iter()
+            .chain(db::deleted_todos(pool, INBOX_LIST_ID).await.unwrap())
+            .find(|todo| todo.todo == text)
+            .unwrap_or_else(|| panic!("no {text:?} in dodo"))
+    }
+
+    async fn local_texts(pool: &SqlitePool) -> Vec<String> {
+        let mut texts: Vec<String> = db::all_todos(pool, INBOX_LIST_ID)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|todo| todo.todo)
+            .collect();
+        texts.sort();
+        texts
+    }
+
+    async fn run(pool: &SqlitePool, collection: &Collection, policy: ConflictPolicy) -> SyncReport {
+        let report = sync(pool, collection, INBOX_LIST_ID, policy).await.unwrap();
+        assert!(report.failures.is_empty(), "{:?}", report.failures);
+        report
+    }
+
+    #[tokio::test]
+    async fn creates_todos_both_ways_with_their_nesting() {
+        let pool = db::memory_pool().await;
+        let (server, collection) = Server::start().await;
+        let parent = add(&pool, "from dodo", None).await;
+        add(&pool, "dodo child", Some(parent)).await;
+        server.put(
+            "remote-1.ics",
+            &remote_vtodo("remote-1", "from server", None),
+        );
+        server.put(
+            "remote-2.ics",
+            &remote_vtodo("remote-2", "server child", Some("remote-1")),
+        );
+
+        let report = run(&pool, &collection, ConflictPolicy::Remote).await;
+        assert_eq!((report.pushed, report.pulled), (2, 2));
+
+        assert_eq!(
+            server.summaries(),
+            ["dodo child", "from dodo", "from server", "server child"]
+        );
+        assert_eq!(local_texts(&pool).await, server.summaries());
+        let server_child = local(&pool, "server child").await;
+        assert_eq!(server_child.parent_id, local(&pool, "from server").await.id);
+        // The alarm's description doesn't end up in the todo
+        assert_eq!(server_child.details, "");
+        assert_eq!(
+            server.component("dodo child").parent_uid,
+            server.component("from dodo").uid
+        );
+
+        let report = run(&pool, &collection, ConflictPolicy::Remote).await;
+        assert_eq!((report.pushed, report.pulled, report.conflicts), (0, 0, 0));
+    }
+
+    #[tokio::test]
+    async fn carries_edits_and_completions_both_ways() {
+        let pool = db::memory_pool().await;
+        let (server, collection) = Server::start().await;
+        let a = add(&pool, "a", None).await;
+        let b = add(&pool, "b", None).await;
+        run(&pool, &collection, ConflictPolicy::Remote).await;
+        let (a_path, b_path) = (server.path_of("a"), server.path_of("b"));
+        let a_etag = server.etag(&a_path);
+
+        db::update_todo_text(&pool, a, "a edited here")
+            .await
+            .unwrap();
+        db::toggle_todo_status_in_database(&pool, Some(b))
+            .await
+            .unwrap();
+        let report = run(&pool, &collection, ConflictPolicy::Remote).await;
+        assert_eq!((report.pushed, report.pulled), (2, 0));
+        assert_ne!(server.etag(&a_path), a_etag);
+        assert_eq!(server.component("a edited here").todo.todo, "a edited here");
+        assert!(server.body(&b_path).contains("STATUS:COMPLETED"));
+
+        // Another client edits both, which shows as new etags
+        server.edit(&b_path, "SUMMARY:b", "SUMMARY:b edited there");
+        server.edit(
+            &a_path,
+            "STATUS:NEEDS-ACTION",
+            "STATUS:COMPLETED\r\nCOMPLETED:20250601T100000Z",
+        );
+        let report = run(&pool, &collection, ConflictPolicy::Remote).await;
+        assert_eq!((report.pushed, report.pulled, report.conflicts), (0, 2, 0));
+        assert!(local(&pool, "a edited here").await.completed_at.is_some());
+        let b = local(&pool, "b edited there").await;
+        assert!(b.completed_at.is_some());
+
+        // Reopening here goes back out
+        db::toggle_todo_status_in_database(&pool, b.id)
+            .await
+            .unwrap();
+        run(&pool, &collection, ConflictPolicy::Remote).await;
+        assert!(server.body(&b_path).contains("STATUS:NEEDS-ACTION"));
+    }
+
+    #[tokio::test]
+    async fn carries_deletes_both_ways() {
+        let pool = db::memory_pool().await;
+        let (server, collection) = Server::start().await;
+        add(&pool, "keep", None).await;
+        let here = add(&pool, "delete here", None).await;
+        let there = add(&pool, "delete there", None).await;
+        add(&pool, "orphan", Some(there)).await;
+        run(&pool, &collection, ConflictPolicy::Remote).await;
+
+        db::delete_todo_from_database(&pool, here).await.unwrap();
+        server.remove(&server.path_of("delete there"));
+        let report = run(&pool, &collection, ConflictPolicy::Remote).await;
+        assert_eq!((report.deleted_here, report.deleted_there), (1, 1));
+
+        assert_eq!(local_texts(&pool).await, ["keep", "orphan"]);
+        assert!(local(&pool, "delete there").await.deleted_at.is_some());
+        // The child stays, moved up a level on both sides
+        assert_eq!(local(&pool, "orphan").await.parent_id, None);
+        run(&pool, &collection, ConflictPolicy::Remote).await;
+        assert_eq!(server.summaries(), ["keep", "orphan"]);
+        assert_eq!(server.component("orphan").parent_uid, None);
+    }
+
+    #[tokio::test]
+    async fn writes_are_refused_once_the_etag_is_stale() {
+        let (server, collection) = Server::start().await;
+        let path = server.put("a.ics", &remote_vtodo("a", "original", None));
+        let stale = server.etag(&path);
+        server.edit(&path, "SUMMARY:original", "SUMMARY:newer");
+
+        let body = remote_vtodo("a", "overwritten", None);
+        let error = collection
+            .put(&path, body.clone(), Some(&stale))
+            .await
+            .unwrap_err();
+        assert!(
+            error.to_string().contains("changed on the server"),
+            "{error}"
+        );
+        assert!(collection.put(&path, body, None).await.is_err());
+        assert!(collection.delete(&path, Some(&stale)).await.is_err());
+        assert_eq!(server.summaries(), ["newer"]);
+
+        let current = server.etag(&path);
+        let etag = collection
+            .put(
+                &path,
+                remote_vtodo("a", "overwritten", None),
+                Some(&current),
+            )
+            .await
+            .unwrap();
+        assert_eq!(etag, Some(server.etag(&path)));
+        assert_eq!(server.summaries(), ["overwritten"]);
+    }
+
+    #[tokio::test]
+    async fn a_change_on_the_server_during_the_sync_is_kept_for_next_time() {
+        let pool = db::memory_pool().await;
+        let (server, collection) = Server::start().await;
+        let id = add(&pool, "raced", None).await;
+        run(&pool, &collection, ConflictPolicy::Local).await;
+        let path = server.path_of("raced");
+
+        db::update_todo_text(&pool, id, "raced here").await.unwrap();
+        server.race_on_next_report(&path);
+        let report = sync(&pool, &collection, INBOX_LIST_ID, ConflictPolicy::Local)
+            .await
+            .unwrap();
+        assert_eq!(report.failures.len(), 1);
+        assert!(report.failures[0].contains("changed on the server"));
+        assert_eq!(server.summaries(), ["Changed during sync, was raced"]);
+
+        // The next sync sees both changes and settles them by the policy
+        let report = run(&pool, &collection, ConflictPolicy::Local).await;
+        assert_eq!((report.conflicts, report.pushed), (1, 1));
+        assert_eq!(server.summaries(), ["raced here"]);
+    }
+
+    /// A todo synced once, then edited on both sides
+    async fn conflict() -> (SqlitePool, Server, Collection) {
+        let pool = db::memory_pool().await;
+        let (server, collection) = Server::start().await;
+        let id = add(&pool, "shared", None).await;
+        run(&pool, &collection, ConflictPolicy::Remote).await;
+        db::update_todo_text(&pool, id, "edited here")
+            .await
+            .unwrap();
+        server.edit(
+            &server.path_of("shared"),
+            "SUMMARY:shared",
+            "SUMMARY:edited there",
+        );
+        (pool, server, collection)
+    }
+
+    #[tokio::test]
+    async fn remote_policy_keeps_the_server_version() {
+        let (pool, server, collection) = conflict().await;
+        let report = run(&pool, &collection, ConflictPolicy::Remote).await;
+        assert_eq!((report.conflicts, report.pulled, report.pushed), (1, 1, 0));
+        assert_eq!(local_texts(&pool).await, ["edited there"]);
+        assert_eq!(server.summaries(), ["edited there"]);
+
+        let report = run(&pool, &collection, ConflictPolicy::Remote).await;
+        assert_eq!((report.conflicts, report.pulled, report.pushed), (0, 0, 0));
+    }
+
+    #[tokio::test]
+    async fn local_policy_keeps_the_dodo_version() {
+        let (pool, server, collection) = conflict().await;
+        let report = run(&pool, &collection, ConflictPolicy::Local).await;
+        assert_eq!((report.conflicts, report.pulled, report.pushed), (1, 0, 1));
+        assert_eq!(local_texts(&pool).await, ["edited here"]);
+        assert_eq!(server.summaries(), ["edited here"]);
+
+        let report = run(&pool, &collection, ConflictPolicy::Local).await;
+        assert_eq!((report.conflicts, report.pulled, report.pushed), (0, 0, 0));
+    }
+
+    #[tokio::test]
+    async fn policies_settle_a_delete_against_an_edit() {
+        for policy in [ConflictPolicy::Remote, ConflictPolicy::Local] {
+            let (pool, server, collection) = conflict().await;
+            let id = local(&pool, "edited here").await.id.unwrap();
+            db::delete_todo_from_database(&pool, id).await.unwrap();
+
+            let report = run(&pool, &collection, policy).await;
+            assert_eq!(report.conflicts, 1, "{policy:?}");
+            match policy {
+                // The edit brings the todo back out of the trash
+                ConflictPolicy::Remote => {
+                    assert_eq!(local_texts(&pool).await, ["edited there"]);
+                    assert_eq!(server.summaries(), ["edited there"]);
+                }
+                ConflictPolicy::Local => {
+                    assert!(local_texts(&pool).await.is_empty());
+                    assert!(server.summaries().is_empty());
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn pulled_todos_go_after_every_sibling_here() {
+        let pool = db::memory_pool().await;
+        let (server, collection) = Server::start().await;
+        add(&pool, "kept", None).await;
+        let trashed = add(&pool, "trashed", None).await;
+        db::delete_todo_from_database(&pool, trashed).await.unwrap();
+        run(&pool, &collection, ConflictPolicy::Remote).await;
+        server.put("new.ics", &remote_vtodo("new", "from server", None));
+
+        run(&pool, &collection, ConflictPolicy::Remote).await;
+        let trashed = db::get_todo(&pool, trashed).await.unwrap().unwrap();
+        assert_eq!(
+            local(&pool, "from server").await.sort_order,
+            trashed.sort_order + crate::models::SORT_GAP
+        );
+    }
+}
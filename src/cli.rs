@@ -0,0 +1,628 @@
+use chrono::{Local, NaiveDate};
+use clap::{Parser, Subcommand};
+use color_eyre::Result;
+use color_eyre::eyre::eyre;
+use sqlx::sqlite::SqlitePool;
+use std::io::Write;
+use std::path::PathBuf;
+
+use crate::caldav::{self, Collection, ConflictPolicy};
+use crate::config::Config;
+use crate::dates::{format_relative, parse_natural_date};
+use crate::db;
+use crate::export::{self, ExportFormat};
+use crate::ics;
+use crate::import::{self, Import, ImportFormat};
+use crate::input::parse_input;
+use crate::models::{
+    INBOX_LIST_ID, NamedList, Priority, SORT_GAP, SortMode, TodoItem, filter_by_tags,
+    new_todo_item, next_sort_order, sort_todos_hierarchically_by, todo_depths,
+};
+use crate::recurrence::Recurrence;
+use crate::sync;
+
+#[derive(Parser)]
+#[command(name = "dodo", about = "A TUI todo application")]
+pub struct Cli {
+    /// Path to the todo database
+    #[arg(long, global = true)]
+    pub db: Option<PathBuf>,
+    /// Print the path of the database that would be used and exit
+    #[arg(long = "where")]
+    pub print_db_path: bool,
+    /// Name of the list to work in, the inbox when not given
+    #[arg(long, global = true)]
+    pub list: Option<String>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Add a new todo
+    Add {
+        /// Todo text, which may contain due:DATE and sched:DATE tokens
+        text: String,
+        /// Make the new todo a child of this todo
+        #[arg(long)]
+        parent: Option<i64>,
+        /// Due date, e.g. "tomorrow", "fri", "+3d" or "2025-06-01"
+        #[arg(long, value_parser = parse_date_arg)]
+        due: Option<NaiveDate>,
+        /// Date to start working on the todo
+        #[arg(long, value_parser = parse_date_arg)]
+        scheduled: Option<NaiveDate>,
+        /// Repeat on completion: daily, weekly[:mon,thu], monthly[:15] or +Nd
+        #[arg(long)]
+        every: Option<Recurrence>,
+        /// none, low, medium, high or urgent
+        #[arg(long)]
+        priority: Option<Priority>,
+    },
+    /// List todos
+    List {
+        /// Show completed todos instead of active ones
+        #[arg(long)]
+        completed: bool,
+        /// Print the todos as JSON
+        #[arg(long)]
+        json: bool,
+        /// Only show todos carrying this #tag or @context, may be repeated
+        #[arg(long)]
+        tag: Vec<String>,
+        /// Order siblings by priority before their manual order
+        #[arg(long)]
+        by_priority: bool,
+        /// Show the todos in the trash
+        #[arg(long, conflicts_with = "completed")]
+        trash: bool,
+    },
+    /// Full-text search active and completed todos, best match first
+    Search {
+        /// e.g. `milk OR bread`, `"buy milk"` for a phrase or `gard*` for a prefix
+        query: String,
+        /// Print the todos as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Mark a todo as completed
+    Done { id: i64 },
+    /// Replace the text of a todo, updating any due:, sched:, rec: or pri: tokens given
+    Edit { id: i64, text: String },
+    /// Move a todo and its children to the trash
+    Rm { id: i64 },
+    /// Take a todo back out of the trash
+    Restore { id: i64 },
+    /// Permanently delete a todo in the trash, or everything in it
+    Purge { id: Option<i64> },
+    /// Show the lists, or create, rename or archive one
+    Lists {
+        #[command(subcommand)]
+        action: Option<ListsAction>,
+    },
+    /// Move a todo and its children to another list
+    Mv {
+        id: i64,
+        /// Name of the list to move it to
+        to: String,
+    },
+    /// Write the list's todos to stdout as JSON, CSV, todo.txt or Markdown
+    Export {
+        #[arg(long, value_enum)]
+        format: ExportFormat,
+        /// Include completed todos
+        #[arg(long)]
+        completed: bool,
+    },
+    /// Import todos from a JSON export, todo.txt, Taskwarrior or a Markdown checklist
+    Import {
+        #[arg(long, value_enum)]
+        format: ImportFormat,
+        file: PathBuf,
+        /// Only show what would be imported
+        #[arg(long)]
+        dry_run: bool,
+        /// Import without asking first
+        #[arg(long, short)]
+        yes: bool,
+    },
+    /// Export or import todos as iCalendar VTODOs
+    Ics {
+        #[command(subcommand)]
+        action: IcsAction,
+    },
+    /// Sync the list with a CalDAV collection set up under `[caldav]` in the config
+    Caldav {
+        #[command(subcommand)]
+        action: CaldavAction,
+    },
+    /// Swap changes with another dodo database or a `dodo serve` server
+    Sync {
+        /// Path to the other database, or URL of the server
+        peer: String,
+    },
+    /// Serve the web app and JSON API
+    Serve {
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        bind: String,
+    },
+    /// Print the log file, set DODO_LOG to change how much goes into it
+    Log {
+        /// Print only the last lines, then keep printing new ones
+        #[arg(long)]
+        tail: bool,
+        /// How many lines --tail starts with
+        #[arg(short = 'n', long, default_value_t = 20)]
+        lines: usize,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ListsAction {
+    /// Create a new list
+    Add { name: String },
+    /// Rename a list
+    Rename { name: String, new_name: String },
+    /// Hide a list from the switcher, keeping its todos
+    Archive { name: String },
+    /// Bring an archived list back
+    Unarchive { name: String },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum IcsAction {
+    /// Write the list's todos to stdout as an .ics calendar
+    Export {
+        /// Include completed todos
+        #[arg(long)]
+        completed: bool,
+    },
+    /// Import the VTODOs of an .ics file
+    Import {
+        file: PathBuf,
+        /// Only show what would be imported
+        #[arg(long)]
+        dry_run: bool,
+        /// Import without asking first
+        #[arg(long, short)]
+        yes: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum CaldavAction {
+    /// Carry changes both ways between the list and the collection
+    Sync {
+        /// URL of the collection, overriding the config
+        #[arg(long)]
+        url: Option<String>,
+        /// User to log in as, overriding the config
+        #[arg(long)]
+        username: Option<String>,
+        /// Which side wins when a todo changed on both, overriding the config
+        #[arg(long, value_enum)]
+        prefer: Option<ConflictPolicy>,
+    },
+}
+
+/// Runs a single command against the database and prints the result
+pub async fn run(
+    command: Command,
+    list: Option<&str>,
+    config: &Config,
+    pool: &SqlitePool,
+) -> Result<()> {
+    let caldav = config.caldav.as_ref();
+    // The CalDAV collection mirrors the list named in its config unless told otherwise
+    let list = match command {
+        Command::Caldav { .. } => list.or(caldav.and_then(|caldav| caldav.list.as_deref())),
+        _ => list,
+    };
+    let list_id = match list {
+        Some(name) => find_list(pool, name).await?.id,
+        None => INBOX_LIST_ID,
+    };
+
+    match command {
+        Command::Add {
+            text,
+            parent,
+            due,
+            scheduled,
+            every,
+            priority,
+        } => {
+            // Children live in their parent's list
+            let list_id = match parent {
+                Some(parent_id) => fetch_todo(pool, parent_id).await?.list_id,
+                None => list_id,
+            };
+
+            let input = parse_input(&text, Local::now().date_naive());
+            let mut todo_item = new_todo_item(&input.text, "", parent);
+            todo_item.list_id = list_id;
+            todo_item.due = due.or(input.due);
+            todo_item.scheduled = scheduled.or(input.scheduled);
+            todo_item.recurrence = every.or(input.recurrence);
+            todo_item.priority = priority.or(input.priority).unwrap_or_default();
+
+            let id = db::append_todo(pool, &todo_item).await?;
+            println!("{id}");
+        }
+        Command::List {
+            completed,
+            json,
+            tag,
+            by_priority,
+            trash,
+        } => {
+            let mut todos: Vec<TodoItem> = if trash {
+                db::deleted_todos(pool, list_id).await?
+            } else if completed {
+                db::completed_todos(pool, list_id).await?
+            } else {
+                db::uncompleted_todos(pool, list_id).await?
+            };
+            if !tag.is_empty() {
+                todos = filter_by_tags(todos, &tag);
+            }
+            if by_priority {
+                todos = sort_todos_hierarchically_by(todos, SortMode::Priority);
+            }
+
+            if json {
+                println!("{}", serde_json::to_string_pretty(&todos)?);
+            } else {
+                for (todo, depth) in todos.iter().zip(todo_depths(&todos)) {
+                    println!("{}", format_todo_line(todo, depth));
+                }
+            }
+        }
+        Command::Search { query, json } => {
+            let todos = db::search_todos(pool, list_id, &query).await?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&todos)?);
+            } else {
+                for todo in &todos {
+                    println!("{}", format_todo_line(todo, 0));
+                }
+            }
+        }
+        Command::Done { id } => {
+            let todo = fetch_todo(pool, id).await?;
+            if todo.completed_at.is_some() {
+                return Err(eyre!("todo {id} is already completed"));
+            }
+            db::toggle_todo_status_in_database(pool, todo.id).await?;
+        }
+        Command::Edit { id, text } => {
+            let todo = fetch_todo(pool, id).await?;
+            let input = parse_input(&text, Local::now().date_naive());
+            // Whatever the new text doesn't mention stays as it was
+            db::edit_todo(
+                pool,
+                id,
+                &input.text,
+                input.due.or(todo.due),
+                input.scheduled.or(todo.scheduled),
+                input.recurrence.as_ref().or(todo.recurrence.as_ref()),
+                input.priority.unwrap_or(todo.priority),
+            )
+            .await?;
+        }
+        Command::Rm { id } => {
+            fetch_todo(pool, id).await?;
+            db::delete_todo_from_database(pool, id).await?;
+        }
+        Command::Restore { id } => {
+            fetch_deleted_todo(pool, id).await?;
+            db::restore_deleted_todo(pool, id).await?;
+        }
+        Command::Purge { id: Some(id) } => {
+            fetch_deleted_todo(pool, id).await?;
+            db::purge_todo(pool, id).await?;
+        }
+        Command::Purge { id: None } => {
+            let purged = db::empty_trash(pool, list_id).await?;
+            println!("Purged {purged} todos");
+        }
+        Command::Lists { action: None } => {
+            for list in db::all_lists(pool).await? {
+                let archived = if list.archived { " (archived)" } else { "" };
+                println!("{:>4} {}{archived}", list.id, list.name);
+            }
+        }
+        Command::Lists {
+            action: Some(action),
+        } => match action {
+            ListsAction::Add { name } => {
+                let id = db::create_list(pool, name.trim()).await?;
+                println!("{id}");
+            }
+            ListsAction::Rename { name, new_name } => {
+                let list = find_list(pool, &name).await?;
+                db::rename_list(pool, list.id, new_name.trim()).await?;
+            }
+            ListsAction::Archive { name } => {
+                let list = find_list(pool, &name).await?;
+                if list.id == INBOX_LIST_ID {
+                    return Err(eyre!("the inbox can't be archived"));
+                }
+                db::set_list_archived(pool, list.id, true).await?;
+            }
+            ListsAction::Unarchive { name } => {
+                let list = find_list(pool, &name).await?;
+                db::set_list_archived(pool, list.id, false).await?;
+            }
+        },
+        Command::Mv { id, to } => {
+            fetch_todo(pool, id).await?;
+            let list = find_list(pool, &to).await?;
+            db::move_todo_to_list(pool, id, list.id).await?;
+        }
+        Command::Export { format, completed } => {
+            let todos: Vec<TodoItem> = if completed {
+                db::all_todos(pool, list_id).await?
+            } else {
+                db::uncompleted_todos(pool, list_id).await?
+            };
+            let mut out = std::io::BufWriter::new(std::io::stdout().lock());
+            export::write(format, &todos, &mut out)?;
+            out.flush()?;
+        }
+        Command::Import {
+            format,
+            file,
+            dry_run,
+            yes,
+        } => {
+            let content = std::fs::read_to_string(&file)?;
+            let import = import::parse(format, &content)?;
+            write_import(pool, list_id, import, dry_run, yes).await?;
+        }
+        Command::Ics {
+            action: IcsAction::Export { completed },
+        } => {
+            let todos: Vec<TodoItem> = if completed {
+                db::all_todos(pool, list_id).await?
+            } else {
+                db::uncompleted_todos(pool, list_id).await?
+            };
+            print!("{}", ics::to_ics(&todos));
+        }
+        Command::Ics {
+            action: IcsAction::Import { file, dry_run, yes },
+        } => {
+            let content = std::fs::read_to_string(&file)?;
+            write_import(pool, list_id, ics::parse_ics(&content), dry_run, yes).await?;
+        }
+        Command::Caldav {
+            action:
+                CaldavAction::Sync {
+                    url,
+                    username,
+                    prefer,
+                },
+        } => {
+            let url = url
+                .or_else(|| caldav.map(|caldav| caldav.url.clone()))
+                .ok_or_else(|| {
+                    eyre!(
+                        "no CalDAV collection, set url under [caldav] in the config or pass --url"
+                    )
+                })?;
+            let username = username.or_else(|| caldav.and_then(|caldav| caldav.username.clone()));
+            let password = caldav
+                .and_then(|caldav| caldav.password.clone())
+                .or_else(|| std::env::var("DODO_CALDAV_PASSWORD").ok());
+            let policy =
+                prefer.unwrap_or_else(|| caldav.map(|caldav| caldav.conflict).unwrap_or_default());
+
+            let collection = Collection::new(&url, username, password)?;
+            let report = caldav::sync(pool, &collection, list_id, policy).await?;
+            println!(
+                "pulled {}, pushed {}, deleted {} here and {} on the server, {} conflicts",
+                report.pulled,
+                report.pushed,
+                report.deleted_here,
+                report.deleted_there,
+                report.conflicts
+            );
+            for failure in &report.failures {
+                eprintln!("failed to sync {failure}");
+            }
+            if !report.failures.is_empty() {
+                return Err(eyre!(
+                    "{} todos weren't synced, they will be tried again next time",
+                    report.failures.len()
+                ));
+            }
+        }
+        Command::Sync { peer } => {
+            let report = sync::sync(pool, &peer, config.sync_token().as_deref()).await?;
+            println!("sent {} changes, received {}", report.sent, report.received);
+        }
+        Command::Serve { bind } => {
+            crate::server::serve(pool.clone(), &bind, config.sync_token()).await?;
+        }
+        Command::Log { tail, lines } => {
+            crate::logging::print(tail, lines).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Looks up a todo outside the trash in any list
+async fn fetch_todo(pool: &SqlitePool, id: i64) -> Result<TodoItem> {
+    db::get_todo(pool, id)
+        .await?
+        .filter(|todo| todo.deleted_at.is_none())
+        .ok_or_else(|| eyre!("no todo with id {id}"))
+}
+
+async fn fetch_deleted_todo(pool: &SqlitePool, id: i64) -> Result<TodoItem> {
+    db::get_todo(pool, id)
+        .await?
+        .filter(|todo| todo.deleted_at.is_some())
+        .ok_or_else(|| eyre!("no todo with id {id} in the trash"))
+}
+
+async fn find_list(pool: &SqlitePool, name: &str) -> Result<NamedList> {
+    db::list_by_name(pool, name)
+        .await?
+        .ok_or_else(|| eyre!("no list named '{name}'"))
+}
+
+/// Shows what an import found, then writes it to the list unless this is a
+/// dry run or the user says no
+async fn write_import(
+    pool: &SqlitePool,
+    list_id: i64,
+    mut import: Import,
+    dry_run: bool,
+    yes: bool,
+) -> Result<()> {
+    let list_name = db::all_lists(pool)
+        .await?
+        .into_iter()
+        .find(|list| list.id == list_id)
+        .map_or_else(|| "Inbox".to_string(), |list| list.name);
+
+    for (imported, depth) in import.todos.iter().zip(import.depths()) {
+        println!("{}", format_todo_line(&imported.todo, depth));
+    }
+    let completed = import
+        .todos
+        .iter()
+        .filter(|imported| imported.todo.completed_at.is_some())
+        .count();
+    let nested = import
+        .todos
+        .iter()
+        .filter(|imported| imported.parent.is_some())
+        .count();
+    println!(
+        "{} todos for {list_name}, {completed} completed and {nested} nested, {} skipped",
+        import.todos.len(),
+        import.skipped
+    );
+
+    if dry_run || import.todos.is_empty() {
+        return Ok(());
+    }
+    if !yes && !confirm("Import them?")? {
+        return Ok(());
+    }
+
+    // Top level todos go after the ones already in the list
+    let base = next_sort_order(&db::all_todos(pool, list_id).await?);
+    for imported in &mut import.todos {
+        imported.todo.list_id = list_id;
+        if imported.parent.is_none() {
+            imported.todo.sort_order += base - SORT_GAP;
+        }
+    }
+    let ids = db::insert_imported_todos(pool, &import.todos).await?;
+    println!("Imported {} todos", ids.len());
+
+    Ok(())
+}
+
+/// Asks a yes or no question on the terminal, taking anything but yes as no
+fn confirm(question: &str) -> Result<bool> {
+    print!("{question} [y/N] ");
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+fn parse_date_arg(input: &str) -> Result<NaiveDate, String> {
+    parse_natural_date(input, Local::now().date_naive())
+        .ok_or_else(|| format!("can't understand the date '{input}'"))
+}
+
+fn format_todo_line(todo: &TodoItem, depth: usize) -> String {
+    let indent = "  ".repeat(depth);
+    let checkbox = if todo.completed_at.is_none() {
+        "☐"
+    } else {
+        "✓"
+    };
+    let today = Local::now().date_naive();
+    let mut line = format!(
+        "{:>4} {}{} {}{}",
+        // Todos that are only being previewed have no id yet
+        todo.id.map(|id| id.to_string()).unwrap_or_default(),
+        indent,
+        checkbox,
+        todo.priority.marker(),
+        todo.todo
+    );
+    if let Some(scheduled) = todo.scheduled {
+        line.push_str(&format!(
+            " (scheduled {})",
+            format_relative(scheduled, today)
+        ));
+    }
+    if let Some(recurrence) = &todo.recurrence {
+        line.push_str(&format!(" (every {recurrence})"));
+    }
+    if let Some(due) = todo.due {
+        let overdue = if todo.is_overdue(today) {
+            ", overdue"
+        } else {
+            ""
+        };
+        line.push_str(&format!(" (due {}{overdue})", format_relative(due, today)));
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::add;
+
+    #[tokio::test]
+    async fn added_todos_go_after_every_sibling_like_in_the_tui() {
+        let pool = db::memory_pool().await;
+        let parent = add(&pool, "parent", None).await;
+        let trashed = add(&pool, "trashed child", Some(parent)).await;
+        db::delete_todo_from_database(&pool, trashed).await.unwrap();
+        let add_command = |text: &str, parent| Command::Add {
+            text: text.to_string(),
+            parent,
+            due: None,
+            scheduled: None,
+            every: None,
+            priority: None,
+        };
+
+        run(
+            add_command("new child", Some(parent)),
+            None,
+            &Config::default(),
+            &pool,
+        )
+        .await
+        .unwrap();
+        run(add_command("top", None), None, &Config::default(), &pool)
+            .await
+            .unwrap();
+
+        let todos = db::all_todos(&pool, INBOX_LIST_ID).await.unwrap();
+        let order = |text: &str| {
+            todos
+                .iter()
+                .find(|todo| todo.todo == text)
+                .map(|todo| todo.sort_order)
+                .unwrap()
+        };
+        let trashed = db::get_todo(&pool, trashed).await.unwrap().unwrap();
+        assert_eq!(order("new child"), trashed.sort_order + SORT_GAP);
+        assert_eq!(order("top"), order("parent") + SORT_GAP);
+    }
+}
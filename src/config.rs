@@ -0,0 +1,97 @@
+use color_eyre::Result;
+use serde::Deserialize;
+use std::env;
+use std::path::PathBuf;
+
+use crate::caldav::ConflictPolicy;
+
+/// Settings read from `$XDG_CONFIG_HOME/dodo/config.toml`
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct Config {
+    pub db: Option<PathBuf>,
+    /// Days a todo stays in the trash before it is purged, 0 keeps it forever
+    pub trash_retention_days: Option<u32>,
+    pub caldav: Option<CaldavConfig>,
+    /// Secret `dodo sync` sends and `dodo serve` asks for on `/api/sync`,
+    /// taken from `DODO_SYNC_TOKEN` when not set here
+    pub sync_token: Option<String>,
+}
+
+/// The `[caldav]` table, naming the collection `dodo caldav sync` mirrors a list to
+#[derive(Deserialize)]
+pub struct CaldavConfig {
+    pub url: String,
+    pub username: Option<String>,
+    /// Taken from `DODO_CALDAV_PASSWORD` when not set here
+    pub password: Option<String>,
+    /// Name of the list to sync, the inbox when not given
+    pub list: Option<String>,
+    #[serde(default)]
+    pub conflict: ConflictPolicy,
+}
+
+impl Config {
+    pub fn trash_retention_days(&self) -> u32 {
+        self.trash_retention_days.unwrap_or(30)
+    }
+
+    pub fn sync_token(&self) -> Option<String> {
+        self.sync_token
+            .clone()
+            .or_else(|| env::var("DODO_SYNC_TOKEN").ok())
+            .filter(|token| !token.is_empty())
+    }
+
+    /// Reads the config file, falling back to defaults when it doesn't exist
+    pub fn load() -> Result<Self> {
+        let path = config_path();
+        if !path.exists() {
+            return Ok(Config::default());
+        }
+        let contents = std::fs::read_to_string(&path)?;
+        Ok(toml::from_str(&contents)?)
+    }
+}
+
+pub fn config_path() -> PathBuf {
+    xdg_dir("XDG_CONFIG_HOME", ".config")
+        .join("dodo")
+        .join("config.toml")
+}
+
+pub fn log_path() -> PathBuf {
+    xdg_dir("XDG_STATE_HOME", ".local/state")
+        .join("dodo")
+        .join("dodo.log")
+}
+
+/// Picks the database from the `--db` flag, `DODO_DB`, the config file and
+/// finally `$XDG_DATA_HOME/dodo/todos.db`, in that order
+pub fn resolve_db_path(flag: Option<PathBuf>, config: &Config) -> PathBuf {
+    flag.or_else(|| env::var_os("DODO_DB").map(PathBuf::from))
+        .or_else(|| config.db.as_deref().map(expand_home))
+        .unwrap_or_else(|| {
+            xdg_dir("XDG_DATA_HOME", ".local/share")
+                .join("dodo")
+                .join("todos.db")
+        })
+}
+
+fn xdg_dir(var: &str, fallback: &str) -> PathBuf {
+    env::var_os(var)
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+        .unwrap_or_else(|| home_dir().join(fallback))
+}
+
+fn home_dir() -> PathBuf {
+    env::var_os("HOME").map(PathBuf::from).unwrap_or_default()
+}
+
+fn expand_home(path: &std::path::Path) -> PathBuf {
+    match path.strip_prefix("~") {
+        Ok(rest) => home_dir().join(rest),
+        Err(_) => path.to_path_buf(),
+    }
+}
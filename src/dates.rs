@@ -0,0 +1,146 @@
+use chrono::{Datelike, Days, Months, NaiveDate, Weekday};
+
+pub const DATE_FORMAT: &str = "%Y-%m-%d";
+
+/// Parses phrases like "today", "tomorrow", "fri", "+3d", "+2w", "+1m" or
+/// "2025-06-01" into a date relative to `today`
+pub fn parse_natural_date(input: &str, today: NaiveDate) -> Option<NaiveDate> {
+    let input = input.trim().to_lowercase();
+
+    match input.as_str() {
+        "today" | "tod" => return Some(today),
+        "tomorrow" | "tom" => return today.checked_add_days(Days::new(1)),
+        "yesterday" => return today.checked_sub_days(Days::new(1)),
+        _ => {}
+    }
+
+    if let Some(weekday) = parse_weekday(&input) {
+        let days_ahead = (7 + weekday.num_days_from_monday() as i64
+            - today.weekday().num_days_from_monday() as i64)
+            % 7;
+        return today.checked_add_days(Days::new(days_ahead as u64));
+    }
+
+    if let Some(offset) = input.strip_prefix('+') {
+        let unit = offset.chars().last()?;
+        let count: u32 = offset[..offset.len() - unit.len_utf8()].parse().ok()?;
+        return match unit {
+            'd' => today.checked_add_days(Days::new(count.into())),
+            'w' => u64::from(count)
+                .checked_mul(7)
+                .and_then(|days| today.checked_add_days(Days::new(days))),
+            'm' => today.checked_add_months(Months::new(count)),
+            'y' => count
+                .checked_mul(12)
+                .and_then(|months| today.checked_add_months(Months::new(months))),
+            _ => None,
+        };
+    }
+
+    NaiveDate::parse_from_str(&input, DATE_FORMAT).ok()
+}
+
+fn parse_weekday(input: &str) -> Option<Weekday> {
+    let weekday = match input {
+        "mon" | "monday" => Weekday::Mon,
+        "tue" | "tues" | "tuesday" => Weekday::Tue,
+        "wed" | "wednesday" => Weekday::Wed,
+        "thu" | "thur" | "thurs" | "thursday" => Weekday::Thu,
+        "fri" | "friday" => Weekday::Fri,
+        "sat" | "saturday" => Weekday::Sat,
+        "sun" | "sunday" => Weekday::Sun,
+        _ => return None,
+    };
+    Some(weekday)
+}
+
+/// Short label for a date in the list, e.g. "today", "tomorrow", "Fri 06-13"
+pub fn format_relative(date: NaiveDate, today: NaiveDate) -> String {
+    match (date - today).num_days() {
+        0 => "today".to_string(),
+        1 => "tomorrow".to_string(),
+        -1 => "yesterday".to_string(),
+        _ if date.year() == today.year() => date.format("%a %m-%d").to_string(),
+        _ => date.format(DATE_FORMAT).to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::date;
+
+    #[test]
+    fn parses_named_days() {
+        // A Wednesday
+        let today = date("2025-06-11");
+        assert_eq!(parse_natural_date("today", today), Some(today));
+        assert_eq!(
+            parse_natural_date("Tomorrow", today),
+            Some(date("2025-06-12"))
+        );
+        assert_eq!(
+            parse_natural_date("yesterday", today),
+            Some(date("2025-06-10"))
+        );
+        assert_eq!(parse_natural_date("fri", today), Some(date("2025-06-13")));
+        assert_eq!(
+            parse_natural_date("monday", today),
+            Some(date("2025-06-16"))
+        );
+        assert_eq!(parse_natural_date("wed", today), Some(today));
+    }
+
+    #[test]
+    fn parses_offsets() {
+        let today = date("2025-01-31");
+        assert_eq!(parse_natural_date("+3d", today), Some(date("2025-02-03")));
+        assert_eq!(parse_natural_date("+2w", today), Some(date("2025-02-14")));
+        // Clamped to the end of the shorter month
+        assert_eq!(parse_natural_date("+1m", today), Some(date("2025-02-28")));
+        assert_eq!(parse_natural_date("+1y", today), Some(date("2026-01-31")));
+    }
+
+    #[test]
+    fn parses_iso_dates() {
+        let today = date("2025-01-01");
+        assert_eq!(
+            parse_natural_date("2025-06-01", today),
+            Some(date("2025-06-01"))
+        );
+        assert_eq!(parse_natural_date("2025-02-30", today), None);
+    }
+
+    #[test]
+    fn rejects_nonsense() {
+        let today = date("2025-01-01");
+        for input in ["", "+", "+d", "+3x", "-3d", "someday", "+ü"] {
+            assert_eq!(parse_natural_date(input, today), None, "{input:?}");
+        }
+    }
+
+    #[test]
+    fn huge_offsets_are_rejected_rather_than_overflowing() {
+        let today = date("2025-01-01");
+        for input in [
+            "+400000000y",
+            "+4294967295y",
+            "+4294967295w",
+            "+4294967295m",
+            "+4294967295d",
+        ] {
+            assert_eq!(parse_natural_date(input, today), None, "{input:?}");
+        }
+        assert_eq!(parse_natural_date("+4294967296d", today), None);
+    }
+
+    #[test]
+    fn formats_relative_labels() {
+        let today = date("2025-06-11");
+        assert_eq!(format_relative(today, today), "today");
+        assert_eq!(format_relative(date("2025-06-12"), today), "tomorrow");
+        assert_eq!(format_relative(date("2025-06-10"), today), "yesterday");
+        assert_eq!(format_relative(date("2025-06-13"), today), "Fri 06-13");
+        assert_eq!(format_relative(date("2026-06-13"), today), "2026-06-13");
+    }
+}
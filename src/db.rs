@@ -1,10 +1,21 @@
-use crate::models::TodoItem;
+use crate::dates::DATE_FORMAT;
+use crate::import::ImportedTodo;
+use crate::input::parse_tags;
+use crate::models::{
+    CaldavResource, Change, INBOX_LIST_ID, NamedList, Priority, SORT_GAP, TodoItem,
+};
 use crate::models::{TodoRow, parse_date_string, sort_todos_hierarchically};
-use sqlx::sqlite::SqlitePool;
+use crate::recurrence::Recurrence;
+use chrono::{Local, NaiveDate, NaiveDateTime};
+use sqlx::Connection;
+use sqlx::sqlite::{SqliteConnection, SqlitePool};
+use std::collections::HashMap;
 
-pub async fn create_todos_table(pool: &SqlitePool) -> Result<(), sqlx::Error> {
-    sqlx::query(
-        r#"
+/// Schema migrations in the order they are applied. The schema version stored
+/// in the database is the number of migrations it has seen, so existing
+/// entries must never be edited or reordered, only appended to.
+const MIGRATIONS: &[&str] = &[
+    r#"
       CREATE TABLE IF NOT EXISTS todos (
           id INTEGER PRIMARY KEY AUTOINCREMENT,
           todo TEXT NOT NULL,
@@ -15,22 +26,281 @@ pub async fn create_todos_table(pool: &SqlitePool) -> Result<(), sqlx::Error> {
           sort_order INTEGER NOT NULL DEFAULT 0
       )
       "#,
-    )
-    .execute(pool)
-    .await?;
+    r#"
+      ALTER TABLE todos ADD COLUMN due TEXT NULL;
+      ALTER TABLE todos ADD COLUMN scheduled TEXT NULL;
+      "#,
+    r#"
+      ALTER TABLE todos ADD COLUMN recurrence TEXT NULL;
+      "#,
+    r#"
+      CREATE TABLE tags (
+          id INTEGER PRIMARY KEY AUTOINCREMENT,
+          name TEXT NOT NULL UNIQUE
+      );
+      CREATE TABLE todo_tags (
+          todo_id INTEGER NOT NULL REFERENCES todos(id) ON DELETE CASCADE,
+          tag_id INTEGER NOT NULL REFERENCES tags(id) ON DELETE CASCADE,
+          PRIMARY KEY (todo_id, tag_id)
+      );
+      "#,
+    r#"
+      ALTER TABLE todos ADD COLUMN priority INTEGER NOT NULL DEFAULT 0;
+      "#,
+    r#"
+      ALTER TABLE todos ADD COLUMN collapsed INTEGER NOT NULL DEFAULT 0;
+      "#,
+    r#"
+      CREATE TABLE history (
+          id INTEGER PRIMARY KEY AUTOINCREMENT,
+          action TEXT NOT NULL,
+          before_state TEXT NOT NULL,
+          after_state TEXT NOT NULL,
+          undone INTEGER NOT NULL DEFAULT 0,
+          created_at TEXT NOT NULL
+      );
+      "#,
+    r#"
+      ALTER TABLE todos ADD COLUMN deleted_at TEXT NULL;
+      "#,
+    r#"
+      CREATE VIRTUAL TABLE todos_fts USING fts5(
+          todo, details, content = 'todos', content_rowid = 'id', prefix = '2 3'
+      );
+      INSERT INTO todos_fts (todos_fts) VALUES ('rebuild');
+      CREATE TRIGGER todos_fts_insert AFTER INSERT ON todos BEGIN
+          INSERT INTO todos_fts (rowid, todo, details) VALUES (new.id, new.todo, new.details);
+      END;
+      CREATE TRIGGER todos_fts_delete AFTER DELETE ON todos BEGIN
+          INSERT INTO todos_fts (todos_fts, rowid, todo, details) VALUES ('delete', old.id, old.todo, old.details);
+      END;
+      CREATE TRIGGER todos_fts_update AFTER UPDATE OF todo, details ON todos BEGIN
+          INSERT INTO todos_fts (todos_fts, rowid, todo, details) VALUES ('delete', old.id, old.todo, old.details);
+          INSERT INTO todos_fts (rowid, todo, details) VALUES (new.id, new.todo, new.details);
+      END;
+      "#,
+    // SQLite won't add a column with both REFERENCES and a non-null default,
+    // so the link to lists is kept up by dodo rather than a foreign key
+    r#"
+      CREATE TABLE lists (
+          id INTEGER PRIMARY KEY AUTOINCREMENT,
+          name TEXT NOT NULL UNIQUE,
+          archived_at TEXT NULL
+      );
+      INSERT INTO lists (id, name) VALUES (1, 'Inbox');
+      ALTER TABLE todos ADD COLUMN list_id INTEGER NOT NULL DEFAULT 1;
+      CREATE INDEX todos_list_id ON todos (list_id);
+      "#,
+    // todo_id goes NULL when a todo is purged, which tells the next CalDAV
+    // sync to delete it from the server too
+    r#"
+      CREATE TABLE caldav_resources (
+          collection TEXT NOT NULL,
+          href TEXT NOT NULL,
+          uid TEXT NOT NULL,
+          etag TEXT NULL,
+          synced TEXT NOT NULL,
+          todo_id INTEGER NULL REFERENCES todos(id) ON DELETE SET NULL,
+          PRIMARY KEY (collection, href)
+      );
+      "#,
+    // Every change to a todo is logged against a UUID that stays the same on
+    // every machine, for `dodo sync`. Existing todos get a UUID made from
+    // their row id and creation time, so copies of one database agree on it.
+    // Their current values are logged as older than any real change, except
+    // that completing or trashing a todo is logged as of when it happened.
+    r#"
+      ALTER TABLE todos ADD COLUMN uuid TEXT NULL;
+      UPDATE todos SET uuid = printf('%08x-0000-4000-8000-%012x', id, CAST(strftime('%s', date) AS INTEGER));
+      CREATE UNIQUE INDEX todos_uuid ON todos (uuid);
+
+      CREATE TABLE changes (
+          seq INTEGER PRIMARY KEY AUTOINCREMENT,
+          change_id TEXT NOT NULL UNIQUE,
+          todo_uuid TEXT NOT NULL,
+          field TEXT NOT NULL,
+          value TEXT NULL,
+          changed_at TEXT NOT NULL
+      );
+      CREATE INDEX changes_todo_field ON changes (todo_uuid, field);
+      CREATE TABLE sync_state (applying INTEGER NOT NULL);
+      INSERT INTO sync_state (applying) VALUES (0);
+      CREATE TABLE sync_peers (
+          peer TEXT PRIMARY KEY,
+          sent INTEGER NOT NULL DEFAULT 0,
+          received INTEGER NOT NULL DEFAULT 0
+      );
+
+      CREATE VIEW todo_fields AS
+          SELECT id AS todo_id, uuid AS todo_uuid, 'todo' AS field, todo AS value FROM todos
+          UNION ALL SELECT id, uuid, 'details', details FROM todos
+          UNION ALL SELECT id, uuid, 'date', date FROM todos
+          UNION ALL SELECT id, uuid, 'completed_at', completed_at FROM todos
+          UNION ALL SELECT id, uuid, 'sort_order', sort_order FROM todos
+          UNION ALL SELECT id, uuid, 'due', due FROM todos
+          UNION ALL SELECT id, uuid, 'scheduled', scheduled FROM todos
+          UNION ALL SELECT id, uuid, 'recurrence', recurrence FROM todos
+          UNION ALL SELECT id, uuid, 'priority', priority FROM todos
+          UNION ALL SELECT id, uuid, 'deleted_at', deleted_at FROM todos
+          UNION ALL SELECT todos.id, todos.uuid, 'parent', parents.uuid FROM todos
+              LEFT JOIN todos AS parents ON parents.id = todos.parent_id
+          UNION ALL SELECT todos.id, todos.uuid, 'list', lists.name FROM todos
+              LEFT JOIN lists ON lists.id = todos.list_id;
+      INSERT INTO changes (change_id, todo_uuid, field, value, changed_at)
+          SELECT lower(hex(randomblob(16))), todo_uuid, field, value, CASE
+              WHEN field IN ('completed_at', 'deleted_at') AND value <> ''
+              THEN coalesce(strftime('%Y-%m-%d %H:%M:%f', value, 'utc'), '1970-01-01 00:00:00.000')
+              ELSE '1970-01-01 00:00:00.000'
+          END
+          FROM todo_fields;
+
+      CREATE TRIGGER todos_log_insert AFTER INSERT ON todos BEGIN
+          UPDATE todos SET uuid = lower(printf('%s-%s-4%s-%s%s-%s',
+              hex(randomblob(4)), hex(randomblob(2)), substr(hex(randomblob(2)), 2),
+              substr('89ab', 1 + abs(random() % 4), 1), substr(hex(randomblob(2)), 2),
+              hex(randomblob(6))))
+          WHERE id = new.id AND uuid IS NULL;
+          INSERT INTO changes (change_id, todo_uuid, field, value, changed_at)
+              SELECT lower(hex(randomblob(16))), todo_uuid, field, value, strftime('%Y-%m-%d %H:%M:%f', 'now')
+              FROM todo_fields
+              WHERE todo_id = new.id AND (SELECT applying FROM sync_state) = 0;
+      END;
+      CREATE TRIGGER todos_log_update AFTER UPDATE ON todos
+      WHEN (SELECT applying FROM sync_state) = 0 BEGIN
+          INSERT INTO changes (change_id, todo_uuid, field, value, changed_at)
+              SELECT lower(hex(randomblob(16))), todo_uuid, field, value, strftime('%Y-%m-%d %H:%M:%f', 'now')
+              FROM todo_fields
+              WHERE todo_id = new.id AND field IN (
+                  SELECT 'todo' WHERE old.todo IS NOT new.todo
+                  UNION ALL SELECT 'details' WHERE old.details IS NOT new.details
+                  UNION ALL SELECT 'date' WHERE old.date IS NOT new.date
+                  UNION ALL SELECT 'completed_at' WHERE old.completed_at IS NOT new.completed_at
+                  UNION ALL SELECT 'sort_order' WHERE old.sort_order IS NOT new.sort_order
+                  UNION ALL SELECT 'due' WHERE old.due IS NOT new.due
+                  UNION ALL SELECT 'scheduled' WHERE old.scheduled IS NOT new.scheduled
+                  UNION ALL SELECT 'recurrence' WHERE old.recurrence IS NOT new.recurrence
+                  UNION ALL SELECT 'priority' WHERE old.priority IS NOT new.priority
+                  UNION ALL SELECT 'deleted_at' WHERE old.deleted_at IS NOT new.deleted_at
+                  UNION ALL SELECT 'parent' WHERE old.parent_id IS NOT new.parent_id
+                  UNION ALL SELECT 'list' WHERE old.list_id IS NOT new.list_id
+              );
+      END;
+      CREATE TRIGGER todos_log_delete AFTER DELETE ON todos
+      WHEN (SELECT applying FROM sync_state) = 0 BEGIN
+          INSERT INTO changes (change_id, todo_uuid, field, value, changed_at)
+              VALUES (lower(hex(randomblob(16))), old.uuid, 'purged', '1', strftime('%Y-%m-%d %H:%M:%f', 'now'));
+      END;
+      CREATE TRIGGER lists_log_rename AFTER UPDATE OF name ON lists
+      WHEN (SELECT applying FROM sync_state) = 0 BEGIN
+          INSERT INTO changes (change_id, todo_uuid, field, value, changed_at)
+              SELECT lower(hex(randomblob(16))), todo_uuid, field, value, strftime('%Y-%m-%d %H:%M:%f', 'now')
+              FROM todo_fields
+              WHERE field = 'list' AND value = new.name;
+      END;
+      "#,
+];
+
+/// Selects the id of the todo bound as the first parameter and all of its descendants
+const SUBTREE_CTE: &str = "WITH RECURSIVE subtree(id) AS (SELECT ? UNION ALL SELECT todos.id FROM todos JOIN subtree ON todos.parent_id = subtree.id)";
+
+/// The parent of the todo bound to it and so on up, ending with a NULL
+const ANCESTORS_CTE: &str = "WITH RECURSIVE ancestors(id) AS (SELECT parent_id FROM todos WHERE id = ? UNION ALL SELECT todos.parent_id FROM todos JOIN ancestors ON todos.id = ancestors.id)";
+
+const TODO_COLUMNS: &str = "id, todo, details, completed_at, date, parent_id, sort_order, due, scheduled, recurrence, priority, collapsed, deleted_at, list_id";
+
+#[derive(Debug)]
+pub enum MigrationError {
+    Database(sqlx::Error),
+    /// The database was written by a newer dodo than this one
+    TooNew {
+        found: i64,
+        supported: i64,
+    },
+}
+
+impl std::fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MigrationError::Database(e) => write!(f, "{e}"),
+            MigrationError::TooNew { found, supported } => write!(
+                f,
+                "database schema version {found} is newer than the {supported} this dodo understands, upgrade dodo to open it"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MigrationError {}
+
+impl From<sqlx::Error> for MigrationError {
+    fn from(e: sqlx::Error) -> Self {
+        MigrationError::Database(e)
+    }
+}
+
+pub async fn schema_version(conn: &mut SqliteConnection) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar("PRAGMA user_version")
+        .fetch_one(conn)
+        .await
+}
+
+/// Brings the database schema up to date, applying each pending migration in its own transaction
+pub async fn migrate(pool: &SqlitePool) -> Result<(), MigrationError> {
+    let supported = MIGRATIONS.len() as i64;
+    // Everything runs on one connection, as a pooled connection opened partway
+    // through can be left without the new tables
+    let mut conn = pool.acquire().await?;
+    let found = schema_version(&mut conn).await?;
+
+    if found > supported {
+        return Err(MigrationError::TooNew { found, supported });
+    }
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(found as usize) {
+        let mut tx = conn.begin().await?;
+        sqlx::raw_sql(migration).execute(&mut *tx).await?;
+        // PRAGMA doesn't accept bound parameters
+        sqlx::raw_sql(&format!("PRAGMA user_version = {}", index + 1))
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+    }
+
     Ok(())
 }
 
-pub async fn all_todos(pool: &SqlitePool) -> Result<Vec<TodoItem>, sqlx::Error> {
-    let rows = sqlx::query_as::<_, TodoRow>(
-        "SELECT id, todo, details, completed_at, date, parent_id, sort_order FROM todos ORDER BY sort_order",
-    )
-        .fetch_all(pool)
-        .await?;
+/// A migrated database that lives as long as the pool, for tests. One
+/// connection, as each in-memory connection would get its own database.
+#[cfg(test)]
+pub async fn memory_pool() -> SqlitePool {
+    let pool = sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(1)
+        .idle_timeout(None)
+        .max_lifetime(None)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    migrate(&pool).await.unwrap();
+    pool
+}
 
-    let todo_items: Vec<TodoItem> = rows
-        .into_iter()
-        .map(|row| TodoItem {
+/// A day in `DATE_FORMAT`, for tests
+#[cfg(test)]
+pub fn date(s: &str) -> NaiveDate {
+    NaiveDate::parse_from_str(s, DATE_FORMAT).unwrap()
+}
+
+/// Adds a todo to the inbox after its siblings, as the TUI does, for tests
+#[cfg(test)]
+pub async fn add(pool: &SqlitePool, text: &str, parent_id: Option<i64>) -> i64 {
+    append_todo(pool, &crate::models::new_todo_item(text, "", parent_id))
+        .await
+        .unwrap()
+}
+
+impl From<TodoRow> for TodoItem {
+    fn from(row: TodoRow) -> Self {
+        TodoItem {
             id: Some(row.id),
             todo: row.todo,
             details: row.details,
@@ -42,14 +312,207 @@ pub async fn all_todos(pool: &SqlitePool) -> Result<Vec<TodoItem>, sqlx::Error>
             date: parse_date_string(&row.date),
             parent_id: row.parent_id,
             sort_order: row.sort_order,
+            due: row.due.as_deref().and_then(parse_day_string),
+            scheduled: row.scheduled.as_deref().and_then(parse_day_string),
+            recurrence: row.recurrence.and_then(|rule| rule.parse().ok()),
+            tags: Vec::new(),
+            priority: Priority::from_level(row.priority),
+            collapsed: row.collapsed,
+            deleted_at: row.deleted_at.as_deref().map(parse_date_string),
+            list_id: row.list_id,
+        }
+    }
+}
+
+/// Every todo in the list that isn't in the trash
+pub async fn all_todos(pool: &SqlitePool, list_id: i64) -> Result<Vec<TodoItem>, sqlx::Error> {
+    load_todos(pool, "deleted_at IS NULL", Some(list_id)).await
+}
+
+/// The todos of the list that are in the trash
+pub async fn deleted_todos(pool: &SqlitePool, list_id: i64) -> Result<Vec<TodoItem>, sqlx::Error> {
+    load_todos(pool, "deleted_at IS NOT NULL", Some(list_id)).await
+}
+
+/// The todos with the given ids along with their UUIDs, parents first.
+/// Looked up by primary key, so it costs the same however big the table is.
+/// Tags are left out, as restoring a todo derives them from its text again.
+pub async fn todos_for_history(
+    pool: &SqlitePool,
+    ids: &[i64],
+) -> Result<Vec<(TodoItem, Option<String>)>, sqlx::Error> {
+    #[derive(sqlx::FromRow)]
+    struct HistoryRow {
+        #[sqlx(flatten)]
+        todo: TodoRow,
+        uuid: Option<String>,
+    }
+
+    let rows = sqlx::query_as::<_, HistoryRow>(&format!(
+        "SELECT {TODO_COLUMNS}, uuid FROM todos WHERE id IN (SELECT value FROM json_each(?)) ORDER BY sort_order"
+    ))
+    .bind(id_list(ids)?)
+    .fetch_all(pool)
+    .await?;
+    let mut uuids: HashMap<i64, Option<String>> = rows
+        .iter()
+        .map(|row| (row.todo.id, row.uuid.clone()))
+        .collect();
+
+    Ok(sort_todos_hierarchically(
+        rows.into_iter()
+            .map(|row| TodoItem::from(row.todo))
+            .collect(),
+    )
+    .into_iter()
+    .map(|todo| {
+        let uuid = todo.id.and_then(|id| uuids.remove(&id)).flatten();
+        (todo, uuid)
+    })
+    .collect())
+}
+
+/// Ids as a JSON array, for binding to `json_each`
+fn id_list(ids: &[i64]) -> Result<String, sqlx::Error> {
+    serde_json::to_string(ids).map_err(|e| sqlx::Error::Encode(Box::new(e)))
+}
+
+async fn load_todos(
+    pool: &SqlitePool,
+    condition: &str,
+    list_id: Option<i64>,
+) -> Result<Vec<TodoItem>, sqlx::Error> {
+    let rows = sqlx::query_as::<_, TodoRow>(&format!(
+        "SELECT {TODO_COLUMNS} FROM todos WHERE {condition} AND list_id = COALESCE(?, list_id) ORDER BY sort_order"
+    ))
+    .bind(list_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(sort_todos_hierarchically(with_tags(pool, rows).await?))
+}
+
+/// Full-text searches the text and details of the list's todos outside the
+/// trash, best match first. Takes FTS5 query syntax: `"buy milk"` for a
+/// phrase, `gard*` for a prefix and `AND`, `OR` and `NOT` between terms.
+pub async fn search_todos(
+    pool: &SqlitePool,
+    list_id: i64,
+    query: &str,
+) -> Result<Vec<TodoItem>, sqlx::Error> {
+    let rows = sqlx::query_as::<_, TodoRow>(&format!(
+        r#"
+        SELECT {TODO_COLUMNS} FROM todos
+        JOIN (SELECT rowid, rank FROM todos_fts WHERE todos_fts MATCH ?) AS hits ON hits.rowid = todos.id
+        WHERE deleted_at IS NULL AND list_id = ?
+        ORDER BY hits.rank
+        "#
+    ))
+    .bind(query)
+    .bind(list_id)
+    .fetch_all(pool)
+    .await?;
+
+    with_tags(pool, rows).await
+}
+
+async fn with_tags(pool: &SqlitePool, rows: Vec<TodoRow>) -> Result<Vec<TodoItem>, sqlx::Error> {
+    let tags: Vec<(i64, String)> = sqlx::query_as(
+        "SELECT todo_tags.todo_id, tags.name FROM todo_tags JOIN tags ON tags.id = todo_tags.tag_id ORDER BY tags.name",
+    )
+    .fetch_all(pool)
+    .await?;
+    let mut tags_by_todo: HashMap<i64, Vec<String>> = HashMap::new();
+    for (todo_id, name) in tags {
+        tags_by_todo.entry(todo_id).or_default().push(name);
+    }
+
+    let todo_items: Vec<TodoItem> = rows
+        .into_iter()
+        .map(|row| {
+            let mut item = TodoItem::from(row);
+            item.tags = item
+                .id
+                .and_then(|id| tags_by_todo.remove(&id))
+                .unwrap_or_default();
+            item
         })
         .collect();
 
-    Ok(sort_todos_hierarchically(todo_items))
+    Ok(todo_items)
+}
+
+pub async fn todo_by_id(
+    conn: &mut SqliteConnection,
+    todo_id: i64,
+) -> Result<Option<TodoItem>, sqlx::Error> {
+    let row =
+        sqlx::query_as::<_, TodoRow>(&format!("SELECT {TODO_COLUMNS} FROM todos WHERE id = ?"))
+            .bind(todo_id)
+            .fetch_optional(&mut *conn)
+            .await?;
+
+    let Some(mut item) = row.map(TodoItem::from) else {
+        return Ok(None);
+    };
+
+    item.tags = sqlx::query_scalar(
+        "SELECT tags.name FROM todo_tags JOIN tags ON tags.id = todo_tags.tag_id WHERE todo_tags.todo_id = ? ORDER BY tags.name",
+    )
+    .bind(todo_id)
+    .fetch_all(&mut *conn)
+    .await?;
+
+    Ok(Some(item))
+}
+
+/// Points the todo's rows in `todo_tags` at the tags found in its text
+async fn sync_todo_tags(
+    conn: &mut SqliteConnection,
+    todo_id: i64,
+    text: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM todo_tags WHERE todo_id = ?")
+        .bind(todo_id)
+        .execute(&mut *conn)
+        .await?;
+
+    for tag in parse_tags(text) {
+        sqlx::query("INSERT OR IGNORE INTO tags (name) VALUES (?)")
+            .bind(&tag)
+            .execute(&mut *conn)
+            .await?;
+        sqlx::query(
+            "INSERT INTO todo_tags (todo_id, tag_id) SELECT ?, id FROM tags WHERE name = ?",
+        )
+        .bind(todo_id)
+        .bind(&tag)
+        .execute(&mut *conn)
+        .await?;
+    }
+
+    Ok(())
 }
 
-pub async fn uncompleted_todos(pool: &SqlitePool) -> Result<Vec<TodoItem>, sqlx::Error> {
-    let uncompleted_todos: Vec<TodoItem> = all_todos(pool)
+/// Every tag in use by at least one todo in the list
+pub async fn all_tags(pool: &SqlitePool, list_id: i64) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query_scalar(
+        "SELECT DISTINCT tags.name FROM tags JOIN todo_tags ON todo_tags.tag_id = tags.id JOIN todos ON todos.id = todo_tags.todo_id WHERE todos.deleted_at IS NULL AND todos.list_id = ? ORDER BY tags.name",
+    )
+    .bind(list_id)
+    .fetch_all(pool)
+    .await
+}
+
+fn parse_day_string(date_str: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(date_str, DATE_FORMAT).ok()
+}
+
+pub async fn uncompleted_todos(
+    pool: &SqlitePool,
+    list_id: i64,
+) -> Result<Vec<TodoItem>, sqlx::Error> {
+    let uncompleted_todos: Vec<TodoItem> = all_todos(pool, list_id)
         .await?
         .into_iter()
         .filter(|item| item.completed_at.is_none())
@@ -58,8 +521,11 @@ pub async fn uncompleted_todos(pool: &SqlitePool) -> Result<Vec<TodoItem>, sqlx:
     Ok(uncompleted_todos)
 }
 
-pub async fn completed_todos(pool: &SqlitePool) -> Result<Vec<TodoItem>, sqlx::Error> {
-    let completed_todos: Vec<TodoItem> = all_todos(pool)
+pub async fn completed_todos(
+    pool: &SqlitePool,
+    list_id: i64,
+) -> Result<Vec<TodoItem>, sqlx::Error> {
+    let completed_todos: Vec<TodoItem> = all_todos(pool, list_id)
         .await?
         .into_iter()
         .filter(|item| item.completed_at.is_some())
@@ -68,40 +534,303 @@ pub async fn completed_todos(pool: &SqlitePool) -> Result<Vec<TodoItem>, sqlx::E
     Ok(completed_todos)
 }
 
-pub async fn write_input_to_database(
+/// Inserts a todo along with its tags after every sibling it will have,
+/// whatever sort order it came with, and returns the id of the new row
+pub async fn append_todo(pool: &SqlitePool, todo: &TodoItem) -> Result<i64, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let siblings = Siblings {
+        todo_id: None,
+        list_id: todo.list_id,
+        parent: todo.parent_id,
+    };
+    let last = siblings.last(&mut tx).await?.unwrap_or(0);
+    let sort_order = i32::try_from(last + i64::from(SORT_GAP))
+        .map_err(|_| sqlx::Error::Protocol("no room left to add todo".to_string()))?;
+    let id = insert_todo(
+        &mut tx,
+        &TodoItem {
+            sort_order,
+            ..todo.clone()
+        },
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok(id)
+}
+
+/// Inserts imported todos in one transaction, pointing each at the new id of
+/// its parent, and returns their ids
+pub async fn insert_imported_todos(
     pool: &SqlitePool,
+    todos: &[ImportedTodo],
+) -> Result<Vec<i64>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let mut ids: Vec<i64> = Vec::with_capacity(todos.len());
+    for imported in todos {
+        let mut todo = imported.todo.clone();
+        todo.parent_id = imported.parent.map(|index| ids[index]);
+        ids.push(insert_todo(&mut tx, &todo).await?);
+    }
+    tx.commit().await?;
+
+    Ok(ids)
+}
+
+async fn insert_todo(conn: &mut SqliteConnection, todo: &TodoItem) -> Result<i64, sqlx::Error> {
+    let query = "INSERT INTO todos (todo, details, date, completed_at, parent_id, sort_order, due, scheduled, recurrence, priority, collapsed, list_id) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)";
+
+    let result = sqlx::query(query)
+        .bind(&todo.todo)
+        .bind(&todo.details)
+        .bind(todo.date.format("%Y-%m-%d %H:%M:%S").to_string())
+        .bind(
+            todo.completed_at
+                .map(|d| d.format("%Y-%m-%d %H:%M:%S").to_string()),
+        )
+        .bind(todo.parent_id)
+        .bind(todo.sort_order)
+        .bind(todo.due.map(|d| d.format(DATE_FORMAT).to_string()))
+        .bind(todo.scheduled.map(|d| d.format(DATE_FORMAT).to_string()))
+        .bind(todo.recurrence.as_ref().map(|rule| rule.to_string()))
+        .bind(todo.priority.level())
+        .bind(todo.collapsed)
+        .bind(todo.list_id)
+        .execute(&mut *conn)
+        .await?;
+
+    let id = result.last_insert_rowid();
+    sync_todo_tags(conn, id, &todo.todo).await?;
+
+    Ok(id)
+}
+
+/// Writes a todo back exactly as it was, recreating the row if it is gone.
+/// A recreated row gets `uuid` back when given, so other machines see the
+/// same todo return rather than a new one, and the log records that it was
+/// restored after being purged.
+pub async fn restore_todo(
+    conn: &mut SqliteConnection,
     todo: &TodoItem,
+    uuid: Option<&str>,
 ) -> Result<(), sqlx::Error> {
-    let query = "INSERT INTO todos (todo, details, date, completed_at, parent_id, sort_order) VALUES (?, ?, ?, ?, ?, ?)";
+    let Some(id) = todo.id else {
+        return Ok(());
+    };
+    let existed: bool = sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM todos WHERE id = ?)")
+        .bind(id)
+        .fetch_one(&mut *conn)
+        .await?;
+
+    // A sync may have brought the todo back under another id meanwhile
+    let query = r#"
+        INSERT INTO todos (id, todo, details, date, completed_at, parent_id, sort_order, due, scheduled, recurrence, priority, collapsed, deleted_at, list_id, uuid)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, (SELECT ? WHERE NOT EXISTS (SELECT 1 FROM todos WHERE uuid = ?)))
+        ON CONFLICT (id) DO UPDATE SET
+            todo = excluded.todo,
+            details = excluded.details,
+            date = excluded.date,
+            completed_at = excluded.completed_at,
+            parent_id = excluded.parent_id,
+            sort_order = excluded.sort_order,
+            due = excluded.due,
+            scheduled = excluded.scheduled,
+            recurrence = excluded.recurrence,
+            priority = excluded.priority,
+            collapsed = excluded.collapsed,
+            deleted_at = excluded.deleted_at,
+            list_id = excluded.list_id
+        "#;
 
     sqlx::query(query)
+        .bind(id)
         .bind(&todo.todo)
         .bind(&todo.details)
-        .bind(&todo.date.format("%Y-%m-%d %H:%M:%S").to_string())
+        .bind(todo.date.format("%Y-%m-%d %H:%M:%S").to_string())
         .bind(
-            &todo
-                .completed_at
+            todo.completed_at
                 .map(|d| d.format("%Y-%m-%d %H:%M:%S").to_string()),
         )
-        .bind(&todo.parent_id)
-        .bind(&todo.sort_order)
-        .execute(pool)
+        .bind(todo.parent_id)
+        .bind(todo.sort_order)
+        .bind(todo.due.map(|d| d.format(DATE_FORMAT).to_string()))
+        .bind(todo.scheduled.map(|d| d.format(DATE_FORMAT).to_string()))
+        .bind(todo.recurrence.as_ref().map(|rule| rule.to_string()))
+        .bind(todo.priority.level())
+        .bind(todo.collapsed)
+        .bind(
+            todo.deleted_at
+                .map(|d| d.format("%Y-%m-%d %H:%M:%S").to_string()),
+        )
+        .bind(todo.list_id)
+        .bind(uuid)
+        .bind(uuid)
+        .execute(&mut *conn)
         .await?;
+    sync_todo_tags(&mut *conn, id, &todo.todo).await?;
 
+    if !existed {
+        sqlx::query(
+            r#"
+            INSERT INTO changes (change_id, todo_uuid, field, value, changed_at)
+            SELECT lower(hex(randomblob(16))), uuid, 'restored', NULL, strftime('%Y-%m-%d %H:%M:%f', 'now')
+            FROM todos WHERE id = ? AND (SELECT applying FROM sync_state) = 0
+            AND EXISTS (SELECT 1 FROM changes WHERE todo_uuid = todos.uuid AND field = 'purged')
+            "#,
+        )
+        .bind(id)
+        .execute(&mut *conn)
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// The id of a todo followed by those of all its descendants, trashed or not
+pub async fn subtree_ids(pool: &SqlitePool, todo_id: i64) -> Result<Vec<i64>, sqlx::Error> {
+    sqlx::query_scalar(&format!("{SUBTREE_CTE} SELECT id FROM subtree"))
+        .bind(todo_id)
+        .fetch_all(pool)
+        .await
+}
+
+/// The ids of a todo's parent, its parent's parent and so on up to the top
+pub async fn ancestor_ids(pool: &SqlitePool, todo_id: i64) -> Result<Vec<i64>, sqlx::Error> {
+    sqlx::query_scalar(&format!(
+        "{ANCESTORS_CTE} SELECT id FROM ancestors WHERE id IS NOT NULL"
+    ))
+    .bind(todo_id)
+    .fetch_all(pool)
+    .await
+}
+
+/// Moves a todo and everything under it to the trash
+pub async fn delete_todo_from_database(pool: &SqlitePool, todo_id: i64) -> Result<(), sqlx::Error> {
+    let now = Local::now().naive_local();
+    sqlx::query(&format!(
+        "{SUBTREE_CTE} UPDATE todos SET deleted_at = ? WHERE id IN (SELECT id FROM subtree) AND deleted_at IS NULL"
+    ))
+    .bind(todo_id)
+    .bind(now.format("%Y-%m-%d %H:%M:%S").to_string())
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Takes a todo out of the trash along with the descendants that were
+/// trashed with it, and any trashed ancestors so it isn't left orphaned
+pub async fn restore_deleted_todo(pool: &SqlitePool, todo_id: i64) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let deleted_at: Option<String> =
+        sqlx::query_scalar("SELECT deleted_at FROM todos WHERE id = ?")
+            .bind(todo_id)
+            .fetch_optional(&mut *tx)
+            .await?
+            .flatten();
+    let Some(deleted_at) = deleted_at else {
+        return Ok(());
+    };
+
+    sqlx::query(&format!(
+        "{SUBTREE_CTE} UPDATE todos SET deleted_at = NULL WHERE id IN (SELECT id FROM subtree) AND deleted_at = ?"
+    ))
+    .bind(todo_id)
+    .bind(&deleted_at)
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query(&format!(
+        "{ANCESTORS_CTE} UPDATE todos SET deleted_at = NULL WHERE id IN (SELECT id FROM ancestors)"
+    ))
+    .bind(todo_id)
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
     Ok(())
 }
 
-pub async fn delete_todo_from_database(
+/// Permanently deletes a todo in the trash and the trashed todos under it
+pub async fn purge_todo(pool: &SqlitePool, todo_id: i64) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    purge_trashed(&mut tx, &[todo_id]).await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Permanently deletes everything that went into the trash before `cutoff`,
+/// returning how many todos were removed
+pub async fn purge_deleted_before(
     pool: &SqlitePool,
-    todo: &TodoItem,
-) -> Result<(), sqlx::Error> {
-    if let Some(id) = todo.id {
-        sqlx::query("DELETE FROM todos WHERE id = ?")
-            .bind(id)
-            .execute(pool)
+    cutoff: NaiveDateTime,
+) -> Result<u64, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let roots: Vec<i64> = sqlx::query_scalar("SELECT id FROM todos WHERE deleted_at < ?")
+        .bind(cutoff.format("%Y-%m-%d %H:%M:%S").to_string())
+        .fetch_all(&mut *tx)
+        .await?;
+    let purged = purge_trashed(&mut tx, &roots).await?;
+    tx.commit().await?;
+    Ok(purged)
+}
+
+/// Permanently deletes everything in the list's trash, returning how many todos were removed
+pub async fn empty_trash(pool: &SqlitePool, list_id: i64) -> Result<u64, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let roots: Vec<i64> =
+        sqlx::query_scalar("SELECT id FROM todos WHERE deleted_at IS NOT NULL AND list_id = ?")
+            .bind(list_id)
+            .fetch_all(&mut *tx)
             .await?;
+    let purged = purge_trashed(&mut tx, &roots).await?;
+    tx.commit().await?;
+    Ok(purged)
+}
+
+/// Deletes the todos in `roots` that are in the trash, along with the trashed
+/// todos under them. Todos under them that are out of the trash, like a child
+/// restored on its own, stay and move up to the nearest ancestor that does.
+async fn purge_trashed(conn: &mut SqliteConnection, roots: &[i64]) -> Result<u64, sqlx::Error> {
+    let ids: Vec<i64> = sqlx::query_scalar(
+        r#"
+        WITH RECURSIVE trashed(id) AS (
+            SELECT id FROM todos
+            WHERE id IN (SELECT value FROM json_each(?)) AND deleted_at IS NOT NULL
+            UNION
+            SELECT todos.id FROM todos JOIN trashed ON todos.parent_id = trashed.id
+            WHERE todos.deleted_at IS NOT NULL
+        )
+        SELECT id FROM trashed
+        "#,
+    )
+    .bind(id_list(roots)?)
+    .fetch_all(&mut *conn)
+    .await?;
+    let ids = id_list(&ids)?;
+
+    // Each pass lifts the survivors one level, until none is left under a purged todo
+    loop {
+        let lifted = sqlx::query(
+            r#"
+            UPDATE todos SET parent_id = (SELECT parent.parent_id FROM todos AS parent WHERE parent.id = todos.parent_id)
+            WHERE parent_id IN (SELECT value FROM json_each(?)) AND id NOT IN (SELECT value FROM json_each(?))
+            "#,
+        )
+        .bind(&ids)
+        .bind(&ids)
+        .execute(&mut *conn)
+        .await?;
+        if lifted.rows_affected() == 0 {
+            break;
+        }
     }
-    Ok(())
+
+    let result = sqlx::query("DELETE FROM todos WHERE id IN (SELECT value FROM json_each(?))")
+        .bind(&ids)
+        .execute(&mut *conn)
+        .await?;
+    Ok(result.rows_affected())
 }
 
 pub async fn update_todo_text(
@@ -109,22 +838,84 @@ pub async fn update_todo_text(
     todo_id: i64,
     new_text: &str,
 ) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
     sqlx::query("UPDATE todos SET todo = ? WHERE id = ?")
         .bind(new_text)
         .bind(todo_id)
+        .execute(&mut *tx)
+        .await?;
+    sync_todo_tags(&mut tx, todo_id, new_text).await?;
+
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Sets everything the input line of a todo holds in one transaction, so a
+/// failure leaves the todo as it was
+pub async fn edit_todo(
+    pool: &SqlitePool,
+    todo_id: i64,
+    text: &str,
+    due: Option<NaiveDate>,
+    scheduled: Option<NaiveDate>,
+    recurrence: Option<&Recurrence>,
+    priority: Priority,
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    sqlx::query(
+        "UPDATE todos SET todo = ?, due = ?, scheduled = ?, recurrence = ?, priority = ? WHERE id = ?",
+    )
+    .bind(text)
+    .bind(due.map(|d| d.format(DATE_FORMAT).to_string()))
+    .bind(scheduled.map(|d| d.format(DATE_FORMAT).to_string()))
+    .bind(recurrence.map(|rule| rule.to_string()))
+    .bind(priority.level())
+    .bind(todo_id)
+    .execute(&mut *tx)
+    .await?;
+    sync_todo_tags(&mut tx, todo_id, text).await?;
+
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Replaces the free-form notes of a todo
+pub async fn update_todo_details(
+    pool: &SqlitePool,
+    todo_id: i64,
+    details: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE todos SET details = ? WHERE id = ?")
+        .bind(details)
+        .bind(todo_id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn update_todo_priority(
+    pool: &SqlitePool,
+    todo_id: i64,
+    priority: Priority,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE todos SET priority = ? WHERE id = ?")
+        .bind(priority.level())
+        .bind(todo_id)
         .execute(pool)
         .await?;
 
     Ok(())
 }
 
-pub async fn update_todo_sort_order(
+pub async fn update_todo_collapsed(
     pool: &SqlitePool,
     todo_id: i64,
-    new_sort_order: i32,
+    collapsed: bool,
 ) -> Result<(), sqlx::Error> {
-    sqlx::query("UPDATE todos SET sort_order = ? WHERE id = ?")
-        .bind(new_sort_order)
+    sqlx::query("UPDATE todos SET collapsed = ? WHERE id = ?")
+        .bind(collapsed)
         .bind(todo_id)
         .execute(pool)
         .await?;
@@ -132,17 +923,246 @@ pub async fn update_todo_sort_order(
     Ok(())
 }
 
-pub async fn toggle_todo_status_in_database(
+/// Folds every todo in the list that has children, or unfolds them all
+pub async fn set_all_collapsed(
     pool: &SqlitePool,
-    todo_id: Option<i64>,
+    list_id: i64,
+    collapsed: bool,
+) -> Result<(), sqlx::Error> {
+    let query = if collapsed {
+        "UPDATE todos SET collapsed = 1 WHERE list_id = ? AND id IN (SELECT parent_id FROM todos WHERE parent_id IS NOT NULL)"
+    } else {
+        "UPDATE todos SET collapsed = 0 WHERE list_id = ?"
+    };
+    sqlx::query(query).bind(list_id).execute(pool).await?;
+
+    Ok(())
+}
+
+/// Moves a todo under `parent`, right after the sibling `after` or right
+/// before the sibling `before`, or between them when given both. With neither
+/// it goes after all its new siblings, and neighbours that aren't among them
+/// are refused. Only the moved row is written, unless
+/// there's no gap left between its neighbours and the siblings are spread out
+/// again first. Its subtree comes along as it stays attached to it.
+pub async fn move_todo(
+    pool: &SqlitePool,
+    todo_id: i64,
+    before: Option<i64>,
+    after: Option<i64>,
+    parent: Option<i64>,
 ) -> Result<(), sqlx::Error> {
-    use chrono::Local;
+    let mut tx = pool.begin().await?;
+
+    let list_id: i64 = sqlx::query_scalar("SELECT list_id FROM todos WHERE id = ?")
+        .bind(todo_id)
+        .fetch_one(&mut *tx)
+        .await?;
+    if let Some(parent) = parent {
+        let subtree: Vec<i64> =
+            sqlx::query_scalar(&format!("{SUBTREE_CTE} SELECT id FROM subtree"))
+                .bind(todo_id)
+                .fetch_all(&mut *tx)
+                .await?;
+        if subtree.contains(&parent) {
+            return Err(sqlx::Error::InvalidArgument(format!(
+                "can't move todo {todo_id} under itself"
+            )));
+        }
+        let parent_list: i64 = sqlx::query_scalar("SELECT list_id FROM todos WHERE id = ?")
+            .bind(parent)
+            .fetch_one(&mut *tx)
+            .await?;
+        if parent_list != list_id {
+            return Err(sqlx::Error::InvalidArgument(format!(
+                "todo {parent} is in another list"
+            )));
+        }
+    }
+
+    let siblings = Siblings {
+        todo_id: Some(todo_id),
+        list_id,
+        parent,
+    };
+    for neighbour in [before, after].into_iter().flatten() {
+        if !siblings.contains(&mut tx, neighbour).await? {
+            return Err(sqlx::Error::InvalidArgument(format!(
+                "todo {neighbour} isn't next to where todo {todo_id} would go"
+            )));
+        }
+    }
+    let sort_order = match siblings.free_sort_order(&mut tx, before, after).await? {
+        Some(sort_order) => sort_order,
+        None => {
+            siblings.spread_out(&mut tx).await?;
+            siblings
+                .free_sort_order(&mut tx, before, after)
+                .await?
+                .ok_or_else(|| sqlx::Error::Protocol("no room left to move todo".to_string()))?
+        }
+    };
 
+    sqlx::query("UPDATE todos SET sort_order = ?, parent_id = ? WHERE id = ?")
+        .bind(sort_order)
+        .bind(parent)
+        .bind(todo_id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+    Ok(())
+}
+
+/// The todo along with every other one under `parent` in its list
+pub async fn sibling_ids(
+    pool: &SqlitePool,
+    todo_id: i64,
+    parent: Option<i64>,
+) -> Result<Vec<i64>, sqlx::Error> {
+    sqlx::query_scalar(
+        "SELECT id FROM todos WHERE list_id = (SELECT list_id FROM todos WHERE id = ?) AND (parent_id IS ? OR id = ?)",
+    )
+    .bind(todo_id)
+    .bind(parent)
+    .bind(todo_id)
+    .fetch_all(pool)
+    .await
+}
+
+/// The todos sharing a parent that one is being moved among or added to, not
+/// counting it
+struct Siblings {
+    todo_id: Option<i64>,
+    list_id: i64,
+    parent: Option<i64>,
+}
+
+impl Siblings {
+    const CONDITION: &str = "list_id = ? AND parent_id IS ? AND id IS NOT ?";
+
+    async fn contains(&self, conn: &mut SqliteConnection, id: i64) -> Result<bool, sqlx::Error> {
+        sqlx::query_scalar(&format!(
+            "SELECT EXISTS (SELECT 1 FROM todos WHERE id = ? AND {})",
+            Self::CONDITION
+        ))
+        .bind(id)
+        .bind(self.list_id)
+        .bind(self.parent)
+        .bind(self.todo_id)
+        .fetch_one(conn)
+        .await
+    }
+
+    /// A sort order between the neighbours, or None when they're too close
+    async fn free_sort_order(
+        &self,
+        conn: &mut SqliteConnection,
+        before: Option<i64>,
+        after: Option<i64>,
+    ) -> Result<Option<i32>, sqlx::Error> {
+        let low = match after {
+            Some(id) => Some(sort_order_of(&mut *conn, id).await?),
+            None => None,
+        };
+        let high = match before {
+            Some(id) => Some(sort_order_of(&mut *conn, id).await?),
+            None => None,
+        };
+        // Fill in the side that wasn't given with the sibling next to the other
+        let (low, high) = match (low, high) {
+            (Some(low), None) => (Some(low), self.next_to(&mut *conn, low, true).await?),
+            (None, Some(high)) => (self.next_to(&mut *conn, high, false).await?, Some(high)),
+            (None, None) => (self.last(&mut *conn).await?, None),
+            both => both,
+        };
+
+        let sort_order = match (low, high) {
+            (Some(low), Some(high)) if high - low >= 2 => low + (high - low) / 2,
+            (Some(_), Some(_)) => return Ok(None),
+            (Some(low), None) => low + i64::from(SORT_GAP),
+            (None, Some(high)) => high - i64::from(SORT_GAP),
+            (None, None) => i64::from(SORT_GAP),
+        };
+        Ok(i32::try_from(sort_order).ok())
+    }
+
+    /// The closest sort order above or below the given one
+    async fn next_to(
+        &self,
+        conn: &mut SqliteConnection,
+        sort_order: i64,
+        above: bool,
+    ) -> Result<Option<i64>, sqlx::Error> {
+        let (aggregate, comparison) = if above { ("MIN", ">") } else { ("MAX", "<") };
+        sqlx::query_scalar(&format!(
+            "SELECT {aggregate}(sort_order) FROM todos WHERE {} AND sort_order {comparison} ?",
+            Self::CONDITION
+        ))
+        .bind(self.list_id)
+        .bind(self.parent)
+        .bind(self.todo_id)
+        .bind(sort_order)
+        .fetch_one(conn)
+        .await
+    }
+
+    async fn last(&self, conn: &mut SqliteConnection) -> Result<Option<i64>, sqlx::Error> {
+        sqlx::query_scalar(&format!(
+            "SELECT MAX(sort_order) FROM todos WHERE {}",
+            Self::CONDITION
+        ))
+        .bind(self.list_id)
+        .bind(self.parent)
+        .bind(self.todo_id)
+        .fetch_one(conn)
+        .await
+    }
+
+    /// Renumbers the siblings `SORT_GAP` apart, keeping their order
+    async fn spread_out(&self, conn: &mut SqliteConnection) -> Result<(), sqlx::Error> {
+        let ids: Vec<i64> = sqlx::query_scalar(&format!(
+            "SELECT id FROM todos WHERE {} ORDER BY sort_order, id",
+            Self::CONDITION
+        ))
+        .bind(self.list_id)
+        .bind(self.parent)
+        .bind(self.todo_id)
+        .fetch_all(&mut *conn)
+        .await?;
+
+        for (index, id) in ids.into_iter().enumerate() {
+            sqlx::query("UPDATE todos SET sort_order = ? WHERE id = ?")
+                .bind((index as i64 + 1) * i64::from(SORT_GAP))
+                .bind(id)
+                .execute(&mut *conn)
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+async fn sort_order_of(conn: &mut SqliteConnection, todo_id: i64) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar("SELECT sort_order FROM todos WHERE id = ?")
+        .bind(todo_id)
+        .fetch_one(conn)
+        .await
+}
+
+/// Completes or reopens a todo. Completing a recurring todo also creates its
+/// next instance, along with copies of its children, whose ids are returned.
+pub async fn toggle_todo_status_in_database(
+    pool: &SqlitePool,
+    todo_id: Option<i64>,
+) -> Result<Vec<i64>, sqlx::Error> {
+    let mut created = Vec::new();
     if let Some(id) = todo_id {
-        let now = Local::now()
-            .naive_local()
-            .format("%Y-%m-%d %H:%M:%S")
-            .to_string();
+        let now = Local::now().naive_local();
+        let mut tx = pool.begin().await?;
+
+        let Some(todo) = todo_by_id(&mut tx, id).await? else {
+            return Ok(created);
+        };
 
         sqlx::query(
             r#"
@@ -154,10 +1174,869 @@ pub async fn toggle_todo_status_in_database(
         WHERE id = ?
         "#,
         )
-        .bind(now)
+        .bind(now.format("%Y-%m-%d %H:%M:%S").to_string())
         .bind(id)
+        .execute(&mut *tx)
+        .await?;
+
+        if todo.completed_at.is_none()
+            && let Some(recurrence) = &todo.recurrence
+        {
+            created = create_next_instance(&mut tx, &todo, recurrence, now.date()).await?;
+        }
+
+        tx.commit().await?;
+    }
+    Ok(created)
+}
+
+async fn create_next_instance(
+    conn: &mut SqliteConnection,
+    todo: &TodoItem,
+    recurrence: &Recurrence,
+    today: NaiveDate,
+) -> Result<Vec<i64>, sqlx::Error> {
+    let base = todo.due.or(todo.scheduled).unwrap_or(today);
+    // The series ends at the end of the calendar
+    let Some(next) = recurrence.next_date(base, today) else {
+        return Ok(Vec::new());
+    };
+    let shift = next - base;
+
+    let renew = |item: &TodoItem, parent_id: Option<i64>| TodoItem {
+        id: None,
+        completed_at: None,
+        date: Local::now().naive_local(),
+        parent_id,
+        due: item.due.and_then(|due| due.checked_add_signed(shift)),
+        scheduled: item
+            .scheduled
+            .and_then(|scheduled| scheduled.checked_add_signed(shift)),
+        ..item.clone()
+    };
+
+    let mut next_todo = renew(todo, todo.parent_id);
+    if next_todo.due.is_none() && next_todo.scheduled.is_none() {
+        next_todo.due = Some(next);
+    }
+    let next_id = insert_todo(&mut *conn, &next_todo).await?;
+    let mut created = vec![next_id];
+
+    // Copy the whole subtree under the new instance
+    let mut pending = vec![(todo.id, next_id)];
+    while let Some((old_parent, new_parent)) = pending.pop() {
+        let children = sqlx::query_as::<_, TodoRow>(&format!(
+            "SELECT {TODO_COLUMNS} FROM todos WHERE parent_id = ? ORDER BY sort_order"
+        ))
+        .bind(old_parent)
+        .fetch_all(&mut *conn)
+        .await?;
+
+        for child in children.into_iter().map(TodoItem::from) {
+            let copy_id = insert_todo(&mut *conn, &renew(&child, Some(new_parent))).await?;
+            created.push(copy_id);
+            pending.push((child.id, copy_id));
+        }
+    }
+
+    Ok(created)
+}
+
+/// Every list, archived ones last
+pub async fn all_lists(pool: &SqlitePool) -> Result<Vec<NamedList>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT id, name, archived_at IS NOT NULL AS archived FROM lists ORDER BY archived, id",
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn list_by_name(pool: &SqlitePool, name: &str) -> Result<Option<NamedList>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT id, name, archived_at IS NOT NULL AS archived FROM lists WHERE name = ? COLLATE NOCASE",
+    )
+    .bind(name)
+    .fetch_optional(pool)
+    .await
+}
+
+/// Adds a list, returning its id
+pub async fn create_list(pool: &SqlitePool, name: &str) -> Result<i64, sqlx::Error> {
+    let result = sqlx::query("INSERT INTO lists (name) VALUES (?)")
+        .bind(name)
         .execute(pool)
         .await?;
+    Ok(result.last_insert_rowid())
+}
+
+pub async fn rename_list(pool: &SqlitePool, list_id: i64, name: &str) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE lists SET name = ? WHERE id = ?")
+        .bind(name)
+        .bind(list_id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Hides a list from the switcher, or brings it back. The inbox is never archived.
+pub async fn set_list_archived(
+    pool: &SqlitePool,
+    list_id: i64,
+    archived: bool,
+) -> Result<(), sqlx::Error> {
+    let now = Local::now().naive_local();
+    sqlx::query("UPDATE lists SET archived_at = ? WHERE id = ? AND id != ?")
+        .bind(archived.then(|| now.format("%Y-%m-%d %H:%M:%S").to_string()))
+        .bind(list_id)
+        .bind(INBOX_LIST_ID)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Moves a todo and everything under it to another list. The todo becomes
+/// top-level there, as its parent stays behind.
+pub async fn move_todo_to_list(
+    pool: &SqlitePool,
+    todo_id: i64,
+    list_id: i64,
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    sqlx::query(&format!(
+        "{SUBTREE_CTE} UPDATE todos SET list_id = ? WHERE id IN (SELECT id FROM subtree)"
+    ))
+    .bind(todo_id)
+    .bind(list_id)
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query("UPDATE todos SET parent_id = NULL WHERE id = ?")
+        .bind(todo_id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Looks a todo up by id whatever list it's in, including the trash
+pub async fn get_todo(pool: &SqlitePool, todo_id: i64) -> Result<Option<TodoItem>, sqlx::Error> {
+    let mut conn = pool.acquire().await?;
+    todo_by_id(&mut conn, todo_id).await
+}
+
+/// Every todo known to be in a CalDAV collection, as of the last sync
+pub async fn caldav_resources(
+    pool: &SqlitePool,
+    collection: &str,
+) -> Result<Vec<CaldavResource>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT collection, href, uid, etag, synced, todo_id FROM caldav_resources WHERE collection = ?",
+    )
+    .bind(collection)
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn save_caldav_resource(
+    pool: &SqlitePool,
+    resource: &CaldavResource,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        INSERT INTO caldav_resources (collection, href, uid, etag, synced, todo_id)
+        VALUES (?, ?, ?, ?, ?, ?)
+        ON CONFLICT (collection, href) DO UPDATE SET
+            uid = excluded.uid,
+            etag = excluded.etag,
+            synced = excluded.synced,
+            todo_id = excluded.todo_id
+        "#,
+    )
+    .bind(&resource.collection)
+    .bind(&resource.href)
+    .bind(&resource.uid)
+    .bind(&resource.etag)
+    .bind(&resource.synced)
+    .bind(resource.todo_id)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn delete_caldav_resource(
+    pool: &SqlitePool,
+    collection: &str,
+    href: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM caldav_resources WHERE collection = ? AND href = ?")
+        .bind(collection)
+        .bind(href)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Overwrites a todo's row with `todo`, tags included
+pub async fn update_todo(pool: &SqlitePool, todo: &TodoItem) -> Result<(), sqlx::Error> {
+    let mut conn = pool.acquire().await?;
+    restore_todo(&mut conn, todo, None).await
+}
+
+/// Columns of `todos` that a change from another machine sets as they are
+const SYNCED_COLUMNS: &[&str] = &[
+    "todo",
+    "details",
+    "date",
+    "completed_at",
+    "sort_order",
+    "due",
+    "scheduled",
+    "recurrence",
+    "priority",
+    "deleted_at",
+];
+
+/// Changes logged after `seq`, oldest first
+pub async fn changes_since(pool: &SqlitePool, seq: i64) -> Result<Vec<Change>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT seq, change_id, todo_uuid, field, value, changed_at FROM changes WHERE seq > ? ORDER BY seq",
+    )
+    .bind(seq)
+    .fetch_all(pool)
+    .await
+}
+
+/// Adds changes made on another machine to the log, in one transaction, and
+/// applies each that is now the latest for its field. A purge is final
+/// unless undone, which logs a `restored` change that brings the todo back
+/// from the log. The writes this makes aren't logged again. Returns how many
+/// changes were new.
+pub async fn apply_changes(pool: &SqlitePool, changes: &[Change]) -> Result<usize, sqlx::Error> {
+    let mut changes: Vec<&Change> = changes.iter().collect();
+    changes.sort_by(|a, b| (&a.changed_at, &a.change_id).cmp(&(&b.changed_at, &b.change_id)));
+
+    let mut tx = pool.begin().await?;
+    sqlx::query("UPDATE sync_state SET applying = 1")
+        .execute(&mut *tx)
+        .await?;
+
+    let mut added = 0;
+    // Parents are linked once every todo they could be has arrived
+    let mut parents: Vec<(i64, Option<String>)> = Vec::new();
+    for change in changes {
+        let result = sqlx::query(
+            "INSERT OR IGNORE INTO changes (change_id, todo_uuid, field, value, changed_at) VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(&change.change_id)
+        .bind(&change.todo_uuid)
+        .bind(&change.field)
+        .bind(&change.value)
+        .bind(&change.changed_at)
+        .execute(&mut *tx)
+        .await?;
+        if result.rows_affected() == 0 {
+            continue;
+        }
+        added += 1;
+
+        let id: Option<i64> = sqlx::query_scalar("SELECT id FROM todos WHERE uuid = ?")
+            .bind(&change.todo_uuid)
+            .fetch_optional(&mut *tx)
+            .await?;
+        match change.field.as_str() {
+            "purged" => {
+                let restored_since: bool = sqlx::query_scalar(
+                    "SELECT EXISTS (SELECT 1 FROM changes WHERE todo_uuid = ? AND field = 'restored' AND (changed_at, change_id) > (?, ?))",
+                )
+                .bind(&change.todo_uuid)
+                .bind(&change.changed_at)
+                .bind(&change.change_id)
+                .fetch_one(&mut *tx)
+                .await?;
+                if let Some(id) = id
+                    && !restored_since
+                {
+                    sqlx::query(&format!(
+                        "{SUBTREE_CTE} DELETE FROM todos WHERE id IN (SELECT id FROM subtree)"
+                    ))
+                    .bind(id)
+                    .execute(&mut *tx)
+                    .await?;
+                }
+                continue;
+            }
+            "restored" => {
+                replay_todo(&mut tx, &change.todo_uuid, id, &mut parents).await?;
+                continue;
+            }
+            _ => {}
+        }
+
+        let superseded: bool = sqlx::query_scalar(
+            r#"
+            SELECT EXISTS (
+                SELECT 1 FROM changes WHERE todo_uuid = ?
+                AND field = ? AND (changed_at, change_id) > (?, ?)
+            ) OR (
+                SELECT field FROM changes WHERE todo_uuid = ? AND field IN ('purged', 'restored')
+                ORDER BY changed_at DESC, change_id DESC LIMIT 1
+            ) IS 'purged'
+            "#,
+        )
+        .bind(&change.todo_uuid)
+        .bind(&change.field)
+        .bind(&change.changed_at)
+        .bind(&change.change_id)
+        .bind(&change.todo_uuid)
+        .fetch_one(&mut *tx)
+        .await?;
+        if superseded {
+            continue;
+        }
+
+        let id = match id {
+            Some(id) => id,
+            None => insert_synced_todo(&mut tx, &change.todo_uuid).await?,
+        };
+        apply_field(&mut tx, id, &change.field, &change.value, &mut parents).await?;
+    }
+
+    for (id, parent_uuid) in &parents {
+        sqlx::query(
+            "UPDATE todos SET parent_id = (SELECT id FROM todos WHERE uuid = ?) WHERE id = ?",
+        )
+        .bind(parent_uuid)
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+    }
+    for (id, _) in &parents {
+        break_parent_cycle(&mut tx, *id).await?;
+    }
+
+    sqlx::query("UPDATE sync_state SET applying = 0")
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    Ok(added)
+}
+
+/// The first change seen for a todo creates it, the rest fill it in
+async fn insert_synced_todo(conn: &mut SqliteConnection, uuid: &str) -> Result<i64, sqlx::Error> {
+    Ok(
+        sqlx::query("INSERT INTO todos (uuid, todo, details, date) VALUES (?, '', '', ?)")
+            .bind(uuid)
+            .bind(Local::now().format("%Y-%m-%d %H:%M:%S").to_string())
+            .execute(&mut *conn)
+            .await?
+            .last_insert_rowid(),
+    )
+}
+
+/// Writes one field of a synced change to the todo, leaving parents to be
+/// linked once every todo has arrived
+async fn apply_field(
+    conn: &mut SqliteConnection,
+    id: i64,
+    field: &str,
+    value: &Option<String>,
+    parents: &mut Vec<(i64, Option<String>)>,
+) -> Result<(), sqlx::Error> {
+    match field {
+        "parent" => parents.push((id, value.clone())),
+        "list" => {
+            let list_id = match value {
+                Some(name) => list_id_for_sync(&mut *conn, name).await?,
+                None => INBOX_LIST_ID,
+            };
+            sqlx::query("UPDATE todos SET list_id = ? WHERE id = ?")
+                .bind(list_id)
+                .bind(id)
+                .execute(&mut *conn)
+                .await?;
+        }
+        field if SYNCED_COLUMNS.contains(&field) => {
+            sqlx::query(&format!("UPDATE todos SET {field} = ? WHERE id = ?"))
+                .bind(value)
+                .bind(id)
+                .execute(&mut *conn)
+                .await?;
+            if field == "todo" {
+                sync_todo_tags(&mut *conn, id, value.as_deref().unwrap_or_default()).await?;
+            }
+        }
+        // Left in the log for a newer dodo to make sense of
+        _ => {}
     }
     Ok(())
 }
+
+/// Brings a todo whose purge was undone back as the latest value of each of
+/// its fields in the log, as those that arrived after the purge were skipped
+async fn replay_todo(
+    conn: &mut SqliteConnection,
+    uuid: &str,
+    id: Option<i64>,
+    parents: &mut Vec<(i64, Option<String>)>,
+) -> Result<(), sqlx::Error> {
+    let latest: Vec<(String, Option<String>)> = sqlx::query_as(
+        r#"
+        SELECT field, value FROM changes AS latest WHERE todo_uuid = ?
+        AND field NOT IN ('purged', 'restored')
+        AND NOT EXISTS (
+            SELECT 1 FROM changes AS newer WHERE newer.todo_uuid = latest.todo_uuid
+            AND newer.field = latest.field
+            AND (newer.changed_at, newer.change_id) > (latest.changed_at, latest.change_id)
+        )
+        "#,
+    )
+    .bind(uuid)
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let id = match id {
+        Some(id) => id,
+        None => insert_synced_todo(&mut *conn, uuid).await?,
+    };
+    for (field, value) in &latest {
+        apply_field(&mut *conn, id, field, value, parents).await?;
+    }
+    Ok(())
+}
+
+/// Undoes a parent link that made a todo its own ancestor, which moves that
+/// were each fine on their own machine can add up to. Of the todos in the
+/// loop, the one whose parent was set longest ago goes to the top level, so
+/// every machine settles on the same tree.
+async fn break_parent_cycle(conn: &mut SqliteConnection, id: i64) -> Result<(), sqlx::Error> {
+    loop {
+        let cycle: Vec<i64> = sqlx::query_scalar(
+            r#"
+            WITH RECURSIVE ancestors(id) AS (
+                SELECT parent_id FROM todos WHERE id = ?
+                UNION SELECT todos.parent_id FROM todos JOIN ancestors ON todos.id = ancestors.id
+            )
+            SELECT id FROM ancestors WHERE id IS NOT NULL
+            AND EXISTS (SELECT 1 FROM ancestors WHERE id = ?)
+            "#,
+        )
+        .bind(id)
+        .bind(id)
+        .fetch_all(&mut *conn)
+        .await?;
+        if cycle.is_empty() {
+            return Ok(());
+        }
+
+        // Walk the loop itself, leaving out any ancestors above where it closes
+        let mut members = vec![id];
+        let mut current = id;
+        loop {
+            let parent: Option<i64> =
+                sqlx::query_scalar("SELECT parent_id FROM todos WHERE id = ?")
+                    .bind(current)
+                    .fetch_one(&mut *conn)
+                    .await?;
+            match parent {
+                Some(parent) if parent != id && !members.contains(&parent) => {
+                    members.push(parent);
+                    current = parent;
+                }
+                _ => break,
+            }
+        }
+
+        let oldest: i64 = sqlx::query_scalar(
+            r#"
+            SELECT id FROM todos WHERE id IN (SELECT value FROM json_each(?))
+            ORDER BY (
+                SELECT changed_at || ' ' || change_id FROM changes
+                WHERE todo_uuid = todos.uuid AND field = 'parent'
+                ORDER BY changed_at DESC, change_id DESC LIMIT 1
+            ), id
+            LIMIT 1
+            "#,
+        )
+        .bind(id_list(&members)?)
+        .fetch_one(&mut *conn)
+        .await?;
+        sqlx::query("UPDATE todos SET parent_id = NULL WHERE id = ?")
+            .bind(oldest)
+            .execute(&mut *conn)
+            .await?;
+    }
+}
+
+/// The id of the list with this name, creating it if this machine hasn't got one
+async fn list_id_for_sync(conn: &mut SqliteConnection, name: &str) -> Result<i64, sqlx::Error> {
+    let existing: Option<i64> =
+        sqlx::query_scalar("SELECT id FROM lists WHERE name = ? COLLATE NOCASE")
+            .bind(name)
+            .fetch_optional(&mut *conn)
+            .await?;
+    match existing {
+        Some(id) => Ok(id),
+        None => Ok(sqlx::query("INSERT INTO lists (name) VALUES (?)")
+            .bind(name)
+            .execute(&mut *conn)
+            .await?
+            .last_insert_rowid()),
+    }
+}
+
+/// How far this log has been sent to another machine, and how far its log
+/// has been received, as positions in each log
+pub async fn sync_peer(pool: &SqlitePool, peer: &str) -> Result<(i64, i64), sqlx::Error> {
+    let cursors = sqlx::query_as("SELECT sent, received FROM sync_peers WHERE peer = ?")
+        .bind(peer)
+        .fetch_optional(pool)
+        .await?;
+    Ok(cursors.unwrap_or((0, 0)))
+}
+
+pub async fn save_sync_peer(
+    pool: &SqlitePool,
+    peer: &str,
+    sent: i64,
+    received: i64,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        INSERT INTO sync_peers (peer, sent, received) VALUES (?, ?, ?)
+        ON CONFLICT (peer) DO UPDATE SET sent = excluded.sent, received = excluded.received
+        "#,
+    )
+    .bind(peer)
+    .bind(sent)
+    .bind(received)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::new_todo_item;
+
+    #[tokio::test]
+    async fn completing_a_recurring_todo_creates_the_next_one_with_its_children() {
+        let pool = memory_pool().await;
+        let mut todo = new_todo_item("water plants", "", None);
+        todo.due = Some(date("2025-06-02"));
+        todo.scheduled = Some(date("2025-06-01"));
+        todo.recurrence = Some(Recurrence::Weekly(Vec::new()));
+        let id = append_todo(&pool, &todo).await.unwrap();
+        add(&pool, "fill the can", Some(id)).await;
+
+        toggle_todo_status_in_database(&pool, Some(id))
+            .await
+            .unwrap();
+
+        let todos = all_todos(&pool, INBOX_LIST_ID).await.unwrap();
+        assert_eq!(todos.len(), 4);
+        let next = todos
+            .iter()
+            .find(|todo| todo.id != Some(id) && todo.parent_id.is_none())
+            .unwrap();
+        assert!(next.completed_at.is_none());
+        assert_eq!(next.recurrence, Some(Recurrence::Weekly(Vec::new())));
+        // Both dates move by the same number of whole weeks, past today
+        let shift = next.due.unwrap() - date("2025-06-02");
+        assert_eq!(shift.num_days() % 7, 0);
+        assert!(next.due.unwrap() > Local::now().date_naive());
+        assert_eq!(next.scheduled.unwrap() - date("2025-06-01"), shift);
+        assert!(
+            todos
+                .iter()
+                .any(|todo| todo.todo == "fill the can" && todo.parent_id == next.id)
+        );
+
+        // Reopening doesn't create another
+        toggle_todo_status_in_database(&pool, Some(id))
+            .await
+            .unwrap();
+        assert_eq!(all_todos(&pool, INBOX_LIST_ID).await.unwrap().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn a_series_ends_at_the_end_of_the_calendar() {
+        let pool = memory_pool().await;
+        let mut todo = new_todo_item("forever", "", None);
+        todo.due = Some(NaiveDate::MAX);
+        todo.recurrence = Some(Recurrence::Daily);
+        let id = append_todo(&pool, &todo).await.unwrap();
+
+        toggle_todo_status_in_database(&pool, Some(id))
+            .await
+            .unwrap();
+
+        let todos = all_todos(&pool, INBOX_LIST_ID).await.unwrap();
+        assert_eq!(todos.len(), 1);
+        assert!(todos[0].completed_at.is_some());
+    }
+
+    async fn trashed(pool: &SqlitePool) -> Vec<i64> {
+        sqlx::query_scalar("SELECT id FROM todos WHERE deleted_at IS NOT NULL ORDER BY id")
+            .fetch_all(pool)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn restoring_brings_back_ancestors_and_what_was_trashed_together() {
+        let pool = memory_pool().await;
+        let parent = add(&pool, "parent", None).await;
+        let child = add(&pool, "child", Some(parent)).await;
+        let earlier = add(&pool, "trashed earlier", Some(child)).await;
+        let grandchild = add(&pool, "grandchild", Some(child)).await;
+        sqlx::query("UPDATE todos SET deleted_at = '2000-01-01 00:00:00' WHERE id = ?")
+            .bind(earlier)
+            .execute(&pool)
+            .await
+            .unwrap();
+        delete_todo_from_database(&pool, parent).await.unwrap();
+        assert_eq!(trashed(&pool).await, [parent, child, earlier, grandchild]);
+
+        restore_deleted_todo(&pool, child).await.unwrap();
+        assert_eq!(trashed(&pool).await, [earlier]);
+    }
+
+    #[tokio::test]
+    async fn every_purge_keeps_children_that_left_the_trash() {
+        for purge in ["todo", "old", "list"] {
+            let pool = memory_pool().await;
+            let grandparent = add(&pool, "grandparent", None).await;
+            let parent = add(&pool, "parent", Some(grandparent)).await;
+            let restored = add(&pool, "restored", Some(parent)).await;
+            let trashed_child = add(&pool, "trashed child", Some(parent)).await;
+            delete_todo_from_database(&pool, parent).await.unwrap();
+            // As a sync from another machine can leave it
+            sqlx::query("UPDATE todos SET deleted_at = NULL WHERE id = ?")
+                .bind(restored)
+                .execute(&pool)
+                .await
+                .unwrap();
+
+            match purge {
+                "todo" => purge_todo(&pool, parent).await.unwrap(),
+                "old" => {
+                    let cutoff = Local::now().naive_local() + chrono::Duration::minutes(1);
+                    assert_eq!(purge_deleted_before(&pool, cutoff).await.unwrap(), 2);
+                }
+                _ => assert_eq!(empty_trash(&pool, INBOX_LIST_ID).await.unwrap(), 2),
+            }
+
+            assert!(get_todo(&pool, parent).await.unwrap().is_none(), "{purge}");
+            assert!(
+                get_todo(&pool, trashed_child).await.unwrap().is_none(),
+                "{purge}"
+            );
+            let restored = get_todo(&pool, restored).await.unwrap().unwrap();
+            assert_eq!(restored.parent_id, Some(grandparent), "{purge}");
+            assert!(get_todo(&pool, grandparent).await.unwrap().is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn purging_leaves_todos_outside_the_trash_alone() {
+        let pool = memory_pool().await;
+        let id = add(&pool, "active", None).await;
+        purge_todo(&pool, id).await.unwrap();
+        let old = Local::now().naive_local() + chrono::Duration::minutes(1);
+        assert_eq!(purge_deleted_before(&pool, old).await.unwrap(), 0);
+        assert_eq!(empty_trash(&pool, INBOX_LIST_ID).await.unwrap(), 0);
+        assert!(get_todo(&pool, id).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn an_edit_is_saved_whole_or_not_at_all() {
+        let pool = memory_pool().await;
+        let id = add(&pool, "call mum #phone", None).await;
+        let weekly: Recurrence = "weekly".parse().unwrap();
+
+        edit_todo(
+            &pool,
+            id,
+            "call dad #phone #family",
+            Some(date("2025-06-08")),
+            Some(date("2025-06-07")),
+            Some(&weekly),
+            Priority::High,
+        )
+        .await
+        .unwrap();
+        let todo = get_todo(&pool, id).await.unwrap().unwrap();
+        assert_eq!(todo.todo, "call dad #phone #family");
+        assert_eq!(todo.due, Some(date("2025-06-08")));
+        assert_eq!(todo.scheduled, Some(date("2025-06-07")));
+        assert_eq!(todo.recurrence, Some(weekly));
+        assert_eq!(todo.priority, Priority::High);
+        assert_eq!(
+            all_tags(&pool, INBOX_LIST_ID).await.unwrap(),
+            ["#family", "#phone"]
+        );
+
+        // A tag that can't be stored fails the edit after the row was updated
+        sqlx::query(
+            "CREATE TRIGGER no_boom BEFORE INSERT ON tags WHEN NEW.name = '#boom' BEGIN SELECT RAISE(ABORT, 'boom'); END",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let edit = edit_todo(&pool, id, "boom #boom", None, None, None, Priority::Low).await;
+        assert!(edit.is_err());
+        let todo = get_todo(&pool, id).await.unwrap().unwrap();
+        assert_eq!(todo.todo, "call dad #phone #family");
+        assert_eq!(todo.due, Some(date("2025-06-08")));
+        assert_eq!(todo.priority, Priority::High);
+        assert_eq!(
+            all_tags(&pool, INBOX_LIST_ID).await.unwrap(),
+            ["#family", "#phone"]
+        );
+    }
+
+    /// The todos of the inbox in display order, indented by depth
+    async fn outline(pool: &SqlitePool) -> Vec<String> {
+        let todos = all_todos(pool, INBOX_LIST_ID).await.unwrap();
+        let depths = crate::models::todo_depths(&todos);
+        todos
+            .iter()
+            .zip(depths)
+            .map(|(todo, depth)| format!("{}{}", "  ".repeat(depth), todo.todo))
+            .collect()
+    }
+
+    async fn sort_order(pool: &SqlitePool, id: i64) -> i32 {
+        get_todo(pool, id).await.unwrap().unwrap().sort_order
+    }
+
+    #[tokio::test]
+    async fn new_todos_go_after_every_sibling_in_the_database() {
+        let pool = memory_pool().await;
+        let a = add(&pool, "a", None).await;
+        let done = add(&pool, "done", None).await;
+        toggle_todo_status_in_database(&pool, Some(done))
+            .await
+            .unwrap();
+        let child = add(&pool, "child", Some(a)).await;
+
+        let b = add(&pool, "b", None).await;
+        assert_eq!(
+            sort_order(&pool, b).await,
+            sort_order(&pool, done).await + SORT_GAP
+        );
+        assert_eq!(sort_order(&pool, child).await, SORT_GAP);
+    }
+
+    #[tokio::test]
+    async fn moving_between_two_siblings_takes_the_middle_of_the_gap() {
+        let pool = memory_pool().await;
+        let a = add(&pool, "a", None).await;
+        let b = add(&pool, "b", None).await;
+        let c = add(&pool, "c", None).await;
+
+        move_todo(&pool, c, Some(b), Some(a), None).await.unwrap();
+        assert_eq!(outline(&pool).await, ["a", "c", "b"]);
+        assert_eq!(sort_order(&pool, c).await, SORT_GAP + SORT_GAP / 2);
+        assert_eq!(sort_order(&pool, a).await, SORT_GAP);
+        assert_eq!(sort_order(&pool, b).await, 2 * SORT_GAP);
+
+        // Given one side, the sibling on the other is found
+        move_todo(&pool, a, None, Some(b), None).await.unwrap();
+        assert_eq!(outline(&pool).await, ["c", "b", "a"]);
+        move_todo(&pool, a, Some(c), None, None).await.unwrap();
+        assert_eq!(outline(&pool).await, ["a", "c", "b"]);
+    }
+
+    #[tokio::test]
+    async fn siblings_are_spread_out_once_the_gap_is_used_up() {
+        let pool = memory_pool().await;
+        let a = add(&pool, "a", None).await;
+        let b = add(&pool, "b", None).await;
+        let c = add(&pool, "c", None).await;
+        sqlx::query("UPDATE todos SET sort_order = id WHERE id IN (?, ?)")
+            .bind(a)
+            .bind(b)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        move_todo(&pool, c, Some(b), Some(a), None).await.unwrap();
+        assert_eq!(outline(&pool).await, ["a", "c", "b"]);
+        assert_eq!(sort_order(&pool, a).await, SORT_GAP);
+        assert_eq!(sort_order(&pool, b).await, 2 * SORT_GAP);
+        assert_eq!(sort_order(&pool, c).await, SORT_GAP + SORT_GAP / 2);
+    }
+
+    #[tokio::test]
+    async fn indenting_and_outdenting_bring_the_subtree_along() {
+        let pool = memory_pool().await;
+        let a = add(&pool, "a", None).await;
+        let b = add(&pool, "b", None).await;
+        add(&pool, "b's child", Some(b)).await;
+        add(&pool, "a's child", Some(a)).await;
+
+        // Indent b under a, after a's child
+        move_todo(&pool, b, None, None, Some(a)).await.unwrap();
+        assert_eq!(
+            outline(&pool).await,
+            ["a", "  a's child", "  b", "    b's child"]
+        );
+
+        // And outdent it again, right after a
+        move_todo(&pool, b, None, Some(a), None).await.unwrap();
+        assert_eq!(
+            outline(&pool).await,
+            ["a", "  a's child", "b", "  b's child"]
+        );
+    }
+
+    #[tokio::test]
+    async fn moves_that_would_break_the_tree_are_refused() {
+        let pool = memory_pool().await;
+        let a = add(&pool, "a", None).await;
+        let child = add(&pool, "child", Some(a)).await;
+        let grandchild = add(&pool, "grandchild", Some(child)).await;
+        let b = add(&pool, "b", None).await;
+        let work = create_list(&pool, "Work").await.unwrap();
+        let elsewhere = append_todo(
+            &pool,
+            &TodoItem {
+                list_id: work,
+                ..new_todo_item("elsewhere", "", None)
+            },
+        )
+        .await
+        .unwrap();
+        let before = outline(&pool).await;
+
+        for (parent, neighbours) in [
+            // Under itself or its own subtree
+            (Some(a), (None, None)),
+            (Some(grandchild), (None, None)),
+            // Under a todo of another list
+            (Some(elsewhere), (None, None)),
+            // Next to todos that won't be its siblings
+            (None, (Some(child), None)),
+            (None, (None, Some(grandchild))),
+            (None, (Some(b), Some(elsewhere))),
+            (Some(b), (Some(child), None)),
+        ] {
+            let (before_id, after_id) = neighbours;
+            let moved = move_todo(&pool, a, before_id, after_id, parent).await;
+            assert!(
+                matches!(moved, Err(sqlx::Error::InvalidArgument(_))),
+                "{parent:?} {neighbours:?}"
+            );
+        }
+        assert_eq!(outline(&pool).await, before);
+        let b_child = move_todo(&pool, b, None, Some(child), Some(a)).await;
+        assert!(b_child.is_ok());
+    }
+}
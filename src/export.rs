@@ -0,0 +1,374 @@
+use clap::ValueEnum;
+use color_eyre::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Write;
+
+use crate::dates::DATE_FORMAT;
+use crate::input::{input_tokens, is_tag};
+use crate::models::{Priority, TodoItem, todo_depths};
+
+/// File formats todos can be written out in
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum ExportFormat {
+    /// Nested todos with every field, which `dodo import --format json` reads back
+    Json,
+    /// One row per todo with its id and parent id
+    Csv,
+    /// One todo per line, children carrying `parent:` keys
+    Todotxt,
+    /// Indented `- [ ]` checkboxes with details beneath each item
+    Markdown,
+}
+
+/// A todo along with the todos nested under it
+#[derive(Serialize, Deserialize)]
+pub struct TodoTree {
+    #[serde(flatten)]
+    pub todo: TodoItem,
+    #[serde(default)]
+    pub children: Vec<TodoTree>,
+}
+
+/// Writes `todos`, in hierarchical order, to `out`. Todos whose parent isn't
+/// among them are written at the top level.
+pub fn write(format: ExportFormat, todos: &[TodoItem], out: &mut impl Write) -> Result<()> {
+    match format {
+        ExportFormat::Json => {
+            serde_json::to_writer_pretty(&mut *out, &todo_trees(todos))?;
+            writeln!(out)?;
+        }
+        ExportFormat::Csv => write_csv(todos, out)?,
+        ExportFormat::Todotxt => {
+            let parents: Vec<i64> = todos.iter().filter_map(|todo| todo.parent_id).collect();
+            for todo in todos {
+                writeln!(out, "{}", todotxt_line(todo, &parents))?;
+            }
+        }
+        ExportFormat::Markdown => {
+            for (todo, depth) in todos.iter().zip(todo_depths(todos)) {
+                let indent = "  ".repeat(depth);
+                let checkbox = if todo.completed_at.is_some() {
+                    "x"
+                } else {
+                    " "
+                };
+                writeln!(
+                    out,
+                    "{indent}- [{checkbox}] {}{}",
+                    todo.todo,
+                    input_tokens(todo)
+                )?;
+                for line in todo.details.lines() {
+                    if line.is_empty() {
+                        writeln!(out)?;
+                    } else {
+                        writeln!(out, "{indent}  {line}")?;
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn todo_trees(todos: &[TodoItem]) -> Vec<TodoTree> {
+    let ids: Vec<i64> = todos.iter().filter_map(|todo| todo.id).collect();
+    let mut children: HashMap<i64, Vec<&TodoItem>> = HashMap::new();
+    let mut roots: Vec<&TodoItem> = Vec::new();
+    for todo in todos {
+        match todo.parent_id {
+            Some(parent_id) if ids.contains(&parent_id) => {
+                children.entry(parent_id).or_default().push(todo)
+            }
+            _ => roots.push(todo),
+        }
+    }
+
+    fn build(todo: &TodoItem, children: &HashMap<i64, Vec<&TodoItem>>) -> TodoTree {
+        let nested = todo
+            .id
+            .and_then(|id| children.get(&id))
+            .map(|nested| nested.iter().map(|child| build(child, children)).collect())
+            .unwrap_or_default();
+        TodoTree {
+            todo: todo.clone(),
+            children: nested,
+        }
+    }
+    roots.iter().map(|todo| build(todo, &children)).collect()
+}
+
+fn write_csv(todos: &[TodoItem], out: &mut impl Write) -> Result<()> {
+    let mut writer = csv::Writer::from_writer(out);
+    writer.write_record([
+        "id",
+        "parent_id",
+        "todo",
+        "details",
+        "completed_at",
+        "created",
+        "due",
+        "scheduled",
+        "recurrence",
+        "priority",
+        "tags",
+    ])?;
+    for todo in todos {
+        writer.write_record([
+            todo.id.map(|id| id.to_string()).unwrap_or_default(),
+            todo.parent_id.map(|id| id.to_string()).unwrap_or_default(),
+            todo.todo.clone(),
+            todo.details.clone(),
+            todo.completed_at
+                .map(|date| date.format("%Y-%m-%d %H:%M:%S").to_string())
+                .unwrap_or_default(),
+            todo.date.format("%Y-%m-%d %H:%M:%S").to_string(),
+            todo.due
+                .map(|date| date.format(DATE_FORMAT).to_string())
+                .unwrap_or_default(),
+            todo.scheduled
+                .map(|date| date.format(DATE_FORMAT).to_string())
+                .unwrap_or_default(),
+            todo.recurrence
+                .as_ref()
+                .map(|rule| rule.to_string())
+                .unwrap_or_default(),
+            todo.priority.to_string(),
+            todo.tags.join(" "),
+        ])?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// The reverse of the todo.txt import: `#tags` become `+projects`, and
+/// todos with children get an `id:` key that the children point at with `parent:`
+fn todotxt_line(todo: &TodoItem, parents: &[i64]) -> String {
+    let mut words: Vec<String> = Vec::new();
+    if let Some(completed_at) = todo.completed_at {
+        words.push("x".to_string());
+        words.push(completed_at.format(DATE_FORMAT).to_string());
+    }
+    let letter = match todo.priority {
+        Priority::None => None,
+        Priority::Urgent => Some('A'),
+        Priority::High => Some('B'),
+        Priority::Medium => Some('C'),
+        Priority::Low => Some('D'),
+    };
+    if let Some(letter) = letter
+        && todo.completed_at.is_none()
+    {
+        words.push(format!("({letter})"));
+    }
+    words.push(todo.date.format(DATE_FORMAT).to_string());
+
+    for word in todo.todo.split_whitespace() {
+        match word.strip_prefix('#') {
+            Some(tag) if is_tag(word) => words.push(format!("+{tag}")),
+            _ => words.push(word.to_string()),
+        }
+    }
+    if let Some(due) = todo.due {
+        words.push(format!("due:{}", due.format(DATE_FORMAT)));
+    }
+    if let Some(scheduled) = todo.scheduled {
+        words.push(format!("t:{}", scheduled.format(DATE_FORMAT)));
+    }
+    if let Some(recurrence) = &todo.recurrence {
+        words.push(format!("rec:{recurrence}"));
+    }
+    // Completed tasks keep their priority as a key so the line still starts with `x`
+    if let Some(letter) = letter
+        && todo.completed_at.is_some()
+    {
+        words.push(format!("pri:{letter}"));
+    }
+    if let Some(id) = todo.id
+        && parents.contains(&id)
+    {
+        words.push(format!("id:{id}"));
+    }
+    if let Some(parent_id) = todo.parent_id {
+        words.push(format!("parent:{parent_id}"));
+    }
+    words.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::date;
+    use crate::import::{self, Import, ImportFormat};
+    use crate::models::new_todo_item;
+    use crate::recurrence::Recurrence;
+    use chrono::NaiveDateTime;
+
+    fn time(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    /// Two trees, the first three deep, with every field that has a place in
+    /// at least one of the formats
+    fn fixture() -> Vec<TodoItem> {
+        let todo = |id: i64, text: &str, parent_id: Option<i64>| TodoItem {
+            id: Some(id),
+            date: time("2025-06-01 09:30:00"),
+            ..new_todo_item(text, "", parent_id)
+        };
+        vec![
+            TodoItem {
+                due: Some(date("2025-07-01")),
+                priority: Priority::High,
+                details: "book flights\n\ncheck passports\n  both expire in 2027".to_string(),
+                tags: vec!["#travel".to_string()],
+                collapsed: true,
+                ..todo(1, "plan trip #travel", None)
+            },
+            TodoItem {
+                scheduled: Some(date("2025-06-20")),
+                completed_at: Some(time("2025-06-05 18:00:00")),
+                priority: Priority::Urgent,
+                ..todo(2, "book hotel", Some(1))
+            },
+            TodoItem {
+                recurrence: Some(Recurrence::Weekly(vec![
+                    chrono::Weekday::Mon,
+                    chrono::Weekday::Fri,
+                ])),
+                due: Some(date("2025-06-02")),
+                ..todo(3, "compare prices", Some(2))
+            },
+            TodoItem {
+                recurrence: Some(Recurrence::Daily),
+                due: Some(date("2025-06-11")),
+                priority: Priority::Low,
+                ..todo(4, "water plants", None)
+            },
+        ]
+    }
+
+    fn round_trip(export: ExportFormat, import: ImportFormat) -> Import {
+        let mut out = Vec::new();
+        write(export, &fixture(), &mut out).unwrap();
+        import::parse(import, &String::from_utf8(out).unwrap()).unwrap()
+    }
+
+    fn texts(import: &Import) -> Vec<(&str, usize)> {
+        import
+            .todos
+            .iter()
+            .zip(import.depths())
+            .map(|(imported, depth)| (imported.todo.todo.as_str(), depth))
+            .collect()
+    }
+
+    #[test]
+    fn json_gives_back_every_field() {
+        let import = round_trip(ExportFormat::Json, ImportFormat::Json);
+        assert_eq!(
+            texts(&import),
+            [
+                ("plan trip #travel", 0),
+                ("book hotel", 1),
+                ("compare prices", 2),
+                ("water plants", 0)
+            ]
+        );
+        for (imported, original) in import.todos.iter().zip(fixture()) {
+            let todo = &imported.todo;
+            assert_eq!(todo.id, None);
+            assert_eq!(todo.details, original.details);
+            assert_eq!(todo.date, original.date);
+            assert_eq!(todo.completed_at, original.completed_at);
+            assert_eq!(todo.due, original.due);
+            assert_eq!(todo.scheduled, original.scheduled);
+            assert_eq!(todo.recurrence, original.recurrence);
+            assert_eq!(todo.priority, original.priority);
+            assert_eq!(todo.collapsed, original.collapsed);
+            assert_eq!(todo.tags, original.tags);
+        }
+    }
+
+    #[test]
+    fn todotxt_gives_back_what_the_format_holds() {
+        let import = round_trip(ExportFormat::Todotxt, ImportFormat::Todotxt);
+        assert_eq!(
+            texts(&import),
+            [
+                ("plan trip #travel", 0),
+                ("book hotel", 1),
+                ("compare prices", 2),
+                ("water plants", 0)
+            ]
+        );
+        for (imported, original) in import.todos.iter().zip(fixture()) {
+            let todo = &imported.todo;
+            // Only the days of the dates are kept
+            assert_eq!(todo.date.date(), original.date.date());
+            assert_eq!(
+                todo.completed_at.map(|at| at.date()),
+                original.completed_at.map(|at| at.date())
+            );
+            assert_eq!(todo.due, original.due);
+            assert_eq!(todo.scheduled, original.scheduled);
+            assert_eq!(todo.recurrence, original.recurrence);
+            assert_eq!(todo.priority, original.priority);
+        }
+    }
+
+    #[test]
+    fn markdown_gives_back_what_the_format_holds() {
+        let import = round_trip(ExportFormat::Markdown, ImportFormat::Markdown);
+        assert_eq!(
+            texts(&import),
+            [
+                ("plan trip #travel", 0),
+                ("book hotel", 1),
+                ("compare prices", 2),
+                ("water plants", 0)
+            ]
+        );
+        for (imported, original) in import.todos.iter().zip(fixture()) {
+            let todo = &imported.todo;
+            assert_eq!(todo.details, original.details);
+            assert_eq!(todo.completed_at.is_some(), original.completed_at.is_some());
+            assert_eq!(todo.due, original.due);
+            assert_eq!(todo.scheduled, original.scheduled);
+            assert_eq!(todo.recurrence, original.recurrence);
+            assert_eq!(todo.priority, original.priority);
+        }
+    }
+
+    #[test]
+    fn csv_has_a_row_per_todo_with_its_parent() {
+        let mut out = Vec::new();
+        write(ExportFormat::Csv, &fixture(), &mut out).unwrap();
+        let mut reader = csv::Reader::from_reader(out.as_slice());
+        let rows: Vec<(String, String, String)> = reader
+            .records()
+            .map(|record| {
+                let record = record.unwrap();
+                (
+                    record[0].to_string(),
+                    record[1].to_string(),
+                    record[3].to_string(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            rows,
+            [
+                (
+                    "1".into(),
+                    "".into(),
+                    "book flights\n\ncheck passports\n  both expire in 2027".into()
+                ),
+                ("2".into(), "1".into(), "".into()),
+                ("3".into(), "2".into(), "".into()),
+                ("4".into(), "".into(), "".into()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,379 @@
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use sqlx::sqlite::{SqliteConnection, SqlitePool};
+use std::fmt;
+
+use crate::db;
+use crate::models::TodoItem;
+
+/// How many entries of history are kept around
+const HISTORY_LIMIT: i64 = 1000;
+
+/// The kind of change an entry in the history records
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Add,
+    Edit,
+    Delete,
+    Toggle,
+    Reorder,
+    Restore,
+    Purge,
+    /// Moving a subtree to another named list
+    Move,
+}
+
+impl Action {
+    fn as_str(self) -> &'static str {
+        match self {
+            Action::Add => "add",
+            Action::Edit => "edit",
+            Action::Delete => "delete",
+            Action::Toggle => "toggle",
+            Action::Reorder => "reorder",
+            Action::Restore => "restore",
+            Action::Purge => "purge",
+            Action::Move => "move",
+        }
+    }
+
+    fn from_str(name: &str) -> Option<Self> {
+        [
+            Action::Add,
+            Action::Edit,
+            Action::Delete,
+            Action::Toggle,
+            Action::Reorder,
+            Action::Restore,
+            Action::Purge,
+            Action::Move,
+        ]
+        .into_iter()
+        .find(|action| action.as_str() == name)
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// A todo as an entry in the history holds it, with the UUID `dodo sync`
+/// knows it by so that undoing a purge brings back the same todo
+#[derive(Clone, Serialize, Deserialize)]
+struct Snapshot {
+    #[serde(flatten)]
+    todo: TodoItem,
+    #[serde(default)]
+    uuid: Option<String>,
+}
+
+async fn snapshot(pool: &SqlitePool, ids: &[i64]) -> Result<Vec<Snapshot>, sqlx::Error> {
+    Ok(db::todos_for_history(pool, ids)
+        .await?
+        .into_iter()
+        .map(|(todo, uuid)| Snapshot { todo, uuid })
+        .collect())
+}
+
+/// The todos an action is about to change, as they were before it ran
+#[derive(Clone)]
+pub struct Checkpoint {
+    ids: Vec<i64>,
+    before: Vec<Snapshot>,
+}
+
+/// Snapshots the given todos before changing them
+pub async fn checkpoint(pool: &SqlitePool, ids: &[i64]) -> Result<Checkpoint, sqlx::Error> {
+    Ok(Checkpoint {
+        ids: ids.to_vec(),
+        before: snapshot(pool, ids).await?,
+    })
+}
+
+/// Adds an entry to the history going from the checkpoint to the current
+/// state of the same todos, plus the ones the action `created`, which undo
+/// deletes again. Anything that could have been redone is forgotten.
+pub async fn record(
+    pool: &SqlitePool,
+    action: Action,
+    checkpoint: Checkpoint,
+    created: &[i64],
+) -> Result<(), sqlx::Error> {
+    let ids = [checkpoint.ids.as_slice(), created].concat();
+    let after = snapshot(pool, &ids).await?;
+
+    let mut tx = pool.begin().await?;
+    sqlx::query("DELETE FROM history WHERE undone = 1")
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query(
+        "INSERT INTO history (action, before_state, after_state, created_at) VALUES (?, ?, ?, ?)",
+    )
+    .bind(action.as_str())
+    .bind(to_json(&checkpoint.before)?)
+    .bind(to_json(&after)?)
+    .bind(
+        Local::now()
+            .naive_local()
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string(),
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query("DELETE FROM history WHERE id <= (SELECT MAX(id) FROM history) - ?")
+        .bind(HISTORY_LIMIT)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Reverts the most recent change, returning what it was
+pub async fn undo(pool: &SqlitePool) -> Result<Option<Action>, sqlx::Error> {
+    step(
+        pool,
+        "SELECT id, action, before_state, after_state FROM history WHERE undone = 0 ORDER BY id DESC LIMIT 1",
+        true,
+    )
+    .await
+}
+
+/// Reapplies the most recently undone change, returning what it was
+pub async fn redo(pool: &SqlitePool) -> Result<Option<Action>, sqlx::Error> {
+    step(
+        pool,
+        "SELECT id, action, before_state, after_state FROM history WHERE undone = 1 ORDER BY id LIMIT 1",
+        false,
+    )
+    .await
+}
+
+async fn step(pool: &SqlitePool, query: &str, undo: bool) -> Result<Option<Action>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let entry: Option<(i64, String, String, String)> =
+        sqlx::query_as(query).fetch_optional(&mut *tx).await?;
+    let Some((id, action, before, after)) = entry else {
+        return Ok(None);
+    };
+
+    let before = from_json(&before)?;
+    let after = from_json(&after)?;
+    if undo {
+        apply(&mut tx, &after, &before).await?;
+    } else {
+        apply(&mut tx, &before, &after).await?;
+    }
+
+    sqlx::query("UPDATE history SET undone = ? WHERE id = ?")
+        .bind(undo)
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    Ok(Action::from_str(&action))
+}
+
+/// Turns the todos in `current` into those in `target`, deleting any that
+/// `target` doesn't have
+async fn apply(
+    conn: &mut SqliteConnection,
+    current: &[Snapshot],
+    target: &[Snapshot],
+) -> Result<(), sqlx::Error> {
+    // Snapshots list parents before children, so delete from the end
+    for snapshot in current.iter().rev() {
+        if !target.iter().any(|item| item.todo.id == snapshot.todo.id) {
+            sqlx::query("DELETE FROM todos WHERE id = ?")
+                .bind(snapshot.todo.id)
+                .execute(&mut *conn)
+                .await?;
+        }
+    }
+
+    for snapshot in target {
+        db::restore_todo(conn, &snapshot.todo, snapshot.uuid.as_deref()).await?;
+    }
+
+    Ok(())
+}
+
+fn to_json(todos: &[Snapshot]) -> Result<String, sqlx::Error> {
+    serde_json::to_string(todos).map_err(|e| sqlx::Error::Encode(Box::new(e)))
+}
+
+fn from_json(json: &str) -> Result<Vec<Snapshot>, sqlx::Error> {
+    serde_json::from_str(json).map_err(|e| sqlx::Error::Decode(Box::new(e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::add;
+    use crate::models::INBOX_LIST_ID;
+
+    async fn text_of(pool: &SqlitePool, id: i64) -> Option<String> {
+        db::get_todo(pool, id).await.unwrap().map(|todo| todo.todo)
+    }
+
+    #[tokio::test]
+    async fn undoes_and_redoes_an_edit() {
+        let pool = db::memory_pool().await;
+        let id = add(&pool, "buy milk #shop", None).await;
+
+        let checkpoint = checkpoint(&pool, &[id]).await.unwrap();
+        db::update_todo_text(&pool, id, "buy oat milk #errand")
+            .await
+            .unwrap();
+        record(&pool, Action::Edit, checkpoint, &[]).await.unwrap();
+
+        assert_eq!(undo(&pool).await.unwrap(), Some(Action::Edit));
+        let todo = db::get_todo(&pool, id).await.unwrap().unwrap();
+        assert_eq!(todo.todo, "buy milk #shop");
+        assert_eq!(todo.tags, ["#shop"]);
+        assert_eq!(undo(&pool).await.unwrap(), None);
+
+        assert_eq!(redo(&pool).await.unwrap(), Some(Action::Edit));
+        assert_eq!(
+            text_of(&pool, id).await.as_deref(),
+            Some("buy oat milk #errand")
+        );
+        assert_eq!(redo(&pool).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn undoing_an_add_removes_the_new_todo_and_redo_brings_it_back() {
+        let pool = db::memory_pool().await;
+        add(&pool, "existing", None).await;
+
+        let checkpoint = checkpoint(&pool, &[]).await.unwrap();
+        let id = add(&pool, "new", None).await;
+        record(&pool, Action::Add, checkpoint, &[id]).await.unwrap();
+
+        undo(&pool).await.unwrap();
+        assert_eq!(text_of(&pool, id).await, None);
+        redo(&pool).await.unwrap();
+        assert_eq!(text_of(&pool, id).await.as_deref(), Some("new"));
+        assert_eq!(db::all_todos(&pool, INBOX_LIST_ID).await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn undoing_a_delete_restores_the_subtree() {
+        let pool = db::memory_pool().await;
+        let parent = add(&pool, "parent", None).await;
+        let child = add(&pool, "child", Some(parent)).await;
+        let grandchild = add(&pool, "grandchild", Some(child)).await;
+
+        let ids = db::subtree_ids(&pool, parent).await.unwrap();
+        let checkpoint = checkpoint(&pool, &ids).await.unwrap();
+        db::delete_todo_from_database(&pool, parent).await.unwrap();
+        record(&pool, Action::Delete, checkpoint, &[])
+            .await
+            .unwrap();
+        assert!(
+            db::all_todos(&pool, INBOX_LIST_ID)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+
+        undo(&pool).await.unwrap();
+        let todos = db::all_todos(&pool, INBOX_LIST_ID).await.unwrap();
+        let ids: Vec<_> = todos.iter().map(|todo| todo.id.unwrap()).collect();
+        assert_eq!(ids, [parent, child, grandchild]);
+        assert!(todos.iter().all(|todo| todo.deleted_at.is_none()));
+    }
+
+    #[tokio::test]
+    async fn a_new_change_forgets_what_could_be_redone() {
+        let pool = db::memory_pool().await;
+        let id = add(&pool, "first", None).await;
+        for text in ["second", "third"] {
+            let checkpoint = checkpoint(&pool, &[id]).await.unwrap();
+            db::update_todo_text(&pool, id, text).await.unwrap();
+            record(&pool, Action::Edit, checkpoint, &[]).await.unwrap();
+        }
+
+        undo(&pool).await.unwrap();
+        assert_eq!(text_of(&pool, id).await.as_deref(), Some("second"));
+        let checkpoint = checkpoint(&pool, &[id]).await.unwrap();
+        db::update_todo_priority(&pool, id, crate::models::Priority::High)
+            .await
+            .unwrap();
+        record(&pool, Action::Edit, checkpoint, &[]).await.unwrap();
+
+        assert_eq!(redo(&pool).await.unwrap(), None);
+        undo(&pool).await.unwrap();
+        undo(&pool).await.unwrap();
+        assert_eq!(text_of(&pool, id).await.as_deref(), Some("first"));
+    }
+
+    #[tokio::test]
+    async fn entries_only_hold_the_todos_the_change_touched() {
+        let pool = db::memory_pool().await;
+        for n in 0..50 {
+            add(&pool, &format!("todo {n}"), None).await;
+        }
+        let id = add(&pool, "edited", None).await;
+
+        let checkpoint = checkpoint(&pool, &[id]).await.unwrap();
+        db::update_todo_text(&pool, id, "edited again")
+            .await
+            .unwrap();
+        record(&pool, Action::Edit, checkpoint, &[]).await.unwrap();
+
+        let (before, after): (String, String) =
+            sqlx::query_as("SELECT before_state, after_state FROM history")
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!(from_json(&before).unwrap().len(), 1);
+        assert_eq!(from_json(&after).unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn undoing_an_add_keeps_todos_others_created_meanwhile() {
+        let pool = db::memory_pool().await;
+        let checkpoint = checkpoint(&pool, &[]).await.unwrap();
+        let id = add(&pool, "mine", None).await;
+        // Added by `dodo add` or a sync while the TUI was saving
+        let theirs = add(&pool, "theirs", None).await;
+        record(&pool, Action::Add, checkpoint, &[id]).await.unwrap();
+
+        undo(&pool).await.unwrap();
+        assert_eq!(text_of(&pool, id).await, None);
+        assert_eq!(text_of(&pool, theirs).await.as_deref(), Some("theirs"));
+        redo(&pool).await.unwrap();
+        assert_eq!(text_of(&pool, id).await.as_deref(), Some("mine"));
+        assert_eq!(text_of(&pool, theirs).await.as_deref(), Some("theirs"));
+    }
+
+    #[tokio::test]
+    async fn undoing_a_completion_removes_the_next_instance_it_created() {
+        let pool = db::memory_pool().await;
+        let mut todo = crate::models::new_todo_item("water plants", "", None);
+        todo.due = Some(db::date("2025-06-02"));
+        todo.recurrence = Some("weekly".parse().unwrap());
+        let id = db::append_todo(&pool, &todo).await.unwrap();
+        let child = add(&pool, "fetch the can", Some(id)).await;
+
+        let checkpoint = checkpoint(&pool, &[id]).await.unwrap();
+        let created = db::toggle_todo_status_in_database(&pool, Some(id))
+            .await
+            .unwrap();
+        assert_eq!(created.len(), 2);
+        record(&pool, Action::Toggle, checkpoint, &created)
+            .await
+            .unwrap();
+
+        undo(&pool).await.unwrap();
+        let todos = db::all_todos(&pool, INBOX_LIST_ID).await.unwrap();
+        let ids: Vec<_> = todos.iter().map(|todo| todo.id.unwrap()).collect();
+        assert_eq!(ids, [id, child]);
+        assert!(todos[0].completed_at.is_none());
+    }
+}
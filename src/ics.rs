@@ -0,0 +1,539 @@
+use chrono::{Local, NaiveDate, NaiveDateTime, Utc, Weekday};
+use std::collections::HashMap;
+
+use crate::import::Import;
+use crate::input::parse_tags;
+use crate::models::{Priority, TodoItem, new_todo_item};
+use crate::recurrence::Recurrence;
+
+const DATE: &str = "%Y%m%d";
+const LOCAL_TIME: &str = "%Y%m%dT%H%M%S";
+const UTC_TIME: &str = "%Y%m%dT%H%M%SZ";
+
+/// Writes todos as an iCalendar (RFC 5545) calendar of VTODO components,
+/// children pointing at their parent with RELATED-TO
+pub fn to_ics(todos: &[TodoItem]) -> String {
+    let components = todos.iter().map(|todo| {
+        vtodo(
+            todo,
+            &todo_uid(todo.id.unwrap_or_default()),
+            todo.parent_id.map(todo_uid).as_deref(),
+        )
+    });
+    calendar(components)
+}
+
+/// Wraps VTODO components in a calendar, stamping each with the current time
+pub fn calendar(components: impl IntoIterator<Item = Vec<String>>) -> String {
+    let stamp = Utc::now().format(UTC_TIME).to_string();
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        "PRODID:-//dodo//dodo//EN".to_string(),
+    ];
+    for mut component in components {
+        // DTSTAMP is required and has to follow BEGIN and UID
+        component.insert(2.min(component.len()), format!("DTSTAMP:{stamp}"));
+        lines.extend(component);
+    }
+    lines.push("END:VCALENDAR".to_string());
+
+    lines.iter().map(|line| fold(line)).collect()
+}
+
+/// The lines of a VTODO for `todo`, leaving out DTSTAMP so the same todo
+/// always gives the same lines
+pub fn vtodo(todo: &TodoItem, uid: &str, parent_uid: Option<&str>) -> Vec<String> {
+    let mut lines = vec!["BEGIN:VTODO".to_string(), format!("UID:{uid}")];
+    lines.push(format!("CREATED:{}", utc_time(todo.date)));
+    lines.push(format!("SUMMARY:{}", escape(&todo.todo)));
+    if !todo.details.is_empty() {
+        lines.push(format!("DESCRIPTION:{}", escape(&todo.details)));
+    }
+    if let Some(scheduled) = todo.scheduled {
+        lines.push(format!("DTSTART;VALUE=DATE:{}", scheduled.format(DATE)));
+    }
+    if let Some(due) = todo.due {
+        lines.push(format!("DUE;VALUE=DATE:{}", due.format(DATE)));
+    }
+    match todo.completed_at {
+        Some(completed_at) => {
+            lines.push("STATUS:COMPLETED".to_string());
+            lines.push(format!("COMPLETED:{}", utc_time(completed_at)));
+        }
+        None => lines.push("STATUS:NEEDS-ACTION".to_string()),
+    }
+    if let Some(level) = ics_priority(todo.priority) {
+        lines.push(format!("PRIORITY:{level}"));
+    }
+    if let Some(rule) = todo.recurrence.as_ref().map(rrule) {
+        lines.push(format!("RRULE:{rule}"));
+    }
+    if !todo.tags.is_empty() {
+        let categories: Vec<String> = todo
+            .tags
+            .iter()
+            .map(|tag| escape(tag.trim_start_matches(['#', '@'])))
+            .collect();
+        lines.push(format!("CATEGORIES:{}", categories.join(",")));
+    }
+    if let Some(parent_uid) = parent_uid {
+        lines.push(format!("RELATED-TO;RELTYPE=PARENT:{parent_uid}"));
+    }
+    lines.push("END:VTODO".to_string());
+    lines
+}
+
+fn todo_uid(id: i64) -> String {
+    format!("dodo-{id}")
+}
+
+/// A VTODO as read from a file, before it is nested under its parent
+pub struct Component {
+    pub uid: Option<String>,
+    pub parent_uid: Option<String>,
+    pub todo: TodoItem,
+}
+
+/// Reads the VTODO components of an iCalendar file. Children are nested under
+/// the VTODO their RELATED-TO points at, and other components such as events
+/// are skipped.
+pub fn parse_ics(content: &str) -> Import {
+    let (components, skipped) = read_vtodos(content);
+    let mut import = Import {
+        skipped,
+        ..Import::default()
+    };
+
+    // Parents may come after their children, so place them by uid afterwards
+    let by_uid: HashMap<String, usize> = components
+        .iter()
+        .enumerate()
+        .filter_map(|(index, component)| component.uid.clone().map(|uid| (uid, index)))
+        .collect();
+    let mut children: HashMap<usize, Vec<usize>> = HashMap::new();
+    let mut roots = Vec::new();
+    for (index, component) in components.iter().enumerate() {
+        match component
+            .parent_uid
+            .as_ref()
+            .and_then(|uid| by_uid.get(uid))
+        {
+            Some(&parent) if parent != index => children.entry(parent).or_default().push(index),
+            _ => roots.push(index),
+        }
+    }
+
+    let mut todos: Vec<Option<TodoItem>> = components
+        .into_iter()
+        .map(|component| Some(component.todo))
+        .collect();
+    let mut stack: Vec<(usize, Option<usize>)> =
+        roots.into_iter().rev().map(|index| (index, None)).collect();
+    loop {
+        while let Some((index, parent)) = stack.pop() {
+            let Some(todo) = todos[index].take() else {
+                continue;
+            };
+            let imported = import.push(todo, parent);
+            for &child in children.get(&index).into_iter().flatten().rev() {
+                stack.push((child, Some(imported)));
+            }
+        }
+        // Todos in a RELATED-TO cycle are never reached from a root
+        match todos.iter().position(Option::is_some) {
+            Some(index) => stack.push((index, None)),
+            None => break,
+        }
+    }
+    import
+}
+
+/// The VTODOs of an iCalendar file, and how many other components it had
+pub fn read_vtodos(content: &str) -> (Vec<Component>, usize) {
+    let mut components: Vec<Component> = Vec::new();
+    let mut current: Option<Component> = None;
+    // How deep inside components of the current VTODO, like VALARM, the line is
+    let mut nested = 0;
+    let mut skipped = 0;
+
+    for line in unfold(content) {
+        let Some((name_and_params, value)) = line.split_once(':') else {
+            continue;
+        };
+        let mut params = name_and_params.split(';');
+        let name = params.next().unwrap_or_default().to_uppercase();
+        let params: Vec<String> = params.map(str::to_uppercase).collect();
+
+        match (name.as_str(), value) {
+            ("END", "VTODO") => {
+                nested = 0;
+                if let Some(component) = current.take() {
+                    if component.todo.todo.is_empty() {
+                        skipped += 1;
+                    } else {
+                        components.push(component);
+                    }
+                }
+            }
+            // An alarm's SUMMARY or DESCRIPTION isn't the todo's
+            ("BEGIN", _) if current.is_some() => nested += 1,
+            ("END", _) if nested > 0 => nested -= 1,
+            ("BEGIN", "VTODO") => {
+                current = Some(Component {
+                    uid: None,
+                    parent_uid: None,
+                    todo: new_todo_item("", "", None),
+                });
+            }
+            ("BEGIN", "VEVENT" | "VJOURNAL") => skipped += 1,
+            _ => {
+                if let Some(component) = current.as_mut()
+                    && nested == 0
+                {
+                    read_property(component, &name, &params, value);
+                }
+            }
+        }
+    }
+
+    (components, skipped)
+}
+
+fn read_property(component: &mut Component, name: &str, params: &[String], value: &str) {
+    let todo = &mut component.todo;
+    match name {
+        "UID" => component.uid = Some(value.to_string()),
+        "RELATED-TO" => {
+            // PARENT is the default relationship
+            let reltype = params
+                .iter()
+                .find_map(|param| param.strip_prefix("RELTYPE="));
+            if reltype.is_none_or(|reltype| reltype == "PARENT") {
+                component.parent_uid = Some(value.to_string());
+            }
+        }
+        "SUMMARY" => todo.todo = unescape(value).replace('\n', " "),
+        "DESCRIPTION" => todo.details = unescape(value),
+        "CREATED" => {
+            if let Some(created) = parse_time(value) {
+                todo.date = created;
+            }
+        }
+        "DTSTART" => todo.scheduled = parse_time(value).map(|time| time.date()),
+        "DUE" => todo.due = parse_time(value).map(|time| time.date()),
+        "COMPLETED" => todo.completed_at = parse_time(value),
+        "STATUS" if value.eq_ignore_ascii_case("COMPLETED") => {
+            todo.completed_at = todo.completed_at.or(Some(Local::now().naive_local()));
+        }
+        "PRIORITY" => {
+            todo.priority = match value.trim().parse::<u8>() {
+                Ok(1..=2) => Priority::Urgent,
+                Ok(3..=4) => Priority::High,
+                Ok(5) => Priority::Medium,
+                Ok(6..=9) => Priority::Low,
+                _ => Priority::None,
+            }
+        }
+        "RRULE" => todo.recurrence = parse_rrule(value),
+        "CATEGORIES" => {
+            let tags = parse_tags(&todo.todo);
+            for category in unescape(value).split(',') {
+                let tag: String = category
+                    .trim()
+                    .chars()
+                    .map(|c| {
+                        if c.is_alphanumeric() || c == '_' {
+                            c
+                        } else {
+                            '-'
+                        }
+                    })
+                    .collect();
+                let already_tagged = ["#", "@"]
+                    .iter()
+                    .any(|prefix| tags.contains(&format!("{prefix}{tag}").to_lowercase()));
+                if !tag.is_empty() && !already_tagged {
+                    todo.todo.push_str(&format!(" #{tag}"));
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Dates, floating local times and UTC times, converted to local time.
+/// Times with a TZID are read as if they were local.
+fn parse_time(value: &str) -> Option<NaiveDateTime> {
+    if let Ok(utc) = NaiveDateTime::parse_from_str(value, UTC_TIME) {
+        return Some(utc.and_utc().with_timezone(&Local).naive_local());
+    }
+    NaiveDateTime::parse_from_str(value, LOCAL_TIME)
+        .ok()
+        .or_else(|| {
+            NaiveDate::parse_from_str(value, DATE)
+                .ok()
+                .and_then(|date| date.and_hms_opt(0, 0, 0))
+        })
+}
+
+fn utc_time(local: NaiveDateTime) -> String {
+    local
+        .and_local_timezone(Local)
+        .earliest()
+        .map_or_else(|| local.and_utc(), |time| time.with_timezone(&Utc))
+        .format(UTC_TIME)
+        .to_string()
+}
+
+/// RFC 5545 has 1 as the highest priority and 9 as the lowest
+fn ics_priority(priority: Priority) -> Option<u8> {
+    match priority {
+        Priority::None => None,
+        Priority::Urgent => Some(1),
+        Priority::High => Some(3),
+        Priority::Medium => Some(5),
+        Priority::Low => Some(7),
+    }
+}
+
+fn rrule(recurrence: &Recurrence) -> String {
+    match recurrence {
+        Recurrence::Daily => "FREQ=DAILY".to_string(),
+        Recurrence::Weekly(weekdays) if weekdays.is_empty() => "FREQ=WEEKLY".to_string(),
+        Recurrence::Weekly(weekdays) => {
+            let days: Vec<String> = weekdays
+                .iter()
+                .map(|day| day.to_string()[..2].to_uppercase())
+                .collect();
+            format!("FREQ=WEEKLY;BYDAY={}", days.join(","))
+        }
+        Recurrence::Monthly(None) => "FREQ=MONTHLY".to_string(),
+        Recurrence::Monthly(Some(day)) => format!("FREQ=MONTHLY;BYMONTHDAY={day}"),
+        // The closest a calendar gets to counting from the completion date
+        Recurrence::AfterCompletion(days) => format!("FREQ=DAILY;INTERVAL={days}"),
+    }
+}
+
+fn parse_rrule(value: &str) -> Option<Recurrence> {
+    let parts: HashMap<String, String> = value
+        .split(';')
+        .filter_map(|part| part.split_once('='))
+        .map(|(key, value)| (key.to_uppercase(), value.to_uppercase()))
+        .collect();
+    let interval: u32 = parts
+        .get("INTERVAL")
+        .and_then(|interval| interval.parse().ok())
+        .unwrap_or(1);
+
+    match parts.get("FREQ")?.as_str() {
+        "DAILY" if interval > 1 => Some(Recurrence::AfterCompletion(interval)),
+        "DAILY" => Some(Recurrence::Daily),
+        "WEEKLY" => {
+            let weekdays = parts
+                .get("BYDAY")
+                .map(|days| days.split(',').filter_map(parse_weekday).collect())
+                .unwrap_or_default();
+            Some(Recurrence::Weekly(weekdays))
+        }
+        "MONTHLY" => Some(Recurrence::Monthly(
+            parts.get("BYMONTHDAY").and_then(|day| day.parse().ok()),
+        )),
+        _ => None,
+    }
+}
+
+fn parse_weekday(day: &str) -> Option<Weekday> {
+    // BYDAY entries may carry an ordinal, like 1MO for the first Monday
+    match day.trim_start_matches(|c: char| c.is_ascii_digit() || c == '-' || c == '+') {
+        "MO" => Some(Weekday::Mon),
+        "TU" => Some(Weekday::Tue),
+        "WE" => Some(Weekday::Wed),
+        "TH" => Some(Weekday::Thu),
+        "FR" => Some(Weekday::Fri),
+        "SA" => Some(Weekday::Sat),
+        "SU" => Some(Weekday::Sun),
+        _ => None,
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+}
+
+fn unescape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n' | 'N') => result.push('\n'),
+            Some(other) => result.push(other),
+            None => result.push('\\'),
+        }
+    }
+    result
+}
+
+/// Splits a content line into lines of at most 75 octets, as RFC 5545 asks,
+/// each ending in CRLF
+fn fold(line: &str) -> String {
+    let mut folded = String::with_capacity(line.len() + 8);
+    let mut width = 0;
+    for c in line.chars() {
+        if width + c.len_utf8() > 75 {
+            folded.push_str("\r\n ");
+            width = 1;
+        }
+        folded.push(c);
+        width += c.len_utf8();
+    }
+    folded.push_str("\r\n");
+    folded
+}
+
+/// Joins folded lines back together
+fn unfold(content: &str) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for line in content.lines() {
+        match (line.strip_prefix([' ', '\t']), lines.last_mut()) {
+            (Some(rest), Some(last)) => last.push_str(rest),
+            _ => lines.push(line.to_string()),
+        }
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::date;
+    use crate::models::INBOX_LIST_ID;
+
+    #[test]
+    fn alarms_inside_a_vtodo_keep_their_own_properties() {
+        let ics = "BEGIN:VCALENDAR\r\n\
+            BEGIN:VTODO\r\n\
+            UID:a\r\n\
+            SUMMARY:Pay rent\r\n\
+            BEGIN:VALARM\r\n\
+            ACTION:DISPLAY\r\n\
+            SUMMARY:Alarm\r\n\
+            DESCRIPTION:Reminder\r\n\
+            STATUS:COMPLETED\r\n\
+            TRIGGER:-PT15M\r\n\
+            END:VALARM\r\n\
+            DESCRIPTION:bank details here\r\n\
+            PRIORITY:1\r\n\
+            END:VTODO\r\n\
+            END:VCALENDAR\r\n";
+
+        let (components, skipped) = read_vtodos(ics);
+        assert_eq!(skipped, 0);
+        assert_eq!(components.len(), 1);
+        let todo = &components[0].todo;
+        assert_eq!(todo.todo, "Pay rent");
+        assert_eq!(todo.details, "bank details here");
+        assert!(todo.completed_at.is_none());
+        assert_eq!(todo.priority, Priority::Urgent);
+    }
+
+    #[test]
+    fn an_unclosed_alarm_ends_with_its_vtodo() {
+        let ics = "BEGIN:VTODO\nSUMMARY:First\nBEGIN:VALARM\nDESCRIPTION:Reminder\nEND:VTODO\n\
+            BEGIN:VTODO\nSUMMARY:Second\nDESCRIPTION:notes\nEND:VTODO\n";
+        let (components, _) = read_vtodos(ics);
+        let read: Vec<(&str, &str)> = components
+            .iter()
+            .map(|component| {
+                (
+                    component.todo.todo.as_str(),
+                    component.todo.details.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(read, [("First", ""), ("Second", "notes")]);
+    }
+
+    #[test]
+    fn round_trips_todos_and_their_nesting() {
+        let mut parent = new_todo_item(
+            "Plan a trip somewhere warm, with a summary long enough to be folded over lines #travel",
+            "Flights; hotels, and a \\ backslash\nSecond line",
+            None,
+        );
+        parent.id = Some(1);
+        parent.due = Some(date("2025-07-01"));
+        parent.scheduled = Some(date("2025-06-20"));
+        parent.priority = Priority::High;
+        parent.recurrence = Some(Recurrence::Weekly(vec![Weekday::Mon, Weekday::Fri]));
+        parent.tags = vec!["#travel".to_string()];
+        let mut child = new_todo_item("Book hotel", "", Some(1));
+        child.id = Some(2);
+        child.completed_at = Some(
+            NaiveDateTime::parse_from_str("2025-06-05 18:00:00", "%Y-%m-%d %H:%M:%S").unwrap(),
+        );
+        child.recurrence = Some(Recurrence::AfterCompletion(3));
+
+        let ics = to_ics(&[parent.clone(), child.clone()]);
+        assert!(ics.lines().all(|line| line.len() <= 75));
+        let import = parse_ics(&ics);
+
+        assert_eq!(import.depths(), [0, 1]);
+        let (read_parent, read_child) = (&import.todos[0].todo, &import.todos[1].todo);
+        assert_eq!(read_parent.todo, parent.todo);
+        assert_eq!(read_parent.details, parent.details);
+        assert_eq!(read_parent.due, parent.due);
+        assert_eq!(read_parent.scheduled, parent.scheduled);
+        assert_eq!(read_parent.priority, parent.priority);
+        assert_eq!(read_parent.recurrence, parent.recurrence);
+        assert_eq!(read_parent.list_id, INBOX_LIST_ID);
+        assert_eq!(read_child.todo, child.todo);
+        assert_eq!(read_child.completed_at, child.completed_at);
+        assert_eq!(read_child.recurrence, child.recurrence);
+    }
+
+    #[test]
+    fn nests_children_that_come_before_their_parent_and_skips_events() {
+        let ics = "BEGIN:VCALENDAR\n\
+            BEGIN:VTODO\nUID:child\nSUMMARY:Child\nRELATED-TO:parent\nEND:VTODO\n\
+            BEGIN:VEVENT\nUID:event\nSUMMARY:Meeting\nEND:VEVENT\n\
+            BEGIN:VTODO\nUID:parent\nSUMMARY:Parent\nCATEGORIES:Home,errands\nEND:VTODO\n\
+            BEGIN:VTODO\nUID:sibling\nSUMMARY:Not a child\nRELATED-TO;RELTYPE=SIBLING:parent\nEND:VTODO\n\
+            BEGIN:VTODO\nUID:empty\nEND:VTODO\n\
+            END:VCALENDAR\n";
+
+        let import = parse_ics(ics);
+        let read: Vec<(&str, usize)> = import
+            .todos
+            .iter()
+            .zip(import.depths())
+            .map(|(imported, depth)| (imported.todo.todo.as_str(), depth))
+            .collect();
+        assert_eq!(
+            read,
+            [
+                ("Parent #Home #errands", 0),
+                ("Child", 1),
+                ("Not a child", 0)
+            ]
+        );
+        // The event and the VTODO without a summary
+        assert_eq!(import.skipped, 2);
+    }
+
+    #[test]
+    fn todos_in_a_related_to_cycle_are_still_imported() {
+        let ics = "BEGIN:VTODO\nUID:a\nSUMMARY:A\nRELATED-TO:b\nEND:VTODO\n\
+            BEGIN:VTODO\nUID:b\nSUMMARY:B\nRELATED-TO:a\nEND:VTODO\n";
+        let import = parse_ics(ics);
+        assert_eq!(import.todos.len(), 2);
+        assert_eq!(import.depths(), [0, 1]);
+    }
+}
@@ -0,0 +1,596 @@
+use chrono::{Local, NaiveDate, NaiveDateTime, NaiveTime};
+use clap::ValueEnum;
+use color_eyre::Result;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use crate::export::TodoTree;
+use crate::input::parse_input;
+use crate::models::{Priority, SORT_GAP, TodoItem, new_todo_item};
+
+/// File formats other todo tools write that can be imported
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum ImportFormat {
+    /// Nested todos as written by `dodo export --format json`
+    Json,
+    /// One todo per line, as described at todotxt.org
+    Todotxt,
+    /// The JSON array written by `task export`
+    Taskwarrior,
+    /// `- [ ]` and `- [x]` items, nested by indentation
+    Markdown,
+}
+
+/// A todo read from a file. Its parent, if any, is given as an index into the
+/// same import and always comes before it.
+pub struct ImportedTodo {
+    pub todo: TodoItem,
+    pub parent: Option<usize>,
+}
+
+/// Everything read from a file, in the order it should be written
+#[derive(Default)]
+pub struct Import {
+    pub todos: Vec<ImportedTodo>,
+    /// Lines or tasks that weren't todos, like headings or deleted tasks
+    pub skipped: usize,
+}
+
+impl Import {
+    pub fn push(&mut self, mut todo: TodoItem, parent: Option<usize>) -> usize {
+        // Keep the order of the file among siblings
+        let siblings = self
+            .todos
+            .iter()
+            .filter(|imported| imported.parent == parent)
+            .count();
+        todo.sort_order = (siblings as i32 + 1) * SORT_GAP;
+        self.todos.push(ImportedTodo { todo, parent });
+        self.todos.len() - 1
+    }
+
+    /// How deeply each todo is nested, in the same order as `todos`
+    pub fn depths(&self) -> Vec<usize> {
+        let mut depths: Vec<usize> = Vec::with_capacity(self.todos.len());
+        for imported in &self.todos {
+            depths.push(imported.parent.map_or(0, |parent| depths[parent] + 1));
+        }
+        depths
+    }
+}
+
+pub fn parse(format: ImportFormat, content: &str) -> Result<Import> {
+    let today = Local::now().date_naive();
+    match format {
+        ImportFormat::Json => parse_json(content),
+        ImportFormat::Todotxt => Ok(parse_todotxt(content, today)),
+        ImportFormat::Taskwarrior => parse_taskwarrior(content),
+        ImportFormat::Markdown => Ok(parse_markdown(content, today)),
+    }
+}
+
+/// Reads back a `dodo export --format json` backup, keeping every field but
+/// the ids, which the database hands out afresh
+fn parse_json(content: &str) -> Result<Import> {
+    fn push_tree(import: &mut Import, tree: TodoTree, parent: Option<usize>) {
+        let mut todo = tree.todo;
+        todo.id = None;
+        todo.parent_id = None;
+        let index = import.push(todo, parent);
+        for child in tree.children {
+            push_tree(import, child, Some(index));
+        }
+    }
+
+    let trees: Vec<TodoTree> = serde_json::from_str(content)?;
+    let mut import = Import::default();
+    for tree in trees {
+        push_tree(&mut import, tree, None);
+    }
+    Ok(import)
+}
+
+/// Reads lines like `x 2024-05-02 2024-05-01 (A) Call mum +family @phone due:2024-05-03`.
+/// Projects become `#tags`, `t:` becomes the scheduled date and `(A)` to `(D)`
+/// become urgent to low priority. A `parent:` key nests a todo under an earlier
+/// one with the same `id:` key.
+fn parse_todotxt(content: &str, today: NaiveDate) -> Import {
+    let mut import = Import::default();
+    // Index of the todo carrying each `id:` key seen so far
+    let mut keyed: HashMap<String, usize> = HashMap::new();
+    for line in content.lines() {
+        let mut words: Vec<&str> = line.split_whitespace().collect();
+        if words.is_empty() {
+            continue;
+        }
+
+        let mut completed_at = None;
+        if words[0] == "x" {
+            words.remove(0);
+            let completed_on = words.first().and_then(|word| parse_iso_date(word));
+            if completed_on.is_some() {
+                words.remove(0);
+            }
+            completed_at =
+                Some(completed_on.map_or_else(|| Local::now().naive_local(), at_midnight));
+        }
+
+        let mut priority = None;
+        if let Some(word) = words.first()
+            && let Some(letter) = todotxt_priority(word)
+        {
+            priority = Some(letter);
+            words.remove(0);
+        }
+
+        let mut created = None;
+        if let Some(date) = words.first().and_then(|word| parse_iso_date(word)) {
+            created = Some(at_midnight(date));
+            words.remove(0);
+        }
+
+        // Some tools write the priority of a completed task after its dates
+        if priority.is_none()
+            && let Some(word) = words.first()
+            && let Some(letter) = todotxt_priority(word)
+        {
+            priority = Some(letter);
+            words.remove(0);
+        }
+
+        let mut key = None;
+        let mut parent = None;
+        let text: Vec<String> = words
+            .into_iter()
+            .filter_map(|word| {
+                if let Some(id) = word.strip_prefix("id:") {
+                    key = Some(id.to_string());
+                    return None;
+                }
+                if let Some(id) = word.strip_prefix("parent:") {
+                    parent = keyed.get(id).copied();
+                    return None;
+                }
+                // Completed tasks often keep their priority as `pri:A`
+                if let Some(letter) = word.strip_prefix("pri:")
+                    && let Some(level) = todotxt_priority(&format!("({letter})"))
+                {
+                    priority = priority.or(Some(level));
+                    return None;
+                }
+                Some(match word.strip_prefix('+') {
+                    Some(project) if !project.is_empty() => format!("#{}", tag_name(project)),
+                    _ => match word.strip_prefix("t:") {
+                        Some(date) => format!("sched:{date}"),
+                        None => word.to_string(),
+                    },
+                })
+            })
+            .collect();
+        if text.is_empty() {
+            import.skipped += 1;
+            continue;
+        }
+
+        let mut todo = todo_from_input(&text.join(" "), today);
+        todo.completed_at = completed_at;
+        if let Some(created) = created {
+            todo.date = created;
+        }
+        if let Some(priority) = priority {
+            todo.priority = priority;
+        }
+        let index = import.push(todo, parent);
+        if let Some(key) = key {
+            keyed.insert(key, index);
+        }
+    }
+    import
+}
+
+fn todotxt_priority(word: &str) -> Option<Priority> {
+    let letter = word.strip_prefix('(')?.strip_suffix(')')?;
+    match letter {
+        "A" => Some(Priority::Urgent),
+        "B" => Some(Priority::High),
+        "C" => Some(Priority::Medium),
+        _ if letter.len() == 1 && letter.chars().all(|c| c.is_ascii_uppercase()) => {
+            Some(Priority::Low)
+        }
+        _ => None,
+    }
+}
+
+/// The fields of a task in `task export` output that have somewhere to go
+#[derive(Deserialize)]
+struct TaskwarriorTask {
+    description: String,
+    status: String,
+    entry: Option<String>,
+    end: Option<String>,
+    due: Option<String>,
+    scheduled: Option<String>,
+    priority: Option<String>,
+    project: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    annotations: Vec<TaskwarriorAnnotation>,
+}
+
+#[derive(Deserialize)]
+struct TaskwarriorAnnotation {
+    description: String,
+}
+
+/// Reads the output of `task export`. Projects and tags become `#tags`,
+/// annotations become the details, and deleted tasks and the templates of
+/// recurring tasks are skipped.
+fn parse_taskwarrior(content: &str) -> Result<Import> {
+    let tasks: Vec<TaskwarriorTask> = serde_json::from_str(content)?;
+
+    let mut import = Import::default();
+    for task in tasks {
+        if !matches!(task.status.as_str(), "pending" | "waiting" | "completed") {
+            import.skipped += 1;
+            continue;
+        }
+
+        let mut text = task.description.trim().to_string();
+        if let Some(project) = &task.project {
+            text.push_str(&format!(" #{}", tag_name(project)));
+        }
+        for tag in &task.tags {
+            text.push_str(&format!(" #{}", tag_name(tag)));
+        }
+
+        // Descriptions are plain text, so don't read tokens like `due:` out of them
+        let mut todo = new_todo_item(&text, "", None);
+        todo.details = task
+            .annotations
+            .iter()
+            .map(|annotation| annotation.description.as_str())
+            .collect::<Vec<_>>()
+            .join("\n");
+        if let Some(entry) = task.entry.as_deref().and_then(parse_taskwarrior_date) {
+            todo.date = entry;
+        }
+        if task.status == "completed" {
+            todo.completed_at = Some(
+                task.end
+                    .as_deref()
+                    .and_then(parse_taskwarrior_date)
+                    .unwrap_or_else(|| Local::now().naive_local()),
+            );
+        }
+        todo.due = task
+            .due
+            .as_deref()
+            .and_then(parse_taskwarrior_date)
+            .map(|date| date.date());
+        todo.scheduled = task
+            .scheduled
+            .as_deref()
+            .and_then(parse_taskwarrior_date)
+            .map(|date| date.date());
+        todo.priority = match task.priority.as_deref() {
+            Some("H") => Priority::High,
+            Some("M") => Priority::Medium,
+            Some("L") => Priority::Low,
+            _ => Priority::None,
+        };
+        import.push(todo, None);
+    }
+    Ok(import)
+}
+
+/// Taskwarrior writes UTC times like `20240501T093000Z`
+fn parse_taskwarrior_date(value: &str) -> Option<NaiveDateTime> {
+    let utc = NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%SZ").ok()?;
+    Some(utc.and_utc().with_timezone(&Local).naive_local())
+}
+
+/// Reads `- [ ]` and `- [x]` items, nesting each under the closest less
+/// indented item above it. Indented text under an item goes into its details
+/// as written, blank lines included, less the indent of the item's text, and
+/// `due:`, `sched:`, `rec:` and `pri:` tokens are read as in the TUI.
+fn parse_markdown(content: &str, today: NaiveDate) -> Import {
+    let mut import = Import::default();
+    // Indentation and index of each item the next one could be nested under
+    let mut open: Vec<(usize, usize)> = Vec::new();
+    // Blank lines seen since the last detail line, kept only if more follow
+    let mut blank_lines = 0;
+
+    for line in content.lines() {
+        if line.trim().is_empty() {
+            blank_lines += 1;
+            continue;
+        }
+        let indent = line
+            .chars()
+            .take_while(|c| c.is_whitespace())
+            .map(|c| if c == '\t' { 4 } else { 1 })
+            .sum();
+
+        let Some((done, text)) = markdown_checkbox(line.trim()) else {
+            match open.last() {
+                Some(&(item_indent, index)) if indent > item_indent => {
+                    let details = &mut import.todos[index].todo.details;
+                    if !details.is_empty() {
+                        details.push_str(&"\n".repeat(blank_lines + 1));
+                    }
+                    // Past the `- ` of the item, so deeper indents survive
+                    details.push_str(strip_indent(line, item_indent + 2));
+                }
+                _ => import.skipped += 1,
+            }
+            blank_lines = 0;
+            continue;
+        };
+        blank_lines = 0;
+
+        while open
+            .last()
+            .is_some_and(|&(item_indent, _)| item_indent >= indent)
+        {
+            open.pop();
+        }
+        let parent = open.last().map(|&(_, index)| index);
+
+        let mut todo = todo_from_input(text, today);
+        if done {
+            todo.completed_at = Some(Local::now().naive_local());
+        }
+        let index = import.push(todo, parent);
+        open.push((indent, index));
+    }
+    import
+}
+
+/// Drops up to `width` columns of leading whitespace, counting a tab as four
+fn strip_indent(line: &str, width: usize) -> &str {
+    let mut columns = 0;
+    for (at, c) in line.char_indices() {
+        if columns >= width || !c.is_whitespace() {
+            return &line[at..];
+        }
+        columns += if c == '\t' { 4 } else { 1 };
+    }
+    ""
+}
+
+/// Splits `- [x] text` into whether it is ticked and its text
+fn markdown_checkbox(line: &str) -> Option<(bool, &str)> {
+    let rest = line
+        .strip_prefix("- ")
+        .or_else(|| line.strip_prefix("* "))
+        .or_else(|| line.strip_prefix("+ "))?;
+    let done = match rest.get(..3)? {
+        "[ ]" => false,
+        "[x]" | "[X]" => true,
+        _ => return None,
+    };
+    let text = rest[3..].trim();
+    (!text.is_empty()).then_some((done, text))
+}
+
+fn todo_from_input(text: &str, today: NaiveDate) -> TodoItem {
+    let input = parse_input(text, today);
+    let mut todo = new_todo_item(&input.text, "", None);
+    todo.due = input.due;
+    todo.scheduled = input.scheduled;
+    todo.recurrence = input.recurrence;
+    todo.priority = input.priority.unwrap_or_default();
+    todo
+}
+
+/// Makes a project or tag name from another tool usable as a `#tag`
+fn tag_name(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '_' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect()
+}
+
+fn parse_iso_date(word: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(word, "%Y-%m-%d").ok()
+}
+
+fn at_midnight(date: NaiveDate) -> NaiveDateTime {
+    date.and_time(NaiveTime::MIN)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::date;
+
+    fn time(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    fn texts(import: &Import) -> Vec<(&str, usize)> {
+        import
+            .todos
+            .iter()
+            .zip(import.depths())
+            .map(|(imported, depth)| (imported.todo.todo.as_str(), depth))
+            .collect()
+    }
+
+    #[test]
+    fn todotxt_reads_completion_dates_and_priorities() {
+        let import = parse_todotxt(
+            "x 2024-05-02 2024-05-01 (A) Call mum +family @phone due:2024-05-03\n\
+             \n\
+             (B) 2024-04-30 Write report t:2024-05-10\n\
+             x 2024-04-29 Pay rent pri:C\n\
+             x Water plants\n\
+             (Q) Someday\n\
+             x 2024-04-28\n",
+            date("2024-05-01"),
+        );
+        assert_eq!(
+            texts(&import),
+            [
+                ("Call mum #family @phone", 0),
+                ("Write report", 0),
+                ("Pay rent", 0),
+                ("Water plants", 0),
+                ("Someday", 0)
+            ]
+        );
+        // A completed line with nothing left but its date isn't a todo
+        assert_eq!(import.skipped, 1);
+
+        let todos: Vec<&TodoItem> = import.todos.iter().map(|imported| &imported.todo).collect();
+        assert_eq!(todos[0].completed_at, Some(time("2024-05-02 00:00:00")));
+        assert_eq!(todos[0].date, time("2024-05-01 00:00:00"));
+        assert_eq!(todos[0].priority, Priority::Urgent);
+        assert_eq!(todos[0].due, Some(date("2024-05-03")));
+
+        assert_eq!(todos[1].completed_at, None);
+        assert_eq!(todos[1].date, time("2024-04-30 00:00:00"));
+        assert_eq!(todos[1].priority, Priority::High);
+        assert_eq!(todos[1].scheduled, Some(date("2024-05-10")));
+
+        // With a single date after `x` it is the completion date
+        assert_eq!(todos[2].completed_at, Some(time("2024-04-29 00:00:00")));
+        assert_eq!(todos[2].priority, Priority::Medium);
+
+        assert!(todos[3].completed_at.is_some());
+        assert_eq!(todos[3].priority, Priority::None);
+        assert_eq!(todos[4].priority, Priority::Low);
+    }
+
+    #[test]
+    fn todotxt_nests_by_id_and_parent_keys() {
+        let import = parse_todotxt(
+            "Plan trip id:1\nBook hotel parent:1 id:2\nCompare prices parent:2\nUnknown parent:9\n",
+            date("2024-05-01"),
+        );
+        assert_eq!(
+            texts(&import),
+            [
+                ("Plan trip", 0),
+                ("Book hotel", 1),
+                ("Compare prices", 2),
+                ("Unknown", 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn taskwarrior_reads_tasks_and_skips_deleted_and_recurring_templates() {
+        let import = parse_taskwarrior(
+            r#"[
+                {"description": "Call mum", "status": "pending", "entry": "20240501T120000Z",
+                 "due": "20240503T120000Z", "scheduled": "20240502T120000Z", "priority": "H",
+                 "project": "family.calls", "tags": ["phone"],
+                 "annotations": [{"description": "ask about june"}, {"description": "due:fri stays text"}]},
+                {"description": "Pay rent due:fri", "status": "completed", "end": "20240502T120000Z",
+                 "priority": "L"},
+                {"description": "Old task", "status": "deleted"},
+                {"description": "Weekly review", "status": "recurring"},
+                {"description": "Wait for parcel", "status": "waiting"}
+            ]"#,
+        )
+        .unwrap();
+        assert_eq!(
+            texts(&import),
+            [
+                ("Call mum #family-calls #phone", 0),
+                ("Pay rent due:fri", 0),
+                ("Wait for parcel", 0)
+            ]
+        );
+        assert_eq!(import.skipped, 2);
+
+        let local = |s: &str| time(s).and_utc().with_timezone(&Local).naive_local();
+        let call = &import.todos[0].todo;
+        assert_eq!(call.date, local("2024-05-01 12:00:00"));
+        assert_eq!(call.due, Some(local("2024-05-03 12:00:00").date()));
+        assert_eq!(call.scheduled, Some(local("2024-05-02 12:00:00").date()));
+        assert_eq!(call.priority, Priority::High);
+        assert_eq!(call.details, "ask about june\ndue:fri stays text");
+        assert_eq!(call.completed_at, None);
+
+        let rent = &import.todos[1].todo;
+        assert_eq!(rent.completed_at, Some(local("2024-05-02 12:00:00")));
+        assert_eq!(rent.due, None);
+        assert_eq!(rent.priority, Priority::Low);
+        assert_eq!(import.todos[2].todo.priority, Priority::None);
+    }
+
+    #[test]
+    fn taskwarrior_rejects_what_isnt_an_export() {
+        assert!(parse_taskwarrior("{\"description\": \"not a list\"}").is_err());
+    }
+
+    #[test]
+    fn markdown_nests_items_and_skips_other_lines() {
+        let import = parse_markdown(
+            "# Trip\n\
+             - [ ] Plan trip due:2024-05-10 pri:high\n\
+             \x20\x20* [x] Book hotel\n\
+             \x20\x20\x20\x20+ [X] Compare prices\n\
+             \x20\x20- [ ] Pack\n\
+             - plain bullet\n\
+             - [ ] Water plants\n",
+            date("2024-05-01"),
+        );
+        assert_eq!(
+            texts(&import),
+            [
+                ("Plan trip", 0),
+                ("Book hotel", 1),
+                ("Compare prices", 2),
+                ("Pack", 1),
+                ("Water plants", 0)
+            ]
+        );
+        assert_eq!(import.skipped, 2);
+
+        let plan = &import.todos[0].todo;
+        assert_eq!(plan.due, Some(date("2024-05-10")));
+        assert_eq!(plan.priority, Priority::High);
+        assert_eq!(plan.completed_at, None);
+        assert!(import.todos[1].todo.completed_at.is_some());
+        assert!(import.todos[2].todo.completed_at.is_some());
+    }
+
+    #[test]
+    fn markdown_keeps_details_as_written() {
+        let import = parse_markdown(
+            "- [ ] Plan trip\n\
+             \x20\x20book flights\n\
+             \n\
+             \x20\x20check passports\n\
+             \x20\x20\x20\x20both expire in 2027\n\
+             \n\
+             \x20\x20- [ ] Book hotel\n\
+             \x20\x20\x20\x20near the station\n\
+             \n\
+             - [ ] Water plants\n",
+            date("2024-05-01"),
+        );
+        let details: Vec<&str> = import
+            .todos
+            .iter()
+            .map(|imported| imported.todo.details.as_str())
+            .collect();
+        assert_eq!(
+            details,
+            [
+                "book flights\n\ncheck passports\n  both expire in 2027",
+                "near the station",
+                ""
+            ]
+        );
+    }
+}
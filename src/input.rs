@@ -0,0 +1,116 @@
+use chrono::NaiveDate;
+
+use crate::dates::{DATE_FORMAT, parse_natural_date};
+use crate::models::{Priority, TodoItem};
+use crate::recurrence::Recurrence;
+
+/// Todo text with any `due:`, `sched:`, `rec:` and `pri:` tokens pulled out
+pub struct ParsedInput {
+    pub text: String,
+    pub due: Option<NaiveDate>,
+    pub scheduled: Option<NaiveDate>,
+    pub recurrence: Option<Recurrence>,
+    pub priority: Option<Priority>,
+}
+
+/// Splits tokens like `due:fri`, `sched:+3d`, `rec:weekly:mon` and `pri:high`
+/// out of the text typed for a todo. Tokens that can't be understood are left
+/// in the text.
+pub fn parse_input(input: &str, today: NaiveDate) -> ParsedInput {
+    let mut due = None;
+    let mut scheduled = None;
+    let mut recurrence = None;
+    let mut priority = None;
+
+    let words: Vec<&str> = input
+        .split(' ')
+        .filter(|word| {
+            if let Some(phrase) = word.strip_prefix("due:")
+                && let Some(date) = parse_natural_date(phrase, today)
+            {
+                due = Some(date);
+                return false;
+            }
+            if let Some(phrase) = word
+                .strip_prefix("sched:")
+                .or_else(|| word.strip_prefix("scheduled:"))
+                && let Some(date) = parse_natural_date(phrase, today)
+            {
+                scheduled = Some(date);
+                return false;
+            }
+            if let Some(rule) = word.strip_prefix("rec:")
+                && let Ok(rule) = rule.parse()
+            {
+                recurrence = Some(rule);
+                return false;
+            }
+            if let Some(level) = word.strip_prefix("pri:")
+                && let Ok(level) = level.parse()
+            {
+                priority = Some(level);
+                return false;
+            }
+            true
+        })
+        .collect();
+
+    ParsedInput {
+        text: words.join(" ").trim().to_string(),
+        due,
+        scheduled,
+        recurrence,
+        priority,
+    }
+}
+
+/// Finds `#tag` and `@context` tokens in todo text, lowercased and without duplicates
+pub fn parse_tags(text: &str) -> Vec<String> {
+    let mut tags: Vec<String> = Vec::new();
+    for word in text.split_whitespace() {
+        if is_tag(word) {
+            let tag = word.to_lowercase();
+            if !tags.contains(&tag) {
+                tags.push(tag);
+            }
+        }
+    }
+    tags
+}
+
+/// A `#` or `@` followed by letters, digits, `-` or `_`
+pub fn is_tag(word: &str) -> bool {
+    let mut chars = word.chars();
+    matches!(chars.next(), Some('#' | '@'))
+        && !chars.as_str().is_empty()
+        && chars.all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Whether a todo's tag satisfies a filter term. Terms without a `#` or `@`
+/// match tags and contexts of the same name.
+pub fn tag_matches(term: &str, tag: &str) -> bool {
+    let term = term.to_lowercase();
+    if term.starts_with(['#', '@']) {
+        term == tag
+    } else {
+        tag.get(1..) == Some(term.as_str())
+    }
+}
+
+/// Turns a todo's fields back into tokens so they survive a round trip through the edit popup
+pub fn input_tokens(todo: &TodoItem) -> String {
+    let mut tokens = String::new();
+    if let Some(due) = todo.due {
+        tokens.push_str(&format!(" due:{}", due.format(DATE_FORMAT)));
+    }
+    if let Some(scheduled) = todo.scheduled {
+        tokens.push_str(&format!(" sched:{}", scheduled.format(DATE_FORMAT)));
+    }
+    if let Some(recurrence) = &todo.recurrence {
+        tokens.push_str(&format!(" rec:{recurrence}"));
+    }
+    if todo.priority != Priority::None {
+        tokens.push_str(&format!(" pri:{}", todo.priority));
+    }
+    tokens
+}
@@ -0,0 +1,231 @@
+use color_eyre::Result;
+use color_eyre::eyre::{WrapErr, eyre};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing_subscriber::EnvFilter;
+
+use crate::config::log_path;
+
+/// Used when `DODO_LOG` isn't set. sqlx logs every query at info.
+const DEFAULT_FILTER: &str = "info,sqlx=warn";
+
+/// Size the log may grow to before it's moved aside
+const MAX_SIZE: u64 = 5 * 1024 * 1024;
+
+/// How many moved aside logs are kept, as `dodo.log.1` (newest) and up
+const KEEP: u32 = 3;
+
+/// Sends tracing output to the log file, at the levels `DODO_LOG` asks for,
+/// e.g. `DODO_LOG=debug` or `DODO_LOG=dodo::writer=trace`
+pub fn init() -> Result<()> {
+    let filter = match std::env::var("DODO_LOG") {
+        Ok(directives) => EnvFilter::try_new(directives).wrap_err("DODO_LOG isn't valid")?,
+        Err(_) => EnvFilter::new(DEFAULT_FILTER),
+    };
+    let file = RotatingFile::open(log_path(), MAX_SIZE)?;
+
+    tracing_subscriber::fmt()
+        .with_writer(Mutex::new(file))
+        .with_ansi(false)
+        .with_env_filter(filter)
+        .try_init()
+        .map_err(|e| eyre!(e))?;
+    Ok(())
+}
+
+/// A log file that's moved to `.1` once it grows past `max_size`, shifting
+/// older ones along and dropping the oldest. Several dodos can write to the
+/// same log, so its size is read from disk rather than counted.
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    max_size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_size: u64) -> io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Self {
+            path,
+            file,
+            max_size,
+        })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        for n in (1..KEEP).rev() {
+            rename_if_exists(&rotated(&self.path, n), &rotated(&self.path, n + 1))?;
+        }
+        rename_if_exists(&self.path, &rotated(&self.path, 1))?;
+        *self = Self::open(self.path.clone(), self.max_size)?;
+        Ok(())
+    }
+
+    /// The size of the log at `path`, first reopening it if another dodo has
+    /// moved the file this one has open aside
+    fn current_size(&mut self) -> io::Result<u64> {
+        let ours = self.file.metadata()?;
+        let moved = match fs::metadata(&self.path) {
+            // The same file looks the same through both; a false alarm only
+            // reopens it
+            Ok(theirs) => {
+                theirs.len() != ours.len() || theirs.modified().ok() != ours.modified().ok()
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => true,
+            Err(e) => return Err(e),
+        };
+        if !moved {
+            return Ok(ours.len());
+        }
+        *self = Self::open(self.path.clone(), self.max_size)?;
+        Ok(self.file.metadata()?.len())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let size = self.current_size()?;
+        if size > 0 && size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn rotated(path: &Path, n: u32) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{n}"));
+    PathBuf::from(name)
+}
+
+fn rename_if_exists(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// Prints the log, or with `follow` its last `lines` lines and then anything
+/// logged after them until interrupted
+pub async fn print(follow: bool, lines: usize) -> Result<()> {
+    let path = log_path();
+    let contents = match fs::read(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound && !follow => {
+            println!("Nothing logged yet at {}", path.display());
+            return Ok(());
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e.into()),
+    };
+    let text = String::from_utf8_lossy(&contents);
+    if !follow {
+        print!("{text}");
+        return Ok(());
+    }
+
+    let all: Vec<&str> = text.lines().collect();
+    for line in &all[all.len().saturating_sub(lines)..] {
+        println!("{line}");
+    }
+
+    let mut offset = contents.len() as u64;
+    loop {
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let size = fs::metadata(&path).map_or(0, |metadata| metadata.len());
+        if size < offset {
+            // Rotated, so the new file is read from its start
+            offset = 0;
+        }
+        if size > offset {
+            let mut file = File::open(&path)?;
+            file.seek(SeekFrom::Start(offset))?;
+            let mut added = Vec::new();
+            file.read_to_end(&mut added)?;
+            offset += added.len() as u64;
+            print!("{}", String::from_utf8_lossy(&added));
+            io::stdout().flush()?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh directory for a test's logs, removed when dropped
+    struct LogDir(PathBuf);
+
+    impl LogDir {
+        fn new() -> Self {
+            let dir = std::env::temp_dir().join(format!("dodo-log-{}", uuid::Uuid::new_v4()));
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+
+        fn log(&self) -> PathBuf {
+            self.0.join("dodo.log")
+        }
+
+        fn read(&self, n: u32) -> Option<String> {
+            let path = if n == 0 {
+                self.log()
+            } else {
+                rotated(&self.log(), n)
+            };
+            fs::read_to_string(path).ok()
+        }
+    }
+
+    impl Drop for LogDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn rotates_past_max_size_and_keeps_the_newest() {
+        let dir = LogDir::new();
+        let mut log = RotatingFile::open(dir.log(), 6).unwrap();
+        for line in [
+            "a\n", "b\n", "c\n", "d\n", "e\n", "f\n", "g\n", "h\n", "i\n", "j\n",
+        ] {
+            log.write_all(line.as_bytes()).unwrap();
+        }
+
+        assert_eq!(dir.read(0).as_deref(), Some("j\n"));
+        assert_eq!(dir.read(1).as_deref(), Some("g\nh\ni\n"));
+        assert_eq!(dir.read(2).as_deref(), Some("d\ne\nf\n"));
+        assert_eq!(dir.read(KEEP).as_deref(), Some("a\nb\nc\n"));
+        assert_eq!(dir.read(KEEP + 1), None);
+    }
+
+    #[test]
+    fn a_log_moved_aside_by_another_process_is_reopened() {
+        let dir = LogDir::new();
+        let mut first = RotatingFile::open(dir.log(), 6).unwrap();
+        let mut second = RotatingFile::open(dir.log(), 6).unwrap();
+
+        first.write_all(b"1\n").unwrap();
+        second.write_all(b"2\n").unwrap();
+        first.write_all(b"3\n").unwrap();
+        // Full counting what `second` wrote, so `first` moves the log aside
+        first.write_all(b"4\n").unwrap();
+        // and `second` follows it to the new one
+        second.write_all(b"5\n").unwrap();
+
+        assert_eq!(dir.read(0).as_deref(), Some("4\n5\n"));
+        assert_eq!(dir.read(1).as_deref(), Some("1\n2\n3\n"));
+        assert_eq!(dir.read(2), None);
+    }
+}
@@ -1,3 +1,4 @@
+use clap::Parser;
 use color_eyre::Result;
 use crossterm::execute;
 use crossterm::terminal::{self, EnterAlternateScreen, LeaveAlternateScreen};
@@ -5,22 +6,79 @@ use ratatui::Terminal;
 use ratatui::backend::CrosstermBackend;
 use sqlx::sqlite::{SqliteConnectOptions, SqlitePool};
 use std::env;
-use std::str::FromStr;
 
 mod app;
+mod caldav;
+mod cli;
+mod config;
+mod dates;
 mod db;
+mod export;
+mod history;
+mod ics;
+mod import;
+mod input;
+mod logging;
 mod models;
+mod recurrence;
+mod search;
+mod server;
+mod sync;
 mod ui;
+mod writer;
 
 #[tokio::main]
 async fn main() -> Result<(), color_eyre::Report> {
     color_eyre::install()?;
 
-    let options = SqliteConnectOptions::from_str("sqlite:todos.db")?.create_if_missing(true);
+    let cli = crate::cli::Cli::parse();
+    let config = crate::config::Config::load()?;
+    let db_path = crate::config::resolve_db_path(cli.db, &config);
+
+    // Not being able to log shouldn't stop dodo from working
+    if let Err(e) = crate::logging::init() {
+        eprintln!(
+            "Not logging to {}: {e}",
+            crate::config::log_path().display()
+        );
+    }
+
+    if cli.print_db_path {
+        println!("{}", db_path.display());
+        return Ok(());
+    }
+
+    if let Some(parent) = db_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let options = SqliteConnectOptions::new()
+        .filename(&db_path)
+        .create_if_missing(true);
     let pool = SqlitePool::connect_with(options).await?;
 
-    // Create the todos table if it doesn't exist
-    crate::db::create_todos_table(&pool).await?;
+    // Create or upgrade the schema before anything reads from it
+    crate::db::migrate(&pool).await?;
+
+    let retention_days = config.trash_retention_days();
+    if retention_days > 0 {
+        let cutoff = chrono::Local::now().naive_local() - chrono::Days::new(retention_days.into());
+        crate::db::purge_deleted_before(&pool, cutoff).await?;
+    }
+
+    if let Some(command) = cli.command {
+        let description = format!("{command:?}");
+        let started = std::time::Instant::now();
+        let result = crate::cli::run(command, cli.list.as_deref(), &config, &pool).await;
+        let elapsed = started.elapsed();
+        match &result {
+            Ok(()) => tracing::info!(command = description, ?elapsed, "ran command"),
+            Err(e) => {
+                tracing::error!(command = description, ?elapsed, error = %e, "command failed")
+            }
+        }
+        return result;
+    }
 
     let app = crate::app::App::with_pool(pool).await?;
 
@@ -1,21 +1,119 @@
-use chrono::{Local, NaiveDateTime};
+use chrono::{Local, NaiveDate, NaiveDateTime};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+use crate::input::tag_matches;
+use crate::recurrence::Recurrence;
 
 #[derive(PartialEq)]
 pub enum InputMode {
     Normal,
     Insert,
+    Filter,
+    /// Typing a fuzzy search that narrows the focused list as it changes
+    Search,
+    /// Full screen editor for the details of a todo
+    Details,
+    /// Popup for picking, creating and archiving named lists
+    Lists,
+    /// Typing a `:` command in the footer
+    Command,
+    /// Popup listing every notice shown this session
+    Messages,
 }
 
 impl InputMode {
     pub fn toggle(&mut self) {
         *self = match self {
             InputMode::Normal => InputMode::Insert,
-            InputMode::Insert => InputMode::Normal,
+            InputMode::Insert
+            | InputMode::Filter
+            | InputMode::Search
+            | InputMode::Details
+            | InputMode::Lists
+            | InputMode::Command
+            | InputMode::Messages => InputMode::Normal,
         }
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    #[default]
+    None,
+    Low,
+    Medium,
+    High,
+    Urgent,
+}
+
+impl Priority {
+    const LEVELS: [Priority; 5] = [
+        Priority::None,
+        Priority::Low,
+        Priority::Medium,
+        Priority::High,
+        Priority::Urgent,
+    ];
+
+    pub fn from_level(level: i64) -> Self {
+        Self::LEVELS[level.clamp(0, 4) as usize]
+    }
+
+    pub fn level(self) -> i64 {
+        self as i64
+    }
+
+    pub fn raise(self) -> Self {
+        Self::from_level(self.level() + 1)
+    }
+
+    pub fn lower(self) -> Self {
+        Self::from_level(self.level() - 1)
+    }
+
+    /// Marker shown in front of the todo text
+    pub fn marker(self) -> &'static str {
+        match self {
+            Priority::None => "",
+            Priority::Low => "↓ ",
+            Priority::Medium => "! ",
+            Priority::High => "!! ",
+            Priority::Urgent => "!!! ",
+        }
+    }
+}
+
+impl std::fmt::Display for Priority {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Priority::None => "none",
+            Priority::Low => "low",
+            Priority::Medium => "medium",
+            Priority::High => "high",
+            Priority::Urgent => "urgent",
+        };
+        write!(f, "{name}")
+    }
+}
+
+impl std::str::FromStr for Priority {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input.to_lowercase().as_str() {
+            "none" | "n" => Ok(Priority::None),
+            "low" | "l" => Ok(Priority::Low),
+            "medium" | "med" | "m" => Ok(Priority::Medium),
+            "high" | "h" => Ok(Priority::High),
+            "urgent" | "u" => Ok(Priority::Urgent),
+            _ => Err(format!("unknown priority '{input}'")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TodoItem {
     pub id: Option<i64>,
     pub todo: String,
@@ -24,6 +122,24 @@ pub struct TodoItem {
     pub date: NaiveDateTime,
     pub parent_id: Option<i64>,
     pub sort_order: i32,
+    pub due: Option<NaiveDate>,
+    pub scheduled: Option<NaiveDate>,
+    pub recurrence: Option<Recurrence>,
+    pub tags: Vec<String>,
+    pub priority: Priority,
+    /// Whether the todo's subtree is folded away in the TUI
+    pub collapsed: bool,
+    /// When the todo was moved to the trash
+    pub deleted_at: Option<NaiveDateTime>,
+    #[serde(default = "inbox_list_id")]
+    pub list_id: i64,
+}
+
+impl TodoItem {
+    /// An uncompleted todo whose due date has passed
+    pub fn is_overdue(&self, today: NaiveDate) -> bool {
+        self.completed_at.is_none() && self.due.is_some_and(|due| due < today)
+    }
 }
 
 pub struct TodoList {
@@ -45,12 +161,116 @@ pub struct TodoRow {
     pub date: String,
     pub parent_id: Option<i64>,
     pub sort_order: i32,
+    pub due: Option<String>,
+    pub scheduled: Option<String>,
+    pub recurrence: Option<String>,
+    pub priority: i64,
+    pub collapsed: bool,
+    pub deleted_at: Option<String>,
+    pub list_id: i64,
+}
+
+/// The list every database starts with, which can't be archived
+pub const INBOX_LIST_ID: i64 = 1;
+
+fn inbox_list_id() -> i64 {
+    INBOX_LIST_ID
+}
+
+/// A named list of todos, such as one per project
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct NamedList {
+    pub id: i64,
+    pub name: String,
+    pub archived: bool,
+}
+
+/// What picking a list in the list popup does
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ListAction {
+    Switch,
+    /// Moves the todo with this id, and its subtree, to the picked list
+    Move(i64),
+}
+
+/// The name being typed in the list popup, when there is one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ListPrompt {
+    Create,
+    Rename(i64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NoticeLevel {
+    Info,
+    Warning,
+    Error,
+}
+
+impl NoticeLevel {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            NoticeLevel::Info => "info",
+            NoticeLevel::Warning => "warning",
+            NoticeLevel::Error => "error",
+        }
+    }
+}
+
+/// A message for the status line, kept for the `:messages` popup
+#[derive(Debug, Clone)]
+pub struct Notice {
+    pub level: NoticeLevel,
+    pub text: String,
+    pub at: NaiveDateTime,
+}
+
+/// A todo as stored on a CalDAV server
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct CaldavResource {
+    /// URL of the collection the todo is in
+    pub collection: String,
+    pub href: String,
+    pub uid: String,
+    pub etag: Option<String>,
+    /// The todo's VTODO as of the last sync, to tell whether it changed since
+    pub synced: String,
+    /// None once the todo has been purged
+    pub todo_id: Option<i64>,
+}
+
+/// One field of a todo being set, as recorded in the change log and passed
+/// between machines by `dodo sync`
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Change {
+    /// Position in this database's log, which differs from machine to machine
+    #[serde(skip)]
+    pub seq: i64,
+    pub change_id: String,
+    pub todo_uuid: String,
+    /// A column of `todos`, `parent` or `list` holding the UUID of the parent
+    /// or the name of the list, `purged` once the todo is gone for good, or
+    /// `restored` when undo brings a purged todo back
+    pub field: String,
+    pub value: Option<String>,
+    /// UTC, to the millisecond, which decides which change to a field wins
+    pub changed_at: String,
+}
+
+/// How siblings are ordered within each level of the hierarchy
+#[derive(Copy, Clone, PartialEq)]
+pub enum SortMode {
+    /// By `sort_order`, as arranged with J/K
+    Manual,
+    /// Highest priority first, then by `sort_order`
+    Priority,
 }
 
 #[derive(Copy, Clone, PartialEq)]
 pub enum WhichList {
     Uncompleted,
     Completed,
+    Trash,
 }
 
 pub fn parse_date_string(date_str: &str) -> NaiveDateTime {
@@ -65,41 +285,200 @@ pub fn new_todo_item(todo: &str, details: &str, parent_id: Option<i64>) -> TodoI
         details: details.to_string(),
         completed_at: None,
         date: Local::now().naive_local(),
-        parent_id: parent_id,
+        parent_id,
         sort_order: 0,
+        due: None,
+        scheduled: None,
+        recurrence: None,
+        tags: Vec::new(),
+        priority: Priority::None,
+        collapsed: false,
+        deleted_at: None,
+        list_id: INBOX_LIST_ID,
     }
 }
 
-pub fn sort_todos_hierarchically(items: Vec<TodoItem>) -> Vec<TodoItem> {
-    let mut result = Vec::new();
+/// Keeps the items matching `predicate` along with their ancestors, so
+/// filtered lists still read as a hierarchy
+pub fn retain_with_ancestors(
+    items: Vec<TodoItem>,
+    predicate: impl Fn(&TodoItem) -> bool,
+) -> Vec<TodoItem> {
+    let mut keep: Vec<Option<i64>> = Vec::new();
+    for item in items.iter().filter(|item| predicate(item)) {
+        keep.push(item.id);
+        let mut parent_id = item.parent_id;
+        while let Some(id) = parent_id {
+            if keep.contains(&Some(id)) {
+                break;
+            }
+            keep.push(Some(id));
+            parent_id = items
+                .iter()
+                .find(|other| other.id == Some(id))
+                .and_then(|parent| parent.parent_id);
+        }
+    }
+
+    items
+        .into_iter()
+        .filter(|item| item.id.is_some() && keep.contains(&item.id) || predicate(item))
+        .collect()
+}
 
-    let mut top_level: Vec<TodoItem> = items
+/// Keeps todos carrying every one of `terms`, either themselves or through
+/// an ancestor, along with the ancestors of those todos
+pub fn filter_by_tags(items: Vec<TodoItem>, terms: &[String]) -> Vec<TodoItem> {
+    let tagged: Vec<Option<i64>> = items
         .iter()
-        .filter(|item| item.parent_id.is_none())
-        .cloned()
+        .filter(|item| {
+            terms
+                .iter()
+                .all(|term| item.tags.iter().any(|tag| tag_matches(term, tag)))
+        })
+        .map(|item| item.id)
         .collect();
 
-    top_level.sort_by_key(|item| item.sort_order);
+    let lookup = items.clone();
+    retain_with_ancestors(items, |item| {
+        let mut current = Some(item);
+        while let Some(todo) = current {
+            if tagged.contains(&todo.id) {
+                return true;
+            }
+            current = todo
+                .parent_id
+                .and_then(|id| lookup.iter().find(|other| other.id == Some(id)));
+        }
+        false
+    })
+}
 
-    let children: Vec<TodoItem> = items
+/// Space left between the sort orders of neighbouring todos, so a todo can
+/// usually be moved between two others by rewriting only its own
+pub const SORT_GAP: i32 = 1024;
+
+/// Returns a sort order that places a new todo after all of the given items
+pub fn next_sort_order(items: &[TodoItem]) -> i32 {
+    items.iter().map(|item| item.sort_order).max().unwrap_or(0) + SORT_GAP
+}
+
+/// The closest todo above the one at `index` that shares its parent, in a
+/// list ordered by `sort_todos_hierarchically_by`
+pub fn previous_sibling(items: &[TodoItem], index: usize) -> Option<&TodoItem> {
+    let parent_id = items.get(index)?.parent_id;
+    items[..index]
         .iter()
-        .filter(|item| item.parent_id.is_some())
-        .cloned()
-        .collect();
+        .rev()
+        .find(|item| item.parent_id == parent_id)
+}
 
-    for parent in top_level {
-        result.push(parent.clone());
+/// The closest todo below the one at `index` that shares its parent, skipping
+/// over its subtree
+pub fn next_sibling(items: &[TodoItem], index: usize) -> Option<&TodoItem> {
+    let parent_id = items.get(index)?.parent_id;
+    items[index + 1..]
+        .iter()
+        .find(|item| item.parent_id == parent_id)
+}
+
+pub fn sort_todos_hierarchically(items: Vec<TodoItem>) -> Vec<TodoItem> {
+    sort_todos_hierarchically_by(items, SortMode::Manual)
+}
 
-        let mut parent_children: Vec<TodoItem> = children
-            .iter()
-            .filter(|child| child.parent_id == parent.id)
-            .cloned()
-            .collect();
+/// Orders todos depth first so each todo is followed by its subtree, however
+/// deep. Todos whose parent isn't among `items` are treated as top level.
+pub fn sort_todos_hierarchically_by(items: Vec<TodoItem>, mode: SortMode) -> Vec<TodoItem> {
+    let sort_key = |item: &TodoItem| {
+        let priority = match mode {
+            SortMode::Manual => Priority::None,
+            SortMode::Priority => item.priority,
+        };
+        (std::cmp::Reverse(priority), item.sort_order)
+    };
 
-        parent_children.sort_by_key(|item| item.sort_order);
+    let ids: HashSet<i64> = items.iter().filter_map(|item| item.id).collect();
+    let mut children: HashMap<i64, Vec<&TodoItem>> = HashMap::new();
+    let mut top_level: Vec<&TodoItem> = Vec::new();
 
-        result.extend(parent_children);
+    for item in &items {
+        match item.parent_id {
+            Some(parent_id) if ids.contains(&parent_id) => {
+                children.entry(parent_id).or_default().push(item)
+            }
+            _ => top_level.push(item),
+        }
+    }
+
+    top_level.sort_by_key(|item| sort_key(item));
+    for siblings in children.values_mut() {
+        siblings.sort_by_key(|item| sort_key(item));
+    }
+
+    let mut result = Vec::with_capacity(items.len());
+    let mut stack: Vec<&TodoItem> = top_level.into_iter().rev().collect();
+    while let Some(item) = stack.pop() {
+        result.push(item.clone());
+        if let Some(id) = item.id
+            && let Some(siblings) = children.get(&id)
+        {
+            stack.extend(siblings.iter().rev());
+        }
     }
 
     result
 }
+
+/// How many ancestors each todo has among `items`, for indentation
+pub fn todo_depths(items: &[TodoItem]) -> Vec<usize> {
+    let parents: HashMap<i64, Option<i64>> = items
+        .iter()
+        .filter_map(|item| item.id.map(|id| (id, item.parent_id)))
+        .collect();
+
+    items
+        .iter()
+        .map(|item| {
+            let mut depth = 0;
+            let mut parent_id = item.parent_id;
+            while let Some(Some(&grandparent_id)) = parent_id.map(|id| parents.get(&id)) {
+                depth += 1;
+                parent_id = grandparent_id;
+                // A parent_id cycle would otherwise never end
+                if depth > items.len() {
+                    break;
+                }
+            }
+            depth
+        })
+        .collect()
+}
+
+/// Drops the descendants of collapsed todos
+pub fn hide_collapsed(items: Vec<TodoItem>) -> Vec<TodoItem> {
+    let parents: HashMap<i64, (Option<i64>, bool)> = items
+        .iter()
+        .filter_map(|item| item.id.map(|id| (id, (item.parent_id, item.collapsed))))
+        .collect();
+
+    items
+        .into_iter()
+        .filter(|item| {
+            let mut parent_id = item.parent_id;
+            let mut steps = 0;
+            while let Some(Some(&(grandparent_id, collapsed))) =
+                parent_id.map(|id| parents.get(&id))
+            {
+                if collapsed {
+                    return false;
+                }
+                parent_id = grandparent_id;
+                steps += 1;
+                if steps > parents.len() {
+                    break;
+                }
+            }
+            true
+        })
+        .collect()
+}
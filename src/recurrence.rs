@@ -0,0 +1,228 @@
+use chrono::{Datelike, Days, Months, NaiveDate, Weekday};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+/// How a todo repeats once it is completed
+#[derive(Debug, Clone, PartialEq)]
+pub enum Recurrence {
+    Daily,
+    /// On the given weekdays, or the weekday of the due date when empty
+    Weekly(Vec<Weekday>),
+    /// On the given day of the month, or the day of the due date when unset
+    Monthly(Option<u32>),
+    /// Every N days counted from when the todo was completed
+    AfterCompletion(u32),
+}
+
+impl Recurrence {
+    /// The date the next instance is due. Dates are stepped forward from `base`
+    /// until they land after `completed`, so a week of missed dailies produces
+    /// one todo for tomorrow rather than seven overdue ones. None once the
+    /// series runs past the last date chrono can represent.
+    pub fn next_date(&self, base: NaiveDate, completed: NaiveDate) -> Option<NaiveDate> {
+        if let Recurrence::AfterCompletion(days) = self {
+            return completed.checked_add_days(Days::new(u64::from(*days).max(1)));
+        }
+
+        let mut next = self.step(base, base)?;
+        while next <= completed {
+            next = self.step(next, base)?;
+        }
+        Some(next)
+    }
+
+    fn step(&self, from: NaiveDate, base: NaiveDate) -> Option<NaiveDate> {
+        match self {
+            Recurrence::Daily => from.checked_add_days(Days::new(1)),
+            Recurrence::Weekly(weekdays) => {
+                let weekdays = if weekdays.is_empty() {
+                    vec![base.weekday()]
+                } else {
+                    weekdays.clone()
+                };
+                (1..=7)
+                    .map_while(|offset| from.checked_add_days(Days::new(offset)))
+                    .find(|date| weekdays.contains(&date.weekday()))
+            }
+            Recurrence::Monthly(day) => {
+                let day = day.unwrap_or(base.day());
+                let next_month = from
+                    .with_day(1)
+                    .and_then(|first| first.checked_add_months(Months::new(1)))?;
+                // Clamp to the last day for short months, e.g. the 31st in April
+                (0..4)
+                    .find_map(|back| next_month.with_day(day.saturating_sub(back).max(1)))
+                    .or(Some(next_month))
+            }
+            Recurrence::AfterCompletion(days) => {
+                from.checked_add_days(Days::new(u64::from(*days).max(1)))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Recurrence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Recurrence::Daily => write!(f, "daily"),
+            Recurrence::Weekly(weekdays) if weekdays.is_empty() => write!(f, "weekly"),
+            Recurrence::Weekly(weekdays) => {
+                let names: Vec<String> = weekdays
+                    .iter()
+                    .map(|day| day.to_string().to_lowercase())
+                    .collect();
+                write!(f, "weekly:{}", names.join(","))
+            }
+            Recurrence::Monthly(None) => write!(f, "monthly"),
+            Recurrence::Monthly(Some(day)) => write!(f, "monthly:{day}"),
+            Recurrence::AfterCompletion(days) => write!(f, "+{days}d"),
+        }
+    }
+}
+
+impl FromStr for Recurrence {
+    type Err = String;
+
+    /// Parses "daily", "weekly", "weekly:mon,thu", "monthly", "monthly:15" or "+3d"
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let input = input.trim().to_lowercase();
+        let (kind, arg) = match input.split_once(':') {
+            Some((kind, arg)) => (kind, Some(arg)),
+            None => (input.as_str(), None),
+        };
+
+        let recurrence = match (kind, arg) {
+            ("daily", None) => Recurrence::Daily,
+            ("weekly", None) => Recurrence::Weekly(Vec::new()),
+            ("weekly", Some(days)) => Recurrence::Weekly(
+                days.split(',')
+                    .map(|day| day.parse::<Weekday>().ok())
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or_else(|| format!("unknown weekday in '{input}'"))?,
+            ),
+            ("monthly", None) => Recurrence::Monthly(None),
+            ("monthly", Some(day)) => Recurrence::Monthly(Some(
+                day.parse()
+                    .ok()
+                    .filter(|day| (1..=31).contains(day))
+                    .ok_or_else(|| format!("day of month must be 1-31 in '{input}'"))?,
+            )),
+            _ => {
+                let days = kind
+                    .strip_prefix('+')
+                    .and_then(|rest| rest.strip_suffix('d'))
+                    .and_then(|count| count.parse().ok())
+                    .filter(|&count: &u32| count > 0)
+                    .ok_or_else(|| format!("unknown recurrence '{input}'"))?;
+                Recurrence::AfterCompletion(days)
+            }
+        };
+
+        Ok(recurrence)
+    }
+}
+
+impl Serialize for Recurrence {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Recurrence {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let rule = String::deserialize(deserializer)?;
+        rule.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::date;
+
+    #[test]
+    fn parses_and_prints_rules() {
+        for rule in [
+            "daily",
+            "weekly",
+            "weekly:mon,thu",
+            "monthly",
+            "monthly:15",
+            "+3d",
+        ] {
+            assert_eq!(rule.parse::<Recurrence>().unwrap().to_string(), rule);
+        }
+        assert_eq!(
+            "Weekly:Monday,FRI".parse(),
+            Ok(Recurrence::Weekly(vec![Weekday::Mon, Weekday::Fri]))
+        );
+    }
+
+    #[test]
+    fn rejects_bad_rules() {
+        for rule in [
+            "",
+            "hourly",
+            "weekly:someday",
+            "monthly:0",
+            "monthly:32",
+            "+0d",
+            "+d",
+            "3d",
+        ] {
+            assert!(rule.parse::<Recurrence>().is_err(), "{rule:?}");
+        }
+    }
+
+    #[test]
+    fn steps_past_the_completion_date() {
+        // Due on a Monday, completed a week and a half late on a Thursday
+        let due = date("2025-06-02");
+        let completed = date("2025-06-12");
+        assert_eq!(
+            Recurrence::Daily.next_date(due, completed),
+            Some(date("2025-06-13"))
+        );
+        assert_eq!(
+            Recurrence::Weekly(Vec::new()).next_date(due, completed),
+            Some(date("2025-06-16"))
+        );
+        assert_eq!(
+            Recurrence::Weekly(vec![Weekday::Tue, Weekday::Sat]).next_date(due, completed),
+            Some(date("2025-06-14"))
+        );
+        assert_eq!(
+            Recurrence::AfterCompletion(3).next_date(due, completed),
+            Some(date("2025-06-15"))
+        );
+    }
+
+    #[test]
+    fn monthly_clamps_to_short_months() {
+        let due = date("2025-01-31");
+        let rule = Recurrence::Monthly(None);
+        assert_eq!(rule.next_date(due, due), Some(date("2025-02-28")));
+        // The day of the original date is kept, not the clamped one
+        assert_eq!(
+            rule.next_date(due, date("2025-02-28")),
+            Some(date("2025-03-31"))
+        );
+        assert_eq!(
+            Recurrence::Monthly(Some(15)).next_date(due, due),
+            Some(date("2025-02-15"))
+        );
+    }
+
+    #[test]
+    fn stops_at_the_end_of_the_calendar() {
+        let last = NaiveDate::MAX;
+        assert_eq!(Recurrence::Daily.next_date(last, last), None);
+        assert_eq!(Recurrence::Weekly(Vec::new()).next_date(last, last), None);
+        assert_eq!(Recurrence::Monthly(None).next_date(last, last), None);
+        assert_eq!(
+            Recurrence::AfterCompletion(u32::MAX).next_date(date("2025-01-01"), date("2025-01-01")),
+            None
+        );
+    }
+}
@@ -0,0 +1,27 @@
+use fuzzy_matcher::FuzzyMatcher;
+use fuzzy_matcher::skim::SkimMatcherV2;
+
+use crate::models::TodoItem;
+
+/// Fuzzy matches `query` against the text and details of a todo. Returns
+/// the byte offsets of the matched characters in the todo text, which is
+/// empty when only the details matched, or None when neither did.
+pub fn fuzzy_match(query: &str, todo: &TodoItem) -> Option<Vec<usize>> {
+    let matcher = SkimMatcherV2::default().smart_case();
+
+    if let Some((_, indices)) = matcher.fuzzy_indices(&todo.todo, query) {
+        // The matcher counts characters, the renderer slices bytes
+        let offsets = todo
+            .todo
+            .char_indices()
+            .enumerate()
+            .filter(|(index, _)| indices.contains(index))
+            .map(|(_, (offset, _))| offset)
+            .collect();
+        return Some(offsets);
+    }
+
+    matcher
+        .fuzzy_match(&todo.details, query)
+        .map(|_| Vec::new())
+}
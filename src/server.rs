@@ -0,0 +1,415 @@
+use axum::extract::{Extension, Path, Query, State};
+use axum::http::{HeaderMap, StatusCode, header};
+use axum::response::{Html, IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use chrono::{Local, NaiveDate};
+use serde::Deserialize;
+use sqlx::sqlite::SqlitePool;
+
+use crate::dates::parse_natural_date;
+use crate::db;
+use crate::ics;
+use crate::input::parse_input;
+use crate::models::{
+    INBOX_LIST_ID, NamedList, TodoItem, filter_by_tags, new_todo_item, next_sibling,
+    previous_sibling,
+};
+use crate::sync::{SyncRequest, SyncResponse, last_seq};
+
+const INDEX_HTML: &str = include_str!("../assets/index.html");
+
+pub enum ApiError {
+    BadRequest(String),
+    NotFound(i64),
+    Unauthorized,
+    Database(sqlx::Error),
+}
+
+impl From<sqlx::Error> for ApiError {
+    fn from(e: sqlx::Error) -> Self {
+        ApiError::Database(e)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let (status, message) = match self {
+            ApiError::BadRequest(message) => (StatusCode::BAD_REQUEST, message),
+            ApiError::NotFound(id) => (StatusCode::NOT_FOUND, format!("no todo with id {id}")),
+            ApiError::Unauthorized => (StatusCode::UNAUTHORIZED, "wrong sync token".to_string()),
+            ApiError::Database(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+        };
+        (status, Json(serde_json::json!({ "error": message }))).into_response()
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ListQuery {
+    /// Id of the list to show, the inbox when not given
+    pub list: Option<i64>,
+    pub completed: Option<bool>,
+    /// Comma separated tags that every returned todo must carry
+    pub tag: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    pub q: String,
+    pub list: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct CreateTodo {
+    pub todo: String,
+    pub parent_id: Option<i64>,
+    /// Ignored for children, which always join their parent's list
+    pub list_id: Option<i64>,
+    pub due: Option<String>,
+    pub scheduled: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct UpdateTodo {
+    pub todo: String,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Up,
+    Down,
+}
+
+#[derive(Deserialize)]
+pub struct MoveTodo {
+    pub direction: Direction,
+}
+
+/// Who may post to `/api/sync`, which hands out and overwrites every list
+#[derive(Clone)]
+pub enum SyncAccess {
+    /// Clients sending this as a bearer token
+    Token(String),
+    /// Anyone who can connect, fine when that is only this machine
+    Open,
+    /// Nobody, the route isn't served
+    Off,
+}
+
+impl SyncAccess {
+    /// Opens `/api/sync` without a token only to clients on this machine
+    pub fn for_bind(token: Option<String>, local_only: bool) -> Self {
+        match token {
+            Some(token) => SyncAccess::Token(token),
+            None if local_only => SyncAccess::Open,
+            None => SyncAccess::Off,
+        }
+    }
+
+    fn allows(&self, headers: &HeaderMap) -> bool {
+        match self {
+            SyncAccess::Token(token) => headers
+                .get(header::AUTHORIZATION)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .is_some_and(|given| same_secret(given.as_bytes(), token.as_bytes())),
+            SyncAccess::Open => true,
+            SyncAccess::Off => false,
+        }
+    }
+}
+
+/// Compares every byte, so the time taken doesn't give away how much matched
+fn same_secret(given: &[u8], expected: &[u8]) -> bool {
+    given.len() == expected.len()
+        && given
+            .iter()
+            .zip(expected)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Builds the routes for the JSON API and the web page
+pub fn router(pool: SqlitePool, sync: SyncAccess) -> Router {
+    let router = Router::new()
+        .route("/", get(index))
+        .route("/api/todos", get(list_todos).post(create_todo))
+        .route("/api/search", get(search_todos))
+        .route("/api/todos.ics", get(todos_ics))
+        .route("/api/lists", get(list_lists))
+        .route(
+            "/api/todos/{id}",
+            get(get_todo).patch(update_todo).delete(delete_todo),
+        )
+        .route("/api/todos/{id}/toggle", post(toggle_todo))
+        .route("/api/todos/{id}/move", post(move_todo));
+    let router = match sync {
+        SyncAccess::Off => router,
+        sync => router.route("/api/sync", post(sync_changes).layer(Extension(sync))),
+    };
+    router.with_state(pool)
+}
+
+/// Serves the web app until the process is stopped
+pub async fn serve(
+    pool: SqlitePool,
+    bind: &str,
+    sync_token: Option<String>,
+) -> std::io::Result<()> {
+    let listener = tokio::net::TcpListener::bind(bind).await?;
+    let address = listener.local_addr()?;
+    let sync = SyncAccess::for_bind(sync_token, address.ip().is_loopback());
+    println!("Serving dodo on http://{address}");
+    if let SyncAccess::Off = sync {
+        eprintln!("Not serving /api/sync beyond this machine without a sync_token in the config");
+    }
+    axum::serve(listener, router(pool, sync)).await
+}
+
+async fn index() -> Html<&'static str> {
+    Html(INDEX_HTML)
+}
+
+async fn find_todo(pool: &SqlitePool, id: i64) -> Result<TodoItem, ApiError> {
+    db::get_todo(pool, id)
+        .await?
+        .filter(|todo| todo.deleted_at.is_none())
+        .ok_or(ApiError::NotFound(id))
+}
+
+async fn list_lists(State(pool): State<SqlitePool>) -> Result<Json<Vec<NamedList>>, ApiError> {
+    let lists = db::all_lists(&pool).await?;
+    Ok(Json(
+        lists.into_iter().filter(|list| !list.archived).collect(),
+    ))
+}
+
+async fn list_todos(
+    State(pool): State<SqlitePool>,
+    Query(query): Query<ListQuery>,
+) -> Result<Json<Vec<TodoItem>>, ApiError> {
+    let list_id = query.list.unwrap_or(INBOX_LIST_ID);
+    let todos = match query.completed {
+        Some(true) => db::completed_todos(&pool, list_id).await?,
+        Some(false) => db::uncompleted_todos(&pool, list_id).await?,
+        None => db::all_todos(&pool, list_id).await?,
+    };
+    let terms: Vec<String> = query
+        .tag
+        .iter()
+        .flat_map(|tags| tags.split(','))
+        .map(str::to_string)
+        .collect();
+    if terms.is_empty() {
+        Ok(Json(todos))
+    } else {
+        Ok(Json(filter_by_tags(todos, &terms)))
+    }
+}
+
+/// The list's todos as an iCalendar feed that calendar apps can subscribe to
+async fn todos_ics(
+    State(pool): State<SqlitePool>,
+    Query(query): Query<ListQuery>,
+) -> Result<Response, ApiError> {
+    let list_id = query.list.unwrap_or(INBOX_LIST_ID);
+    let todos = match query.completed {
+        Some(true) => db::completed_todos(&pool, list_id).await?,
+        Some(false) => db::uncompleted_todos(&pool, list_id).await?,
+        None => db::all_todos(&pool, list_id).await?,
+    };
+    Ok((
+        [(header::CONTENT_TYPE, "text/calendar; charset=utf-8")],
+        ics::to_ics(&todos),
+    )
+        .into_response())
+}
+
+/// Full-text search with FTS5 query syntax, best match first
+async fn search_todos(
+    State(pool): State<SqlitePool>,
+    Query(query): Query<SearchQuery>,
+) -> Result<Json<Vec<TodoItem>>, ApiError> {
+    let list_id = query.list.unwrap_or(INBOX_LIST_ID);
+    match db::search_todos(&pool, list_id, &query.q).await {
+        Ok(todos) => Ok(Json(todos)),
+        // SQLite rejects malformed queries, like an unbalanced quote
+        Err(sqlx::Error::Database(e)) => Err(ApiError::BadRequest(e.message().to_string())),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn get_todo(
+    State(pool): State<SqlitePool>,
+    Path(id): Path<i64>,
+) -> Result<Json<TodoItem>, ApiError> {
+    Ok(Json(find_todo(&pool, id).await?))
+}
+
+async fn create_todo(
+    State(pool): State<SqlitePool>,
+    Json(input): Json<CreateTodo>,
+) -> Result<(StatusCode, Json<TodoItem>), ApiError> {
+    let list_id = match input.parent_id {
+        Some(parent_id) => find_todo(&pool, parent_id).await?.list_id,
+        None => {
+            let list_id = input.list_id.unwrap_or(INBOX_LIST_ID);
+            if !db::all_lists(&pool)
+                .await?
+                .iter()
+                .any(|list| list.id == list_id)
+            {
+                return Err(ApiError::BadRequest(format!("no list with id {list_id}")));
+            }
+            list_id
+        }
+    };
+
+    let today = Local::now().date_naive();
+    let parsed = parse_input(&input.todo, today);
+    let mut todo_item = new_todo_item(&parsed.text, "", input.parent_id);
+    todo_item.list_id = list_id;
+    todo_item.due = parse_date_field(input.due.as_deref(), today)?.or(parsed.due);
+    todo_item.scheduled = parse_date_field(input.scheduled.as_deref(), today)?.or(parsed.scheduled);
+    todo_item.recurrence = parsed.recurrence;
+    let id = db::append_todo(&pool, &todo_item).await?;
+
+    Ok((StatusCode::CREATED, Json(find_todo(&pool, id).await?)))
+}
+
+fn parse_date_field(value: Option<&str>, today: NaiveDate) -> Result<Option<NaiveDate>, ApiError> {
+    value
+        .map(|phrase| {
+            parse_natural_date(phrase, today).ok_or_else(|| {
+                ApiError::BadRequest(format!("can't understand the date '{phrase}'"))
+            })
+        })
+        .transpose()
+}
+
+async fn update_todo(
+    State(pool): State<SqlitePool>,
+    Path(id): Path<i64>,
+    Json(input): Json<UpdateTodo>,
+) -> Result<Json<TodoItem>, ApiError> {
+    find_todo(&pool, id).await?;
+    db::update_todo_text(&pool, id, &input.todo).await?;
+    Ok(Json(find_todo(&pool, id).await?))
+}
+
+async fn toggle_todo(
+    State(pool): State<SqlitePool>,
+    Path(id): Path<i64>,
+) -> Result<Json<TodoItem>, ApiError> {
+    find_todo(&pool, id).await?;
+    db::toggle_todo_status_in_database(&pool, Some(id)).await?;
+    Ok(Json(find_todo(&pool, id).await?))
+}
+
+async fn delete_todo(
+    State(pool): State<SqlitePool>,
+    Path(id): Path<i64>,
+) -> Result<StatusCode, ApiError> {
+    find_todo(&pool, id).await?;
+    db::delete_todo_from_database(&pool, id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Moves an active todo past the sibling above or below it, like J/K in the TUI
+async fn move_todo(
+    State(pool): State<SqlitePool>,
+    Path(id): Path<i64>,
+    Json(input): Json<MoveTodo>,
+) -> Result<Json<Vec<TodoItem>>, ApiError> {
+    let list_id = find_todo(&pool, id).await?.list_id;
+    let todos = db::uncompleted_todos(&pool, list_id).await?;
+    let Some(index) = todos.iter().position(|todo| todo.id == Some(id)) else {
+        return Err(ApiError::NotFound(id));
+    };
+
+    let (before, after) = match input.direction {
+        Direction::Up => (
+            previous_sibling(&todos, index).and_then(|todo| todo.id),
+            None,
+        ),
+        Direction::Down => (None, next_sibling(&todos, index).and_then(|todo| todo.id)),
+    };
+    if before.is_some() || after.is_some() {
+        db::move_todo(&pool, id, before, after, todos[index].parent_id).await?;
+    }
+
+    Ok(Json(db::uncompleted_todos(&pool, list_id).await?))
+}
+
+/// The other end of `dodo sync URL`: takes the client's changes and hands back
+/// the ones it hasn't received yet
+async fn sync_changes(
+    State(pool): State<SqlitePool>,
+    Extension(access): Extension<SyncAccess>,
+    headers: HeaderMap,
+    Json(request): Json<SyncRequest>,
+) -> Result<Json<SyncResponse>, ApiError> {
+    if !access.allows(&headers) {
+        return Err(ApiError::Unauthorized);
+    }
+    // Read before applying, so the client isn't sent its own changes back
+    let changes = db::changes_since(&pool, request.since).await?;
+    let cursor = last_seq(&changes, request.since);
+    let accepted = db::apply_changes(&pool, &request.changes).await?;
+    Ok(Json(SyncResponse {
+        accepted,
+        changes,
+        cursor,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::add;
+    use crate::models::SORT_GAP;
+
+    async fn sort_order(pool: &SqlitePool, id: i64) -> i32 {
+        db::get_todo(pool, id).await.unwrap().unwrap().sort_order
+    }
+
+    #[tokio::test]
+    async fn created_todos_go_after_every_sibling_like_in_the_tui() {
+        let pool = db::memory_pool().await;
+        let parent = add(&pool, "parent", None).await;
+        add(&pool, "child", Some(parent)).await;
+        let trashed = add(&pool, "trashed child", Some(parent)).await;
+        db::delete_todo_from_database(&pool, trashed).await.unwrap();
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/api/todos", listener.local_addr().unwrap());
+        let app = router(pool.clone(), SyncAccess::Off);
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let create = |body: serde_json::Value| {
+            let url = url.clone();
+            async move {
+                reqwest::Client::new()
+                    .post(url)
+                    .json(&body)
+                    .send()
+                    .await
+                    .unwrap()
+                    .error_for_status()
+                    .unwrap()
+                    .json::<TodoItem>()
+                    .await
+                    .unwrap()
+            }
+        };
+
+        let child = create(serde_json::json!({ "todo": "new child", "parent_id": parent })).await;
+        assert_eq!(child.parent_id, Some(parent));
+        assert_eq!(
+            child.sort_order,
+            sort_order(&pool, trashed).await + SORT_GAP
+        );
+        let top = create(serde_json::json!({ "todo": "top" })).await;
+        assert_eq!(top.sort_order, sort_order(&pool, parent).await + SORT_GAP);
+    }
+}
@@ -0,0 +1,410 @@
+use color_eyre::Result;
+use color_eyre::eyre::bail;
+use serde::{Deserialize, Serialize};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool};
+use std::path::Path;
+
+use crate::db;
+use crate::models::Change;
+
+/// What `dodo sync` posts to a server's `/api/sync`
+#[derive(Serialize, Deserialize)]
+pub struct SyncRequest {
+    /// How far into the server's log this machine has already received
+    pub since: i64,
+    pub changes: Vec<Change>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SyncResponse {
+    /// How many of the posted changes the server hadn't seen
+    pub accepted: usize,
+    pub changes: Vec<Change>,
+    /// How far into the server's log `changes` goes
+    pub cursor: i64,
+}
+
+/// How many changes each side hadn't seen
+#[derive(Debug)]
+pub struct SyncReport {
+    pub sent: usize,
+    pub received: usize,
+}
+
+/// Exchanges change logs with another dodo database or a dodo server. Each
+/// side only sends what it logged since the last exchange with the other.
+/// `token` is sent to servers that ask for one.
+pub async fn sync(pool: &SqlitePool, peer: &str, token: Option<&str>) -> Result<SyncReport> {
+    if peer.starts_with("http://") || peer.starts_with("https://") {
+        sync_with_server(pool, peer.trim_end_matches('/'), token).await
+    } else {
+        sync_with_database(pool, Path::new(peer)).await
+    }
+}
+
+async fn sync_with_database(pool: &SqlitePool, path: &Path) -> Result<SyncReport> {
+    if !path.exists() {
+        bail!("no database at {}", path.display());
+    }
+    let peer = path.canonicalize()?.display().to_string();
+    let other = SqlitePool::connect_with(SqliteConnectOptions::new().filename(path)).await?;
+    // It may have been left at an older dodo without a change log
+    db::migrate(&other).await?;
+
+    let (sent, received) = db::sync_peer(pool, &peer).await?;
+    let ours = db::changes_since(pool, sent).await?;
+    let theirs = db::changes_since(&other, received).await?;
+    let report = SyncReport {
+        sent: db::apply_changes(&other, &ours).await?,
+        received: db::apply_changes(pool, &theirs).await?,
+    };
+    db::save_sync_peer(
+        pool,
+        &peer,
+        last_seq(&ours, sent),
+        last_seq(&theirs, received),
+    )
+    .await?;
+    other.close().await;
+
+    Ok(report)
+}
+
+async fn sync_with_server(pool: &SqlitePool, url: &str, token: Option<&str>) -> Result<SyncReport> {
+    let (sent, received) = db::sync_peer(pool, url).await?;
+    let ours = db::changes_since(pool, sent).await?;
+    let request = SyncRequest {
+        since: received,
+        changes: ours,
+    };
+
+    let mut post = reqwest::Client::new().post(format!("{url}/api/sync"));
+    if let Some(token) = token {
+        post = post.bearer_auth(token);
+    }
+    let response = post.json(&request).send().await?;
+    match response.status() {
+        reqwest::StatusCode::UNAUTHORIZED => {
+            bail!("{url} refused the sync token, set the same sync_token as the server's")
+        }
+        reqwest::StatusCode::NOT_FOUND => {
+            bail!("{url} doesn't sync, give it a sync_token or serve it on 127.0.0.1")
+        }
+        _ => {}
+    }
+    let response: SyncResponse = response.error_for_status()?.json().await?;
+    let report = SyncReport {
+        sent: response.accepted,
+        received: db::apply_changes(pool, &response.changes).await?,
+    };
+    db::save_sync_peer(pool, url, last_seq(&request.changes, sent), response.cursor).await?;
+
+    Ok(report)
+}
+
+/// Where the log stands after `changes`, which were read from after `since`
+pub fn last_seq(changes: &[Change], since: i64) -> i64 {
+    changes.last().map_or(since, |change| change.seq)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::add;
+    use crate::history;
+    use crate::models::INBOX_LIST_ID;
+    use crate::server::{self, SyncAccess};
+    use std::path::PathBuf;
+
+    async fn texts(pool: &SqlitePool) -> Vec<String> {
+        let mut texts: Vec<String> = db::all_todos(pool, INBOX_LIST_ID)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|todo| todo.todo)
+            .collect();
+        texts.sort();
+        texts
+    }
+
+    async fn id_of(pool: &SqlitePool, text: &str) -> i64 {
+        db::all_todos(pool, INBOX_LIST_ID)
+            .await
+            .unwrap()
+            .into_iter()
+            .find(|todo| todo.todo == text)
+            .and_then(|todo| todo.id)
+            .unwrap_or_else(|| panic!("no {text:?}"))
+    }
+
+    /// Lets the change log's millisecond clock move on
+    async fn tick() {
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+    }
+
+    /// A database file of its own, removed again when dropped
+    struct PeerFile(PathBuf);
+
+    impl PeerFile {
+        async fn create() -> (Self, SqlitePool) {
+            let path = std::env::temp_dir().join(format!("dodo-sync-{}.db", uuid::Uuid::new_v4()));
+            let pool = SqlitePool::connect_with(
+                SqliteConnectOptions::new()
+                    .filename(&path)
+                    .create_if_missing(true),
+            )
+            .await
+            .unwrap();
+            db::migrate(&pool).await.unwrap();
+            (PeerFile(path), pool)
+        }
+
+        fn peer(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for PeerFile {
+        fn drop(&mut self) {
+            for suffix in ["", "-wal", "-shm"] {
+                let _ = std::fs::remove_file(format!("{}{suffix}", self.0.display()));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn only_new_changes_are_applied_and_the_later_edit_wins() {
+        let (here, there) = (db::memory_pool().await, db::memory_pool().await);
+        let id = add(&here, "shared", None).await;
+        add(&here, "child", Some(id)).await;
+        let sent = db::changes_since(&here, 0).await.unwrap();
+        assert_eq!(db::apply_changes(&there, &sent).await.unwrap(), sent.len());
+        assert_eq!(db::apply_changes(&there, &sent).await.unwrap(), 0);
+        assert_eq!(texts(&there).await, ["child", "shared"]);
+        let child = id_of(&there, "child").await;
+        assert_eq!(
+            db::get_todo(&there, child)
+                .await
+                .unwrap()
+                .unwrap()
+                .parent_id,
+            Some(id_of(&there, "shared").await)
+        );
+        // Applying isn't logged again, so it wouldn't be sent back
+        let there_cursor = last_seq(&db::changes_since(&there, 0).await.unwrap(), 0);
+
+        db::update_todo_text(&there, id_of(&there, "shared").await, "edited there")
+            .await
+            .unwrap();
+        tick().await;
+        db::update_todo_text(&here, id, "edited here")
+            .await
+            .unwrap();
+        let ours = db::changes_since(&here, last_seq(&sent, 0)).await.unwrap();
+        let theirs = db::changes_since(&there, there_cursor).await.unwrap();
+        assert_eq!((ours.len(), theirs.len()), (1, 1));
+        db::apply_changes(&here, &theirs).await.unwrap();
+        db::apply_changes(&there, &ours).await.unwrap();
+
+        assert_eq!(texts(&here).await, ["child", "edited here"]);
+        assert_eq!(texts(&there).await, ["child", "edited here"]);
+    }
+
+    #[tokio::test]
+    async fn syncing_with_a_database_file_sends_each_change_once() {
+        let pool = db::memory_pool().await;
+        let (file, other) = PeerFile::create().await;
+        let kept = add(&pool, "kept", None).await;
+        add(&other, "from the other", None).await;
+
+        let report = sync(&pool, file.peer(), None).await.unwrap();
+        assert_eq!((report.sent > 0, report.received > 0), (true, true));
+        assert_eq!(texts(&pool).await, ["from the other", "kept"]);
+        assert_eq!(texts(&other).await, ["from the other", "kept"]);
+
+        let report = sync(&pool, file.peer(), None).await.unwrap();
+        assert_eq!((report.sent, report.received), (0, 0));
+
+        db::toggle_todo_status_in_database(&pool, Some(kept))
+            .await
+            .unwrap();
+        let gone = id_of(&other, "from the other").await;
+        db::delete_todo_from_database(&other, gone).await.unwrap();
+        sync(&pool, file.peer(), None).await.unwrap();
+        assert_eq!(texts(&pool).await, ["kept"]);
+        let completed = db::completed_todos(&other, INBOX_LIST_ID).await.unwrap();
+        assert_eq!(completed.len(), 1);
+        assert_eq!(completed[0].todo, "kept");
+    }
+
+    #[tokio::test]
+    async fn a_missing_database_file_is_an_error() {
+        let pool = db::memory_pool().await;
+        assert!(sync(&pool, "/nonexistent/todos.db", None).await.is_err());
+    }
+
+    /// A `dodo serve` on a free port, returning its URL
+    async fn serve(pool: SqlitePool, access: SyncAccess) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, server::router(pool, access)).await });
+        url
+    }
+
+    #[tokio::test]
+    async fn the_server_wants_its_token() {
+        let (pool, server_pool) = (db::memory_pool().await, db::memory_pool().await);
+        add(&pool, "mine", None).await;
+        add(&server_pool, "the server's", None).await;
+        let url = serve(server_pool.clone(), SyncAccess::Token("s3cret".into())).await;
+
+        for token in [None, Some("wrong"), Some("s3cre")] {
+            let error = sync(&pool, &url, token).await.unwrap_err();
+            assert!(error.to_string().contains("refused"), "{error}");
+        }
+        assert_eq!(texts(&server_pool).await, ["the server's"]);
+
+        let report = sync(&pool, &url, Some("s3cret")).await.unwrap();
+        assert!(report.sent > 0 && report.received > 0);
+        assert_eq!(texts(&pool).await, ["mine", "the server's"]);
+        assert_eq!(texts(&server_pool).await, ["mine", "the server's"]);
+
+        let report = sync(&pool, &format!("{url}/"), Some("s3cret"))
+            .await
+            .unwrap();
+        assert_eq!((report.sent, report.received), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn sync_is_only_open_without_a_token_on_this_machine() {
+        assert!(matches!(SyncAccess::for_bind(None, true), SyncAccess::Open));
+        assert!(matches!(SyncAccess::for_bind(None, false), SyncAccess::Off));
+        assert!(matches!(
+            SyncAccess::for_bind(Some("t".into()), false),
+            SyncAccess::Token(_)
+        ));
+
+        let pool = db::memory_pool().await;
+        let open = serve(db::memory_pool().await, SyncAccess::Open).await;
+        assert!(sync(&pool, &open, None).await.is_ok());
+        let off = serve(db::memory_pool().await, SyncAccess::Off).await;
+        let error = sync(&pool, &off, None).await.unwrap_err();
+        assert!(error.to_string().contains("doesn't sync"), "{error}");
+    }
+
+    /// Each todo's text with that of its parent, sorted
+    async fn tree(pool: &SqlitePool) -> Vec<(String, Option<String>)> {
+        let todos = db::all_todos(pool, INBOX_LIST_ID).await.unwrap();
+        let mut tree: Vec<_> = todos
+            .iter()
+            .map(|todo| {
+                let parent = todos
+                    .iter()
+                    .find(|parent| parent.id.is_some() && parent.id == todo.parent_id)
+                    .map(|parent| parent.todo.clone());
+                (todo.todo.clone(), parent)
+            })
+            .collect();
+        tree.sort();
+        tree
+    }
+
+    async fn uuid_of(pool: &SqlitePool, text: &str) -> Option<String> {
+        sqlx::query_scalar("SELECT uuid FROM todos WHERE todo = ?")
+            .bind(text)
+            .fetch_optional(pool)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn crossed_moves_settle_on_the_same_tree_everywhere() {
+        let pool = db::memory_pool().await;
+        let (file, other) = PeerFile::create().await;
+        let a = add(&pool, "a", None).await;
+        let b = add(&pool, "b", None).await;
+        add(&pool, "under a", Some(a)).await;
+        sync(&pool, file.peer(), None).await.unwrap();
+
+        // A under B here, then B under A over there
+        db::move_todo(&pool, a, None, None, Some(b)).await.unwrap();
+        tick().await;
+        let (other_a, other_b) = (id_of(&other, "a").await, id_of(&other, "b").await);
+        db::move_todo(&other, other_b, None, None, Some(other_a))
+            .await
+            .unwrap();
+        sync(&pool, file.peer(), None).await.unwrap();
+
+        // The later move wins and the earlier one is undone
+        let expected = vec![
+            ("a".to_string(), None),
+            ("b".to_string(), Some("a".to_string())),
+            ("under a".to_string(), Some("a".to_string())),
+        ];
+        assert_eq!(tree(&pool).await, expected);
+        assert_eq!(tree(&other).await, expected);
+        let shown = crate::models::sort_todos_hierarchically(
+            db::all_todos(&pool, INBOX_LIST_ID).await.unwrap(),
+        );
+        assert_eq!(shown.len(), 3);
+
+        let report = sync(&pool, file.peer(), None).await.unwrap();
+        assert_eq!((report.sent, report.received), (0, 0));
+        assert_eq!(tree(&pool).await, expected);
+    }
+
+    #[tokio::test]
+    async fn an_undone_purge_brings_back_the_same_todo_everywhere() {
+        let pool = db::memory_pool().await;
+        let (file, other) = PeerFile::create().await;
+        let id = add(&pool, "purged", None).await;
+        add(&pool, "child", Some(id)).await;
+        sync(&pool, file.peer(), None).await.unwrap();
+        let uuid = uuid_of(&pool, "purged").await;
+        assert!(uuid.is_some());
+
+        db::delete_todo_from_database(&pool, id).await.unwrap();
+        let ids = db::subtree_ids(&pool, id).await.unwrap();
+        let checkpoint = history::checkpoint(&pool, &ids).await.unwrap();
+        db::purge_todo(&pool, id).await.unwrap();
+        history::record(&pool, history::Action::Purge, checkpoint, &[])
+            .await
+            .unwrap();
+        sync(&pool, file.peer(), None).await.unwrap();
+        assert!(uuid_of(&other, "purged").await.is_none());
+
+        tick().await;
+        history::undo(&pool).await.unwrap();
+        assert_eq!(uuid_of(&pool, "purged").await, uuid);
+        sync(&pool, file.peer(), None).await.unwrap();
+
+        let restored = |pool: SqlitePool| async move {
+            let todos = db::deleted_todos(&pool, INBOX_LIST_ID).await.unwrap();
+            let mut texts: Vec<_> = todos.into_iter().map(|todo| todo.todo).collect();
+            texts.sort();
+            texts
+        };
+        assert_eq!(restored(pool.clone()).await, ["child", "purged"]);
+        assert_eq!(restored(other.clone()).await, ["child", "purged"]);
+        assert_eq!(uuid_of(&other, "purged").await, uuid);
+        assert_eq!(
+            tree(&other).await,
+            tree(&pool).await,
+            "the trashed todos keep their nesting"
+        );
+        let children: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM todos WHERE parent_id = (SELECT id FROM todos WHERE todo = 'purged')",
+        )
+        .fetch_one(&other)
+        .await
+        .unwrap();
+        assert_eq!(children, 1);
+
+        // Purging it again for good reaches the other side too
+        tick().await;
+        history::redo(&pool).await.unwrap();
+        sync(&pool, file.peer(), None).await.unwrap();
+        assert!(uuid_of(&other, "purged").await.is_none());
+        assert!(uuid_of(&other, "child").await.is_none());
+    }
+}
@@ -1,4 +1,8 @@
+use crate::dates::format_relative;
+use crate::input::is_tag;
 use crate::models::*;
+use crate::search::fuzzy_match;
+use chrono::{Local, NaiveDate};
 use ratatui::layout::{Constraint, Layout};
 use ratatui::style::palette::tailwind::SLATE;
 use ratatui::style::{Color, Modifier, Style, Stylize};
@@ -24,22 +28,89 @@ pub fn render_impl(app: &mut crate::app::App, frame: &mut ratatui::Frame) {
     // Rendering each area
     frame.render_widget(title(app), mode_area);
 
-    let list = todo_list(app, terminal_width - 2);
-    frame.render_stateful_widget(list, todo_list_area, &mut app.uncompleted_todo_list.state);
+    let [todo_list_area, details_area] =
+        Layout::horizontal([Constraint::Fill(2), Constraint::Fill(1)]).areas(todo_list_area);
 
-    let completed_list = completed_todo_list(app, terminal_width - 2);
-    frame.render_stateful_widget(
-        completed_list,
-        completed_tasks,
-        &mut app.completed_todo_list.state,
+    let list = todo_list(app, todo_list_area.width.saturating_sub(2));
+    frame.render_stateful_widget(list, todo_list_area, &mut app.uncompleted_todo_list.state);
+    frame.render_widget(
+        details_pane(app, details_area.width.saturating_sub(2)),
+        details_area,
     );
 
-    frame.render_widget(footer(), hotkeys_area);
+    if app.show_trash {
+        let trash_list = trash_todo_list(app, terminal_width - 2);
+        frame.render_stateful_widget(trash_list, completed_tasks, &mut app.trash_todo_list.state);
+    } else {
+        let completed_list = completed_todo_list(app, terminal_width - 2);
+        frame.render_stateful_widget(
+            completed_list,
+            completed_tasks,
+            &mut app.completed_todo_list.state,
+        );
+    }
+
+    if matches!(app.input_mode, InputMode::Search | InputMode::Command) {
+        let [prompt_area, search_area] =
+            Layout::horizontal([Constraint::Length(1), Constraint::Fill(1)]).areas(hotkeys_area);
+        let prompt = if app.input_mode == InputMode::Command {
+            ":"
+        } else if app.full_text_hits.is_some() {
+            "?"
+        } else {
+            "/"
+        };
+        frame.render_widget(Paragraph::new(prompt), prompt_area);
+        frame.render_widget(&app.textarea, search_area);
+    } else if let Some(notice) = &app.status {
+        let width = (notice.text.chars().count() as u16 + 2).min(terminal_width * 2 / 3);
+        let [footer_area, status_area] =
+            Layout::horizontal([Constraint::Fill(1), Constraint::Length(width)])
+                .areas(hotkeys_area);
+        frame.render_widget(footer(app), footer_area);
+        frame.render_widget(status_line(notice), status_area);
+    } else {
+        frame.render_widget(footer(app), hotkeys_area);
+    }
+    render_lists_popup(app, frame);
+    render_messages_popup(app, frame);
     render_input_box(app, frame);
+    render_details_editor(app, frame);
+}
+
+/// Shows the details of the selected todo next to the active list
+pub fn details_pane(app: &crate::app::App, width: u16) -> Paragraph<'static> {
+    let block = Block::default().borders(Borders::ALL).title("Details");
+    let Some(todo) = app.selected_todo() else {
+        return Paragraph::new("").block(block);
+    };
+
+    let lines: Vec<Line> = if todo.details.is_empty() {
+        vec![Line::styled(
+            "No details, press E to add some",
+            Style::default().fg(Color::DarkGray),
+        )]
+    } else {
+        todo.details
+            .lines()
+            .flat_map(|line| wrap_text(line, width as usize))
+            .map(|line| Line::from(text_spans(&line, Style::default(), &[])))
+            .collect()
+    };
+    Paragraph::new(lines).block(block)
+}
+
+pub fn render_details_editor(app: &crate::app::App, frame: &mut ratatui::Frame) {
+    if app.input_mode == InputMode::Details {
+        let area = frame.area();
+        frame.render_widget(ratatui::widgets::Clear, area);
+        frame.render_widget(&app.textarea, area);
+    }
 }
 
 pub fn render_input_box(app: &crate::app::App, frame: &mut ratatui::Frame) {
-    if app.input_mode == InputMode::Insert {
+    let typing_list_name = app.input_mode == InputMode::Lists && app.list_prompt.is_some();
+    if matches!(app.input_mode, InputMode::Insert | InputMode::Filter) || typing_list_name {
         let area = frame.area();
         let popup_width = 60;
         let popup_height = 3;
@@ -52,33 +123,158 @@ pub fn render_input_box(app: &crate::app::App, frame: &mut ratatui::Frame) {
     }
 }
 
-pub fn title(app: &crate::app::App) -> Paragraph {
-    if app.input_mode == InputMode::Insert {
-        if app.editing_index.is_some() {
-            Paragraph::new("Editing")
-                .bold()
-                .style(Style::default().fg(Color::Cyan))
-                .centered()
-        } else {
-            Paragraph::new("Insert Mode")
-                .bold()
-                .style(Style::default().fg(Color::Green))
-                .centered()
-        }
+/// Popup for switching between named lists, or picking where to move a todo
+pub fn render_lists_popup(app: &mut crate::app::App, frame: &mut ratatui::Frame) {
+    if app.input_mode != InputMode::Lists {
+        return;
+    }
+
+    let title = match app.list_action {
+        ListAction::Switch => "Lists (Enter open, a add, r rename, x archive)",
+        ListAction::Move(_) => "Move to list (Enter move, a add)",
+    };
+    let items: Vec<ListItem> = app
+        .lists
+        .iter()
+        .map(|list| {
+            let marker = if list.id == app.list_id { "• " } else { "  " };
+            ListItem::new(format!("{marker}{}", list.name))
+        })
+        .collect();
+    let list = List::new(items)
+        .block(Block::default().borders(Borders::ALL).title(title))
+        .highlight_style(SELECTED_STYLE)
+        .highlight_symbol(">")
+        .highlight_spacing(HighlightSpacing::Always);
+
+    let area = frame.area();
+    let popup_width = 52.min(area.width);
+    let popup_height = (app.lists.len() as u16 + 2).clamp(3, area.height.saturating_sub(4));
+    let x = (area.width.saturating_sub(popup_width)) / 2;
+    let y = (area.height.saturating_sub(popup_height)) / 2;
+    let popup_rect = ratatui::layout::Rect::new(x, y, popup_width, popup_height);
+
+    frame.render_widget(ratatui::widgets::Clear, popup_rect);
+    frame.render_stateful_widget(list, popup_rect, &mut app.lists_state);
+}
+
+/// Popup listing the notices shown this session, newest at the bottom
+pub fn render_messages_popup(app: &mut crate::app::App, frame: &mut ratatui::Frame) {
+    if app.input_mode != InputMode::Messages {
+        return;
+    }
+
+    let items: Vec<ListItem> = if app.messages.is_empty() {
+        vec![ListItem::new(Line::styled(
+            "No messages yet",
+            Style::default().fg(Color::DarkGray),
+        ))]
     } else {
-        Paragraph::new("Normal Mode")
+        app.messages
+            .iter()
+            .map(|notice| {
+                ListItem::new(Line::from(vec![
+                    Span::styled(
+                        format!("{} ", notice.at.format("%H:%M:%S")),
+                        Style::default().fg(Color::DarkGray),
+                    ),
+                    Span::styled(
+                        format!("{:<8}", notice.level.as_str()),
+                        notice_style(notice.level),
+                    ),
+                    Span::raw(notice.text.clone()),
+                ]))
+            })
+            .collect()
+    };
+    let list = List::new(items)
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title("Messages (Esc close)"),
+        )
+        .highlight_style(SELECTED_STYLE)
+        .highlight_spacing(HighlightSpacing::Always);
+
+    let area = frame.area();
+    let popup_width = area.width.saturating_sub(8);
+    let popup_height = area.height.saturating_sub(6);
+    let x = (area.width.saturating_sub(popup_width)) / 2;
+    let y = (area.height.saturating_sub(popup_height)) / 2;
+    let popup_rect = ratatui::layout::Rect::new(x, y, popup_width, popup_height);
+
+    frame.render_widget(ratatui::widgets::Clear, popup_rect);
+    frame.render_stateful_widget(list, popup_rect, &mut app.messages_state);
+}
+
+fn status_line(notice: &Notice) -> Paragraph<'static> {
+    Paragraph::new(notice.text.clone())
+        .style(notice_style(notice.level))
+        .right_aligned()
+}
+
+fn notice_style(level: NoticeLevel) -> Style {
+    match level {
+        NoticeLevel::Info => Style::default().fg(Color::Green),
+        NoticeLevel::Warning => Style::default().fg(Color::Yellow),
+        NoticeLevel::Error => Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
+    }
+}
+
+pub fn title(app: &crate::app::App) -> Paragraph<'_> {
+    match app.input_mode {
+        InputMode::Insert if app.editing_index.is_some() => Paragraph::new("Editing")
+            .bold()
+            .style(Style::default().fg(Color::Cyan))
+            .centered(),
+        InputMode::Insert => Paragraph::new("Insert Mode")
+            .bold()
+            .style(Style::default().fg(Color::Green))
+            .centered(),
+        InputMode::Details => Paragraph::new("Details")
+            .bold()
+            .style(Style::default().fg(Color::Blue))
+            .centered(),
+        InputMode::Search => Paragraph::new("Search")
+            .bold()
+            .style(Style::default().fg(Color::LightYellow))
+            .centered(),
+        InputMode::Filter => Paragraph::new("Filter")
+            .bold()
+            .style(Style::default().fg(Color::Magenta))
+            .centered(),
+        InputMode::Lists => Paragraph::new("Lists")
+            .bold()
+            .style(Style::default().fg(Color::LightCyan))
+            .centered(),
+        InputMode::Command => Paragraph::new("Command")
+            .bold()
+            .style(Style::default().fg(Color::LightYellow))
+            .centered(),
+        InputMode::Messages => Paragraph::new("Messages")
+            .bold()
+            .style(Style::default().fg(Color::LightRed))
+            .centered(),
+        InputMode::Normal => Paragraph::new("Normal Mode")
             .bold()
             .style(Style::default().fg(Color::Yellow))
-            .centered()
+            .centered(),
     }
 }
 
-fn indent_span(todo_item: &TodoItem) -> Span<'static> {
-    if todo_item.parent_id.is_some() {
-        Span::raw("  ")
-    } else {
-        Span::raw("")
-    }
+fn indent_span(depth: usize) -> Span<'static> {
+    Span::raw("  ".repeat(depth))
+}
+
+fn priority_span(todo_item: &TodoItem) -> Span<'static> {
+    let style = match todo_item.priority {
+        Priority::None => Style::default(),
+        Priority::Low => Style::default().fg(Color::DarkGray),
+        Priority::Medium => Style::default().fg(Color::Yellow),
+        Priority::High => Style::default().fg(Color::LightRed),
+        Priority::Urgent => Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
+    };
+    Span::styled(todo_item.priority.marker(), style)
 }
 
 fn checkbox_span(todo_item: &TodoItem) -> Span<'static> {
@@ -90,39 +286,61 @@ fn checkbox_span(todo_item: &TodoItem) -> Span<'static> {
 }
 
 pub fn completed_todo_list(app: &crate::app::App, width: u16) -> List<'static> {
-    let todo_items: Vec<ListItem> = app
-        .completed_todo_list
-        .items
+    plain_todo_list(
+        &app.completed_todo_list.items,
+        app.focused_list == WhichList::Completed,
+        "Completed",
+        app.fuzzy_search_for(WhichList::Completed),
+        width,
+    )
+}
+
+pub fn trash_todo_list(app: &crate::app::App, width: u16) -> List<'static> {
+    plain_todo_list(
+        &app.trash_todo_list.items,
+        app.focused_list == WhichList::Trash,
+        "Trash",
+        app.fuzzy_search_for(WhichList::Trash),
+        width,
+    )
+}
+
+fn plain_todo_list(
+    items: &[TodoItem],
+    is_focused: bool,
+    title: &str,
+    search: Option<&str>,
+    width: u16,
+) -> List<'static> {
+    let todo_items: Vec<ListItem> = items
         .iter()
-        .map(|todo_item| {
-            let indent = indent_span(todo_item);
+        .zip(todo_depths(items))
+        .map(|(todo_item, depth)| {
+            let indent = indent_span(depth);
             let checkbox = checkbox_span(todo_item);
-            let prefix_width = indent.width() + checkbox.width();
+            let priority = priority_span(todo_item);
+            let prefix_width = indent.width() + checkbox.width() + priority.width();
 
             let text_width = (width as usize).saturating_sub(prefix_width);
             // get the text content for wrapping
             let text_content = todo_item.todo.clone();
 
             let wrapped = wrap_text(&text_content, text_width);
+            let highlights = search_highlights(search, todo_item, &wrapped);
 
             // Create Lines
             let lines: Vec<Line> = wrapped
                 .iter()
+                .zip(highlights)
                 .enumerate()
-                .map(|(i, line)| {
-                    if i == 0 {
-                        Line::from(vec![
-                            indent.clone(),
-                            checkbox.clone(),
-                            Span::raw(line.to_string()),
-                        ])
+                .map(|(i, (line, highlights))| {
+                    let mut spans = if i == 0 {
+                        vec![indent.clone(), checkbox.clone(), priority.clone()]
                     } else {
-                        Line::from(vec![
-                            indent.clone(),
-                            Span::raw("  ".to_string()),
-                            Span::raw(line.to_string()),
-                        ])
-                    }
+                        vec![indent.clone(), Span::raw("  ".to_string())]
+                    };
+                    spans.extend(text_spans(line, Style::default(), &highlights));
+                    Line::from(spans)
                 })
                 .collect();
 
@@ -131,10 +349,7 @@ pub fn completed_todo_list(app: &crate::app::App, width: u16) -> List<'static> {
         .collect();
 
     List::new(todo_items)
-        .block(get_list_block(
-            app.focused_list == WhichList::Completed,
-            "Completed",
-        ))
+        .block(get_list_block(is_focused, title))
         .highlight_style(SELECTED_STYLE)
         .highlight_symbol(">")
         .highlight_spacing(HighlightSpacing::Always)
@@ -154,56 +369,233 @@ fn get_list_block(is_focused: bool, title: &str) -> Block<'static> {
 }
 
 pub fn todo_list(app: &crate::app::App, width: u16) -> List<'static> {
+    let today = Local::now().date_naive();
+    let search = app.fuzzy_search_for(WhichList::Uncompleted);
+
     let todo_items: Vec<ListItem> = app
         .uncompleted_todo_list
         .items
         .iter()
-        .map(|todo_item| {
-            let indent = indent_span(todo_item);
+        .zip(todo_depths(&app.uncompleted_todo_list.items))
+        .map(|(todo_item, depth)| {
+            let indent = indent_span(depth);
             let checkbox = checkbox_span(todo_item);
-            let prefix_width = indent.width() + checkbox.width();
+            let priority = priority_span(todo_item);
+            let prefix_width = indent.width() + checkbox.width() + priority.width();
 
             let text_width = (width as usize).saturating_sub(prefix_width);
             // get the text content for wrapping
             let text_content = todo_item.todo.clone();
+            let text_style = if todo_item.is_overdue(today) {
+                Style::default().fg(Color::Red)
+            } else {
+                Style::default()
+            };
 
             let wrapped = wrap_text(&text_content, text_width);
+            let highlights = search_highlights(search, todo_item, &wrapped);
 
             // Create Lines
-            let lines: Vec<Line> = wrapped
+            let mut lines: Vec<Line> = wrapped
                 .iter()
+                .zip(highlights)
                 .enumerate()
-                .map(|(i, line)| {
-                    if i == 0 {
-                        Line::from(vec![
-                            indent.clone(),
-                            checkbox.clone(),
-                            Span::raw(line.to_string()),
-                        ])
+                .map(|(i, (line, highlights))| {
+                    let mut spans = if i == 0 {
+                        vec![indent.clone(), checkbox.clone(), priority.clone()]
                     } else {
-                        Line::from(vec![
-                            indent.clone(),
-                            Span::raw("  ".to_string()),
-                            Span::raw(line.to_string()),
-                        ])
-                    }
+                        vec![indent.clone(), Span::raw("  ".to_string())]
+                    };
+                    spans.extend(text_spans(line, text_style, &highlights));
+                    Line::from(spans)
                 })
                 .collect();
 
+            // Dates go at the end of the last line, or on a line of their own when they don't fit
+            let dates = date_spans(todo_item, today);
+            let dates_width: usize = dates.iter().map(Span::width).sum();
+            match lines.last_mut() {
+                Some(last) if last.width() + dates_width <= width as usize => {
+                    last.spans.extend(dates);
+                }
+                _ if !dates.is_empty() => {
+                    let mut spans = vec![indent.clone(), Span::raw("  ".to_string())];
+                    spans.extend(dates);
+                    lines.push(Line::from(spans));
+                }
+                _ => {}
+            }
+
             ListItem::new(lines)
         })
         .collect();
 
+    let mut list_title = format!("Active Tasks in {}", app.current_list_name());
+    if app.sort_mode == SortMode::Priority {
+        list_title.push_str(" by priority");
+    }
+    if !app.tag_filter.is_empty() {
+        list_title.push_str(&format!(" [{}]", app.tag_filter.join(" ")));
+    }
+    if let Some(query) = app.search_for(WhichList::Uncompleted) {
+        let prompt = if app.full_text_hits.is_some() {
+            '?'
+        } else {
+            '/'
+        };
+        list_title.push_str(&format!(" {prompt}{query}"));
+    }
+
     List::new(todo_items)
         .block(get_list_block(
             app.focused_list == WhichList::Uncompleted,
-            "Active Tasks",
+            &list_title,
         ))
         .highlight_style(SELECTED_STYLE)
         .highlight_symbol(">")
         .highlight_spacing(HighlightSpacing::Always)
 }
 
+const TAG_COLORS: [Color; 6] = [
+    Color::Cyan,
+    Color::Magenta,
+    Color::Blue,
+    Color::LightGreen,
+    Color::LightMagenta,
+    Color::LightBlue,
+];
+
+const MATCH_STYLE: Style = Style::new()
+    .fg(Color::LightYellow)
+    .add_modifier(Modifier::BOLD.union(Modifier::UNDERLINED));
+
+/// Splits a line of todo text into spans, colouring `#tags` and `@contexts`
+/// and highlighting the characters at the byte offsets in `highlights`
+fn text_spans(line: &str, style: Style, highlights: &[usize]) -> Vec<Span<'static>> {
+    let mut spans = Vec::new();
+    let mut offset = 0;
+    for (i, word) in line.split(' ').enumerate() {
+        if i > 0 {
+            spans.push(Span::styled(" ", style));
+            offset += 1;
+        }
+        let mut word_style = style;
+        if is_tag(word) {
+            // The same tag always gets the same colour
+            let hash: usize = word.to_lowercase().bytes().map(usize::from).sum();
+            word_style = style.fg(TAG_COLORS[hash % TAG_COLORS.len()]);
+            if word.starts_with('@') {
+                word_style = word_style.add_modifier(Modifier::ITALIC);
+            }
+        }
+        spans.extend(highlighted_spans(word, offset, word_style, highlights));
+        offset += word.len();
+    }
+    spans
+}
+
+/// Splits `text`, which starts at `offset` in its line, into runs of highlighted and plain characters
+fn highlighted_spans(
+    text: &str,
+    offset: usize,
+    style: Style,
+    highlights: &[usize],
+) -> Vec<Span<'static>> {
+    let mut spans = Vec::new();
+    let mut run = String::new();
+    let mut run_highlighted = false;
+    for (index, c) in text.char_indices() {
+        let highlighted = highlights.contains(&(offset + index));
+        if highlighted != run_highlighted && !run.is_empty() {
+            let run_style = if run_highlighted {
+                style.patch(MATCH_STYLE)
+            } else {
+                style
+            };
+            spans.push(Span::styled(std::mem::take(&mut run), run_style));
+        }
+        run_highlighted = highlighted;
+        run.push(c);
+    }
+    if !run.is_empty() || spans.is_empty() {
+        let run_style = if run_highlighted {
+            style.patch(MATCH_STYLE)
+        } else {
+            style
+        };
+        spans.push(Span::styled(run, run_style));
+    }
+    spans
+}
+
+/// The matched byte offsets of a todo's text, split up to fit each of its wrapped lines
+fn search_highlights(
+    search: Option<&str>,
+    todo_item: &TodoItem,
+    wrapped: &[String],
+) -> Vec<Vec<usize>> {
+    let matched = search
+        .and_then(|query| fuzzy_match(query, todo_item))
+        .unwrap_or_default();
+
+    // Wrapping drops the spaces it breaks on, so find where each line starts
+    let mut start = 0;
+    wrapped
+        .iter()
+        .map(|line| {
+            start = todo_item.todo[start..]
+                .find(line.as_str())
+                .map_or(start, |found| start + found);
+            let end = start + line.len();
+            let line_matches = matched
+                .iter()
+                .filter(|&&offset| offset >= start && offset < end)
+                .map(|offset| offset - start)
+                .collect();
+            start = end;
+            line_matches
+        })
+        .collect()
+}
+
+fn date_spans(todo_item: &TodoItem, today: NaiveDate) -> Vec<Span<'static>> {
+    let mut spans = Vec::new();
+
+    if todo_item.collapsed {
+        spans.push(Span::styled(" ▸", Style::default().fg(Color::DarkGray)));
+    }
+
+    if let Some(scheduled) = todo_item.scheduled {
+        spans.push(Span::styled(
+            format!(" ⏵ {}", format_relative(scheduled, today)),
+            Style::default().fg(Color::DarkGray),
+        ));
+    }
+
+    if let Some(due) = todo_item.due {
+        let style = if todo_item.is_overdue(today) {
+            Style::default().fg(Color::Red).add_modifier(Modifier::BOLD)
+        } else if due == today {
+            Style::default().fg(Color::Yellow)
+        } else {
+            Style::default().fg(Color::DarkGray)
+        };
+        spans.push(Span::styled(
+            format!(" due {}", format_relative(due, today)),
+            style,
+        ));
+    }
+
+    if let Some(recurrence) = &todo_item.recurrence {
+        spans.push(Span::styled(
+            format!(" ↻ {recurrence}"),
+            Style::default().fg(Color::DarkGray),
+        ));
+    }
+
+    spans
+}
+
 #[allow(dead_code)]
 pub fn calculate_total_display_lines(app: &crate::app::App, width: u16) -> usize {
     app.uncompleted_todo_list
@@ -225,8 +617,15 @@ pub fn calculate_total_display_lines(app: &crate::app::App, width: u16) -> usize
         .sum()
 }
 
-pub fn footer() -> Paragraph<'static> {
-    Paragraph::new("j down, k up, e edit, c/Enter completed, d delete").centered()
+pub fn footer(app: &crate::app::App) -> Paragraph<'static> {
+    if app.focused_list == WhichList::Trash {
+        return Paragraph::new("j/k move, r restore, d delete forever, u undo, T hide trash")
+            .centered();
+    }
+    Paragraph::new(
+        "e edit, E notes, / search, c done, d delete, u undo, +/- pri, P sort, za fold, f filter, T trash, : cmd",
+    )
+    .centered()
 }
 
 fn wrap_text(text: &str, max_width: usize) -> Vec<String> {
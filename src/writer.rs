@@ -0,0 +1,669 @@
+use std::future::Future;
+use std::sync::mpsc as std_mpsc;
+use std::time::{Duration, Instant};
+
+use chrono::NaiveDate;
+use sqlx::sqlite::SqlitePool;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{Instrument, debug, error, field, info, info_span, warn};
+
+use crate::db;
+use crate::history::{self, Action};
+use crate::models::{Priority, TodoItem};
+use crate::recurrence::Recurrence;
+
+/// How many times a write that failed for a passing reason is tried again
+const RETRIES: u32 = 3;
+
+/// A change the TUI makes to the database, along with its history entry
+pub enum Write {
+    Add(TodoItem),
+    Edit {
+        id: i64,
+        text: String,
+        due: Option<NaiveDate>,
+        scheduled: Option<NaiveDate>,
+        recurrence: Option<Recurrence>,
+        priority: Priority,
+    },
+    Details {
+        id: i64,
+        details: String,
+    },
+    Priority {
+        id: i64,
+        priority: Priority,
+    },
+    /// Moves a todo among its siblings or under another parent, see
+    /// `db::move_todo`
+    Reorder {
+        id: i64,
+        before: Option<i64>,
+        after: Option<i64>,
+        parent: Option<i64>,
+    },
+    Toggle(i64),
+    /// Moves a todo and its subtree to the trash
+    Delete(i64),
+    Purge(i64),
+    /// Takes a todo out of the trash, along with its ancestors if they're in it
+    Restore(i64),
+    Fold {
+        id: i64,
+        collapsed: bool,
+    },
+    FoldAll {
+        list_id: i64,
+        collapsed: bool,
+    },
+    MoveToList {
+        id: i64,
+        list_id: i64,
+    },
+    Undo,
+    Redo,
+    CreateList(String),
+    RenameList {
+        id: i64,
+        name: String,
+    },
+    ArchiveList(i64),
+    /// Writes nothing, for waiting on the writes queued before it
+    Flush,
+}
+
+impl Write {
+    /// What the write was doing, for error messages
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Write::Add(_) => "adding todo",
+            Write::Edit { .. } => "updating todo",
+            Write::Details { .. } => "updating details",
+            Write::Priority { .. } => "updating priority",
+            Write::Reorder { .. } => "reordering todo",
+            Write::Toggle(_) => "toggling status",
+            Write::Delete(_) => "deleting todo",
+            Write::Purge(_) => "purging todo",
+            Write::Restore(_) => "restoring todo",
+            Write::Fold { .. } => "updating fold",
+            Write::FoldAll { .. } => "updating folds",
+            Write::MoveToList { .. } => "moving todo",
+            Write::Undo => "undoing",
+            Write::Redo => "redoing",
+            Write::CreateList(_) | Write::RenameList { .. } => "saving list",
+            Write::ArchiveList(_) => "archiving list",
+            Write::Flush => "flushing writes",
+        }
+    }
+
+    /// The todo the write is about, when there's one already in the database
+    fn todo_id(&self) -> Option<i64> {
+        match self {
+            Write::Edit { id, .. }
+            | Write::Details { id, .. }
+            | Write::Priority { id, .. }
+            | Write::Reorder { id, .. }
+            | Write::Fold { id, .. }
+            | Write::MoveToList { id, .. } => Some(*id),
+            Write::Toggle(id) | Write::Delete(id) | Write::Purge(id) | Write::Restore(id) => {
+                Some(*id)
+            }
+            _ => None,
+        }
+    }
+}
+
+struct Job {
+    write: Write,
+    /// Where to send the outcome, when someone is waiting for it
+    reply: Option<oneshot::Sender<Result<Option<i64>, sqlx::Error>>>,
+}
+
+/// Applies writes one at a time, in the order they were queued, on a
+/// background task. Failures of writes nobody waited for are kept until
+/// `take_failures` picks them up.
+pub struct Writer {
+    jobs: mpsc::UnboundedSender<Job>,
+    failures: std_mpsc::Receiver<String>,
+}
+
+impl Writer {
+    pub fn start(pool: SqlitePool) -> Self {
+        let (jobs, mut queue) = mpsc::unbounded_channel::<Job>();
+        let (failed, failures) = std_mpsc::channel();
+
+        tokio::spawn(async move {
+            while let Some(job) = queue.recv().await {
+                if let Write::Flush = job.write {
+                    if let Some(reply) = job.reply {
+                        let _ = reply.send(Ok(None));
+                    }
+                    continue;
+                }
+
+                let span = info_span!(
+                    "write",
+                    action = job.write.describe(),
+                    todo_id = field::Empty
+                );
+                if let Some(id) = job.write.todo_id() {
+                    span.record("todo_id", id);
+                }
+                let started = Instant::now();
+                let result = apply(&pool, &failed, &job.write)
+                    .instrument(span.clone())
+                    .await;
+                let elapsed = started.elapsed();
+                span.in_scope(|| match &result {
+                    Ok(id) => {
+                        // The id handed out for a new todo
+                        if let Some(id) = id {
+                            span.record("todo_id", id);
+                        }
+                        info!(?elapsed, "saved");
+                    }
+                    Err(e) => error!(
+                        ?elapsed,
+                        error = %e,
+                        queued = job.reply.is_none(),
+                        "write failed"
+                    ),
+                });
+
+                match job.reply {
+                    Some(reply) => {
+                        let _ = reply.send(result);
+                    }
+                    None => {
+                        if let Err(e) = result {
+                            let _ = failed
+                                .send(format!("Database error {}: {e}", job.write.describe()));
+                        }
+                    }
+                }
+            }
+        });
+
+        Self { jobs, failures }
+    }
+
+    /// Queues a write behind every earlier one without waiting for it
+    pub fn submit(&self, write: Write) {
+        let _ = self.jobs.send(Job { write, reply: None });
+    }
+
+    /// Queues a write and waits for it, and so for every write queued
+    /// before it. Returns the id of the row it created, if any.
+    pub fn run(&self, write: Write) -> Result<Option<i64>, sqlx::Error> {
+        let (reply, outcome) = oneshot::channel();
+        self.jobs
+            .send(Job {
+                write,
+                reply: Some(reply),
+            })
+            .map_err(|_| sqlx::Error::PoolClosed)?;
+        tokio::task::block_in_place(|| outcome.blocking_recv())
+            .unwrap_or(Err(sqlx::Error::PoolClosed))
+    }
+
+    /// Waits until every queued write has been applied
+    pub fn flush(&self) -> Result<(), sqlx::Error> {
+        self.run(Write::Flush).map(|_| ())
+    }
+
+    /// Messages for the writes that failed without anyone waiting, oldest first
+    pub fn take_failures(&self) -> Vec<String> {
+        self.failures.try_iter().collect()
+    }
+}
+
+/// Makes one write, sending to `failed` what went wrong after it was saved
+async fn apply(
+    pool: &SqlitePool,
+    failed: &std_mpsc::Sender<String>,
+    write: &Write,
+) -> Result<Option<i64>, sqlx::Error> {
+    match write {
+        Write::Add(todo) => {
+            debug!(parent_id = todo.parent_id, list_id = todo.list_id, "adding");
+            let id = with_history(pool, failed, Action::Add, &[], || {
+                db::append_todo(pool, todo)
+            })
+            .await?;
+            return Ok(Some(id));
+        }
+        Write::Edit {
+            id,
+            text,
+            due,
+            scheduled,
+            recurrence,
+            priority,
+        } => {
+            let (id, text, recurrence) = (*id, text.as_str(), recurrence.as_ref());
+            with_history(pool, failed, Action::Edit, &[id], || {
+                db::edit_todo(pool, id, text, *due, *scheduled, recurrence, *priority)
+            })
+            .await?;
+        }
+        Write::Details { id, details } => {
+            with_history(pool, failed, Action::Edit, &[*id], || {
+                db::update_todo_details(pool, *id, details)
+            })
+            .await?;
+        }
+        Write::Priority { id, priority } => {
+            with_history(pool, failed, Action::Edit, &[*id], || {
+                db::update_todo_priority(pool, *id, *priority)
+            })
+            .await?;
+        }
+        Write::Reorder {
+            id,
+            before,
+            after,
+            parent,
+        } => {
+            debug!(?before, ?after, ?parent, "moving");
+            // The siblings are only rewritten when they run out of room, but
+            // undo has to be able to put them back when they do
+            let ids = retry(|| db::sibling_ids(pool, *id, *parent)).await?;
+            with_history(pool, failed, Action::Reorder, &ids, || {
+                db::move_todo(pool, *id, *before, *after, *parent)
+            })
+            .await?;
+        }
+        Write::Toggle(id) => {
+            with_history(pool, failed, Action::Toggle, &[*id], || {
+                db::toggle_todo_status_in_database(pool, Some(*id))
+            })
+            .await?;
+        }
+        Write::Delete(id) => {
+            let ids = retry(|| db::subtree_ids(pool, *id)).await?;
+            with_history(pool, failed, Action::Delete, &ids, || {
+                db::delete_todo_from_database(pool, *id)
+            })
+            .await?;
+        }
+        Write::Purge(id) => {
+            let ids = retry(|| db::subtree_ids(pool, *id)).await?;
+            with_history(pool, failed, Action::Purge, &ids, || {
+                db::purge_todo(pool, *id)
+            })
+            .await?;
+        }
+        Write::Restore(id) => {
+            let mut ids = retry(|| db::subtree_ids(pool, *id)).await?;
+            ids.extend(retry(|| db::ancestor_ids(pool, *id)).await?);
+            with_history(pool, failed, Action::Restore, &ids, || {
+                db::restore_deleted_todo(pool, *id)
+            })
+            .await?;
+        }
+        Write::Fold { id, collapsed } => {
+            retry(|| db::update_todo_collapsed(pool, *id, *collapsed)).await?;
+        }
+        Write::FoldAll { list_id, collapsed } => {
+            retry(|| db::set_all_collapsed(pool, *list_id, *collapsed)).await?;
+        }
+        Write::MoveToList { id, list_id } => {
+            let ids = retry(|| db::subtree_ids(pool, *id)).await?;
+            with_history(pool, failed, Action::Move, &ids, || {
+                db::move_todo_to_list(pool, *id, *list_id)
+            })
+            .await?;
+        }
+        Write::Undo => {
+            retry(|| history::undo(pool)).await?;
+        }
+        Write::Redo => {
+            retry(|| history::redo(pool)).await?;
+        }
+        Write::CreateList(name) => {
+            return retry(|| db::create_list(pool, name)).await.map(Some);
+        }
+        Write::RenameList { id, name } => {
+            retry(|| db::rename_list(pool, *id, name)).await?;
+        }
+        Write::ArchiveList(id) => {
+            retry(|| db::set_list_archived(pool, *id, true)).await?;
+        }
+        Write::Flush => {}
+    }
+    Ok(None)
+}
+
+/// What a write hands back that names the todos it created
+trait Created {
+    fn created(&self) -> Vec<i64>;
+}
+
+impl Created for () {
+    fn created(&self) -> Vec<i64> {
+        Vec::new()
+    }
+}
+
+/// The id of the one new todo
+impl Created for i64 {
+    fn created(&self) -> Vec<i64> {
+        vec![*self]
+    }
+}
+
+impl Created for Vec<i64> {
+    fn created(&self) -> Vec<i64> {
+        self.clone()
+    }
+}
+
+/// Runs `write` between a history checkpoint of the todos in `ids` and the
+/// entry recording it, retrying each step on its own so nothing is written
+/// twice. Only the todos the write says it created are undone as new. Once
+/// the write is saved it counts as done, even if its entry can't be, which
+/// is reported to `failed` instead.
+async fn with_history<T, F, Fut>(
+    pool: &SqlitePool,
+    failed: &std_mpsc::Sender<String>,
+    action: Action,
+    ids: &[i64],
+    write: F,
+) -> Result<T, sqlx::Error>
+where
+    T: Created,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, sqlx::Error>>,
+{
+    let checkpoint = retry(|| history::checkpoint(pool, ids)).await?;
+    let value = retry(write).await?;
+    let created = value.created();
+    if let Err(e) = retry(|| history::record(pool, action, checkpoint.clone(), &created)).await {
+        error!(error = %e, %action, "saved but not undoable");
+        let _ = failed.send(format!("Saved the {action}, but it can't be undone: {e}"));
+    }
+    Ok(value)
+}
+
+/// Runs `attempt` until it succeeds, fails for good, or runs out of retries,
+/// waiting longer after each failure
+async fn retry<T, F, Fut>(mut attempt: F) -> Result<T, sqlx::Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, sqlx::Error>>,
+{
+    let mut delay = Duration::from_millis(50);
+    let mut retries = 0;
+    loop {
+        match attempt().await {
+            Err(e) if retries < RETRIES && is_transient(&e) => {
+                warn!(error = %e, retry = retries + 1, "database busy, trying again");
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+                retries += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Whether an error is likely to go away if the write is tried again, like
+/// another process holding a lock on the database
+fn is_transient(e: &sqlx::Error) -> bool {
+    match e {
+        sqlx::Error::PoolTimedOut | sqlx::Error::Io(_) => true,
+        // SQLITE_BUSY and SQLITE_LOCKED, or one of their extended codes
+        sqlx::Error::Database(e) => e
+            .code()
+            .and_then(|code| code.parse::<i32>().ok())
+            .is_some_and(|code| matches!(code & 0xff, 5 | 6)),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::add;
+
+    async fn history_sizes(pool: &SqlitePool) -> (usize, usize) {
+        let (before, after): (String, String) = sqlx::query_as(
+            "SELECT before_state, after_state FROM history ORDER BY id DESC LIMIT 1",
+        )
+        .fetch_one(pool)
+        .await
+        .unwrap();
+        let count = |json: &str| {
+            serde_json::from_str::<Vec<serde_json::Value>>(json)
+                .unwrap()
+                .len()
+        };
+        (count(&before), count(&after))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn restoring_checkpoints_only_what_it_can_bring_back() {
+        let pool = db::memory_pool().await;
+        for n in 0..20 {
+            let id = add(&pool, &format!("trashed {n}"), None).await;
+            db::delete_todo_from_database(&pool, id).await.unwrap();
+        }
+        let parent = add(&pool, "parent", None).await;
+        let child = add(&pool, "child", Some(parent)).await;
+        add(&pool, "grandchild", Some(child)).await;
+        db::delete_todo_from_database(&pool, parent).await.unwrap();
+
+        let writer = Writer::start(pool.clone());
+        writer.run(Write::Restore(child)).unwrap();
+        assert_eq!(history_sizes(&pool).await, (3, 3));
+        assert!(
+            db::get_todo(&pool, parent)
+                .await
+                .unwrap()
+                .unwrap()
+                .deleted_at
+                .is_none()
+        );
+
+        writer.run(Write::Undo).unwrap();
+        let parent = db::get_todo(&pool, parent).await.unwrap().unwrap();
+        assert!(parent.deleted_at.is_some());
+    }
+
+    async fn texts(pool: &SqlitePool) -> Vec<String> {
+        db::all_todos(pool, crate::models::INBOX_LIST_ID)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|todo| todo.todo)
+            .collect()
+    }
+
+    fn edit(id: i64, text: &str) -> Write {
+        Write::Edit {
+            id,
+            text: text.to_string(),
+            due: None,
+            scheduled: None,
+            recurrence: None,
+            priority: Priority::None,
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn submitted_writes_are_applied_in_order_and_flush_waits_for_them() {
+        let pool = db::memory_pool().await;
+        let id = add(&pool, "first", None).await;
+        let writer = Writer::start(pool.clone());
+
+        for n in 0..20 {
+            writer.submit(edit(id, &format!("edit {n}")));
+            writer.submit(Write::Add(crate::models::new_todo_item(
+                &format!("added {n}"),
+                "",
+                None,
+            )));
+        }
+        writer.flush().unwrap();
+
+        let mut expected = vec!["edit 19".to_string()];
+        expected.extend((0..20).map(|n| format!("added {n}")));
+        assert_eq!(texts(&pool).await, expected);
+        assert!(writer.take_failures().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn running_an_add_returns_the_new_id() {
+        let pool = db::memory_pool().await;
+        let writer = Writer::start(pool.clone());
+
+        let todo = crate::models::new_todo_item("new", "", None);
+        let id = writer.run(Write::Add(todo)).unwrap().unwrap();
+        assert_eq!(db::get_todo(&pool, id).await.unwrap().unwrap().todo, "new");
+        assert_eq!(writer.run(edit(id, "edited")).unwrap(), None);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn failures_of_submitted_writes_are_kept_for_later() {
+        let pool = db::memory_pool().await;
+        let a = add(&pool, "a", None).await;
+        let child = add(&pool, "child", Some(a)).await;
+        let writer = Writer::start(pool.clone());
+
+        writer.submit(Write::Reorder {
+            id: a,
+            before: Some(child),
+            after: None,
+            parent: None,
+        });
+        writer.submit(edit(a, "still saved"));
+        writer.flush().unwrap();
+
+        let failures = writer.take_failures();
+        assert_eq!(failures.len(), 1);
+        assert!(failures[0].starts_with("Database error reordering todo"));
+        assert!(writer.take_failures().is_empty());
+        assert_eq!(texts(&pool).await, ["still saved", "child"]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_write_whose_history_fails_is_saved_but_reported() {
+        let pool = db::memory_pool().await;
+        sqlx::query(
+            "CREATE TRIGGER no_history BEFORE INSERT ON history BEGIN SELECT RAISE(ABORT, 'full'); END",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let writer = Writer::start(pool.clone());
+
+        let todo = crate::models::new_todo_item("kept", "", None);
+        let id = writer.run(Write::Add(todo)).unwrap();
+        assert!(id.is_some());
+        assert_eq!(texts(&pool).await, ["kept"]);
+        let failures = writer.take_failures();
+        assert_eq!(failures.len(), 1);
+        assert!(
+            failures[0].starts_with("Saved the add, but it can't be undone"),
+            "{failures:?}"
+        );
+    }
+
+    /// A database error with just a code, as SQLite would report it
+    #[derive(Debug)]
+    struct CodedError(&'static str);
+
+    impl std::fmt::Display for CodedError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "error code {}", self.0)
+        }
+    }
+
+    impl std::error::Error for CodedError {}
+
+    impl sqlx::error::DatabaseError for CodedError {
+        fn message(&self) -> &str {
+            "coded error"
+        }
+
+        fn code(&self) -> Option<std::borrow::Cow<'_, str>> {
+            Some(self.0.into())
+        }
+
+        fn as_error(&self) -> &(dyn std::error::Error + Send + Sync + 'static) {
+            self
+        }
+
+        fn as_error_mut(&mut self) -> &mut (dyn std::error::Error + Send + Sync + 'static) {
+            self
+        }
+
+        fn into_error(self: Box<Self>) -> Box<dyn std::error::Error + Send + Sync + 'static> {
+            self
+        }
+
+        fn kind(&self) -> sqlx::error::ErrorKind {
+            sqlx::error::ErrorKind::Other
+        }
+    }
+
+    fn coded(code: &'static str) -> sqlx::Error {
+        sqlx::Error::Database(Box::new(CodedError(code)))
+    }
+
+    #[tokio::test]
+    async fn busy_and_locked_databases_are_worth_retrying() {
+        // SQLITE_BUSY, SQLITE_LOCKED, SQLITE_BUSY_SNAPSHOT, SQLITE_LOCKED_SHAREDCACHE
+        for code in ["5", "6", "517", "262"] {
+            assert!(is_transient(&coded(code)), "{code}");
+        }
+        // SQLITE_ERROR, SQLITE_CONSTRAINT, SQLITE_CONSTRAINT_UNIQUE
+        for code in ["1", "19", "2067"] {
+            assert!(!is_transient(&coded(code)), "{code}");
+        }
+
+        // A real constraint error is final too
+        let pool = db::memory_pool().await;
+        let duplicate = sqlx::query("INSERT INTO lists (id, name) VALUES (?, 'again')")
+            .bind(crate::models::INBOX_LIST_ID)
+            .execute(&pool)
+            .await
+            .unwrap_err();
+        assert!(!is_transient(&duplicate), "{duplicate}");
+        assert!(is_transient(&sqlx::Error::PoolTimedOut));
+        assert!(!is_transient(&sqlx::Error::RowNotFound));
+    }
+
+    #[tokio::test]
+    async fn retrying_gives_up_after_the_last_retry() {
+        let attempts = std::sync::atomic::AtomicU32::new(0);
+        let result: Result<(), _> = retry(|| async {
+            attempts.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Err(coded("5"))
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.into_inner(), RETRIES + 1);
+
+        // Errors that won't go away aren't tried again
+        let attempts = std::sync::atomic::AtomicU32::new(0);
+        let result: Result<(), _> = retry(|| async {
+            attempts.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Err(coded("19"))
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.into_inner(), 1);
+
+        // Nor is anything once it works
+        let attempts = std::sync::atomic::AtomicU32::new(0);
+        let result = retry(|| async {
+            match attempts.fetch_add(1, std::sync::atomic::Ordering::SeqCst) {
+                0 => Err(coded("6")),
+                n => Ok(n),
+            }
+        })
+        .await;
+        assert_eq!(result.unwrap(), 1);
+    }
+}